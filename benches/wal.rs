@@ -0,0 +1,154 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use guardian_bell::model::metrics::{
+    AggregationTemporality, HistogramDataPoint, Metric, MetricData,
+};
+use guardian_bell::record::Record;
+use guardian_bell::wal::{Config, Durability, Position, WAL};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use temp_dir::TempDir;
+
+const RECORD_SIZE: usize = 256;
+const WRITERS: usize = 8;
+const RECORDS_PER_WRITER: usize = 16;
+const RECOVERED_RECORDS: usize = 10_000;
+
+fn open(dir: &TempDir, durability: Durability) -> WAL {
+    WAL::new(Config {
+        durability,
+        ..Config::new(dir.path().to_path_buf())
+    })
+    .unwrap()
+}
+
+fn wal(dir: &TempDir, durability: Durability) -> Arc<Mutex<WAL>> {
+    Arc::new(Mutex::new(open(dir, durability)))
+}
+
+/// concurrent writers append under the lock and wait for the commit without
+/// it, like the ingestion does, so with `always` they share the syncs.
+fn write(wal: &Arc<Mutex<WAL>>) {
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|_| {
+            let wal = wal.clone();
+            thread::spawn(move || {
+                let record = [7_u8; RECORD_SIZE];
+                for _ in 0..RECORDS_PER_WRITER {
+                    let commit = {
+                        let mut wal = wal.lock().unwrap();
+                        wal.append(1, &record).unwrap();
+                        wal.commit()
+                    };
+                    commit.wait().unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+}
+
+fn durability(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wal_durability");
+    group.throughput(Throughput::Elements((WRITERS * RECORDS_PER_WRITER) as u64));
+    group.sample_size(20);
+
+    for (name, durability) in [
+        ("always", Durability::Always),
+        (
+            "every_10ms",
+            Durability::Interval(Duration::from_millis(10)),
+        ),
+        ("every_64kb", Durability::Bytes(64 * 1024)),
+        ("os", Durability::Os),
+    ] {
+        let dir = TempDir::new().unwrap();
+        let wal = wal(&dir, durability);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &wal, |b, wal| {
+            b.iter(|| write(wal))
+        });
+    }
+    group.finish();
+}
+
+fn metric(i: usize) -> Metric {
+    Metric {
+        name: "http.server.duration".to_string(),
+        unit: "ms".to_string(),
+        data: MetricData::Histogram(
+            HistogramDataPoint {
+                start_time: 1_700_000_000_000,
+                time: 1_700_000_000_000 + i as u64 * 1_000,
+                count: 60,
+                sum: 1_234.5,
+                bucket_counts: Box::new([10, 20, 20, 8, 2]),
+                explicity_bouds: Box::new([5.0, 10.0, 25.0, 50.0]),
+            },
+            AggregationTemporality::Delta,
+        ),
+        time: 1_700_000_000_000 + i as u64 * 1_000,
+        attributes: HashMap::from([
+            ("host".to_string(), format!("web-{}", i % 8)),
+            ("route".to_string(), "/api/v1/orders".to_string()),
+        ]),
+    }
+}
+
+/// reads and decodes every metric of the WAL, like the alarm service recovery.
+fn recover(dir: &TempDir) -> usize {
+    let mut wal = open(dir, Durability::Os);
+    let first_page = wal.first_page();
+    let mut recovered = 0;
+    if wal.is_unframed(first_page).unwrap() {
+        for entry in wal.iter_unframed(first_page).unwrap() {
+            Record::decode_unframed(&entry.unwrap().1).unwrap();
+            recovered += 1;
+        }
+        return recovered;
+    }
+    for entry in wal.iter_from(Position::new(first_page, 0)).unwrap() {
+        let (_, frame) = entry.unwrap();
+        Record::decode(frame.record_type, &frame.data).unwrap();
+        recovered += 1;
+    }
+    recovered
+}
+
+/// compares the recovery of metrics written as JSON in unframed pages
+/// (before the binary encoding) with the current encoding.
+fn recovery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wal_recovery");
+    group.throughput(Throughput::Elements(RECOVERED_RECORDS as u64));
+    group.sample_size(20);
+
+    for name in ["json", "binary"] {
+        let dir = TempDir::new().unwrap();
+        let mut wal = open(&dir, Durability::Os);
+        for i in 0..RECOVERED_RECORDS {
+            match name {
+                "json" => {
+                    // prefixed by its length, as before records were framed
+                    let json = serde_json::to_vec(&metric(i)).unwrap();
+                    let mut data = json.len().to_ne_bytes().to_vec();
+                    data.extend_from_slice(&json);
+                    wal.write(&data).unwrap();
+                }
+                _ => {
+                    let (record_type, data) = Record::Metric(metric(i)).encode().unwrap();
+                    wal.append(record_type, &data).unwrap();
+                }
+            }
+        }
+        drop(wal);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &dir, |b, dir| {
+            b.iter(|| assert_eq!(RECOVERED_RECORDS, recover(dir)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, durability, recovery);
+criterion_main!(benches);
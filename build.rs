@@ -1,6 +1,15 @@
+use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// packages that also get serde implementations, needed by OTLP/HTTP with JSON.
+const JSON_PACKAGES: &[&str] = &[
+    ".opentelemetry.proto.common.v1",
+    ".opentelemetry.proto.resource.v1",
+    ".opentelemetry.proto.metrics.v1",
+    ".opentelemetry.proto.collector.metrics.v1",
+];
+
 fn main() {
     let proto_root = Path::new("proto");
     let proto_files = collect_proto_files(proto_root);
@@ -10,9 +19,18 @@ fn main() {
         .map(|path| path.to_str().unwrap())
         .collect();
 
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("proto_descriptor.bin");
+
     tonic_build::configure()
+        .file_descriptor_set_path(&descriptor_path)
         .compile(&proto_paths, &[proto_root.to_str().unwrap()])
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
+
+    let descriptor_set = fs::read(&descriptor_path).expect("Failed to read descriptor set");
+    pbjson_build::Builder::new()
+        .register_descriptors(&descriptor_set)
+        .and_then(|builder| builder.build(JSON_PACKAGES))
+        .unwrap_or_else(|e| panic!("Failed to generate serde for protos {:?}", e));
 }
 
 fn collect_proto_files(dir: &Path) -> Vec<PathBuf> {
@@ -1,6 +1,15 @@
+use crate::alarm::service::{AlarmService, Error as AlarmServiceError};
+use crate::alarm::state::AlarmState as State;
+use crate::model::dsl;
 use crate::server;
 use proto::admin_server::{Admin, AdminServer};
-use proto::{ShutdownRequest, ShutdownResponse};
+use proto::{
+    Alarm, AlarmState, AlarmStatus, CreateAlarmRequest, DeleteAlarmRequest, DeleteAlarmResponse,
+    GetAlarmRequest, GetAlarmStateRequest, ListAlarmsRequest, ListAlarmsResponse, ShutdownRequest,
+    ShutdownResponse, UpdateAlarmRequest,
+};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::sync::mpsc::Sender;
 use tonic::{Request, Response, Status};
 use tonic_health::server::HealthReporter;
@@ -11,17 +20,65 @@ pub mod proto {
     tonic::include_proto!("admin_service");
 }
 
-#[derive(Clone, Debug)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Alarm service is unavailable")]
+    AlarmServiceUnavailable,
+    #[error("Invalid alarm: {0}")]
+    InvalidAlarm(String),
+    #[error("Alarm {0} already exists")]
+    AlreadyExists(String),
+    #[error("Alarm {0} not found")]
+    NotFound(String),
+    #[error("Could not save the alarm {0}")]
+    AlarmServiceError(#[from] AlarmServiceError),
+}
+
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::InvalidAlarm(_)
+            | Error::AlarmServiceError(AlarmServiceError::DependencyCycle(_)) => {
+                Status::invalid_argument(e.to_string())
+            }
+            Error::AlreadyExists(_) => Status::already_exists(e.to_string()),
+            Error::AlarmServiceError(AlarmServiceError::NotManaged(_)) => {
+                Status::failed_precondition(e.to_string())
+            }
+            Error::NotFound(_) => Status::not_found(e.to_string()),
+            Error::AlarmServiceUnavailable | Error::AlarmServiceError(_) => {
+                Status::internal(e.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct AdminService {
     health_reporter: HealthReporter,
     tx: Sender<bool>,
+    alarm_service: Arc<Mutex<AlarmService>>,
+}
+
+impl fmt::Debug for AdminService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminService").finish_non_exhaustive()
+    }
 }
 
 impl AdminService {
-    pub fn new(health_reporter: HealthReporter, tx: Sender<bool>) -> Self {
+    /// used when ListAlarms does not set the page size.
+    const DEFAULT_PAGE_SIZE: usize = 100;
+
+    pub fn new(
+        health_reporter: HealthReporter,
+        tx: Sender<bool>,
+        alarm_service: Arc<Mutex<AlarmService>>,
+    ) -> Self {
         Self {
             health_reporter,
             tx,
+            alarm_service,
         }
     }
 
@@ -31,6 +88,56 @@ impl AdminService {
             .await;
         AdminServer::new(self.clone())
     }
+
+    fn alarm_service(&self) -> Result<MutexGuard<'_, AlarmService>, Error> {
+        self.alarm_service
+            .lock()
+            .map_err(|_| Error::AlarmServiceUnavailable)
+    }
+
+    /// validates the alarm and adds it to the alarm service. `exists` is
+    /// whether the alarm must already exist (update) or not (create).
+    /// Returns once the alarm is durable.
+    async fn put_alarm(&self, alarm: Option<Alarm>, exists: bool) -> Result<Alarm, Error> {
+        let alarm = alarm.ok_or_else(|| Error::InvalidAlarm("alarm is required".to_string()))?;
+        if alarm.id.is_empty() {
+            return Err(Error::InvalidAlarm("id is required".to_string()));
+        }
+        let config =
+            dsl::parse(&alarm.expression).map_err(|e| Error::InvalidAlarm(e.to_string()))?;
+
+        let expression = config.to_string();
+        let append = {
+            let mut alarm_service = self.alarm_service()?;
+            match (alarm_service.alarm(&alarm.id).is_some(), exists) {
+                (true, false) => return Err(Error::AlreadyExists(alarm.id)),
+                (false, true) => return Err(Error::NotFound(alarm.id)),
+                _ => {}
+            }
+
+            match exists {
+                true => alarm_service.update_alarm(alarm.id.clone(), config)?,
+                false => alarm_service.create_alarm(alarm.id.clone(), config)?,
+            }
+        };
+        append.await.map_err(AlarmServiceError::from)?;
+
+        Ok(Alarm {
+            id: alarm.id,
+            expression,
+        })
+    }
+}
+
+impl From<State> for AlarmState {
+    fn from(state: State) -> Self {
+        match state {
+            State::InsufficientData => AlarmState::InsufficientData,
+            State::Ok => AlarmState::Ok,
+            State::Pending => AlarmState::Pending,
+            State::Alarm => AlarmState::Alarm,
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -67,4 +174,344 @@ impl Admin for AdminService {
             }
         }
     }
+
+    #[instrument]
+    async fn create_alarm(
+        &self,
+        req: Request<CreateAlarmRequest>,
+    ) -> Result<Response<Alarm>, Status> {
+        Ok(Response::new(
+            self.put_alarm(req.into_inner().alarm, false).await?,
+        ))
+    }
+
+    #[instrument]
+    async fn update_alarm(
+        &self,
+        req: Request<UpdateAlarmRequest>,
+    ) -> Result<Response<Alarm>, Status> {
+        Ok(Response::new(
+            self.put_alarm(req.into_inner().alarm, true).await?,
+        ))
+    }
+
+    #[instrument]
+    async fn delete_alarm(
+        &self,
+        req: Request<DeleteAlarmRequest>,
+    ) -> Result<Response<DeleteAlarmResponse>, Status> {
+        let id = req.into_inner().id;
+        let append = self
+            .alarm_service()?
+            .delete_alarm(&id)
+            .map_err(Error::from)?
+            .ok_or(Error::NotFound(id))?;
+        append
+            .await
+            .map_err(|e| Error::from(AlarmServiceError::from(e)))?;
+        Ok(Response::new(DeleteAlarmResponse {}))
+    }
+
+    #[instrument]
+    async fn get_alarm(&self, req: Request<GetAlarmRequest>) -> Result<Response<Alarm>, Status> {
+        let id = req.into_inner().id;
+        let alarm_service = self.alarm_service()?;
+        let alarm = alarm_service
+            .alarm(&id)
+            .ok_or_else(|| Error::NotFound(id.clone()))?;
+        Ok(Response::new(Alarm {
+            expression: alarm.config().to_string(),
+            id,
+        }))
+    }
+
+    #[instrument]
+    async fn list_alarms(
+        &self,
+        req: Request<ListAlarmsRequest>,
+    ) -> Result<Response<ListAlarmsResponse>, Status> {
+        let req = req.into_inner();
+        let page_size = match req.page_size {
+            0 => Self::DEFAULT_PAGE_SIZE,
+            page_size => page_size as usize,
+        };
+        let states: Vec<AlarmState> = req.states().collect();
+
+        let alarm_service = self.alarm_service()?;
+        let mut alarms: Vec<_> = alarm_service
+            .alarms()
+            .filter(|alarm| {
+                let id = alarm.identifier();
+                // the page token is the last id of the previous page
+                id.starts_with(&req.id_prefix) && (req.page_token.is_empty() || id > req.page_token)
+            })
+            .filter(|alarm| {
+                states.is_empty() || states.contains(&AlarmState::from(alarm.status().state))
+            })
+            .collect();
+        alarms.sort_by_key(|alarm| alarm.identifier());
+
+        let next_page_token = match alarms.len() > page_size {
+            true => alarms[page_size - 1].identifier(),
+            false => String::new(),
+        };
+        let alarms = alarms
+            .into_iter()
+            .take(page_size)
+            .map(|alarm| Alarm {
+                id: alarm.identifier(),
+                expression: alarm.config().to_string(),
+            })
+            .collect();
+
+        Ok(Response::new(ListAlarmsResponse {
+            alarms,
+            next_page_token,
+        }))
+    }
+
+    #[instrument]
+    async fn get_alarm_state(
+        &self,
+        req: Request<GetAlarmStateRequest>,
+    ) -> Result<Response<AlarmStatus>, Status> {
+        let id = req.into_inner().id;
+        let alarm_service = self.alarm_service()?;
+        let alarm = alarm_service
+            .alarm(&id)
+            .ok_or_else(|| Error::NotFound(id.clone()))?;
+        let status = alarm.status();
+        Ok(Response::new(AlarmStatus {
+            id,
+            state: AlarmState::from(status.state).into(),
+            since: status.since.timestamp_millis(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alarm::alarm;
+    use crate::alarm::service::Config;
+    use temp_dir::TempDir;
+    use tokio::sync::mpsc;
+    use tonic::Code;
+
+    fn admin_service(dir: &TempDir) -> AdminService {
+        admin_service_with(dir, vec![])
+    }
+
+    fn admin_service_with(dir: &TempDir, alarms: Vec<Box<dyn alarm::Alarm>>) -> AdminService {
+        let (tx, _) = mpsc::channel(1);
+        let alarm_service = AlarmService::new(Config::new(dir.path().to_owned()), alarms).unwrap();
+        AdminService::new(
+            tonic_health::server::health_reporter().0,
+            tx,
+            Arc::new(Mutex::new(alarm_service)),
+        )
+    }
+
+    fn alarm(id: &str, expression: &str) -> Option<Alarm> {
+        Some(Alarm {
+            id: id.to_string(),
+            expression: expression.to_string(),
+        })
+    }
+
+    async fn create(admin: &AdminService, id: &str, expression: &str) -> Result<Alarm, Status> {
+        admin
+            .create_alarm(Request::new(CreateAlarmRequest {
+                alarm: alarm(id, expression),
+            }))
+            .await
+            .map(Response::into_inner)
+    }
+
+    async fn list(admin: &AdminService, request: ListAlarmsRequest) -> ListAlarmsResponse {
+        admin
+            .list_alarms(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    #[tokio::test]
+    async fn manages_alarms() {
+        let dir = TempDir::new().unwrap();
+        let admin = admin_service(&dir);
+
+        let created = create(&admin, "cpu", "max(cpu.usage) > 80 over 5m")
+            .await
+            .unwrap();
+        assert_eq!(alarm("cpu", "max(cpu.usage) > 80 over 5m"), Some(created));
+        assert_eq!(
+            Code::AlreadyExists,
+            create(&admin, "cpu", "max(cpu.usage) > 90")
+                .await
+                .unwrap_err()
+                .code()
+        );
+
+        let updated = admin
+            .update_alarm(Request::new(UpdateAlarmRequest {
+                alarm: alarm("cpu", "max(cpu.usage) > 90"),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let got = admin
+            .get_alarm(Request::new(GetAlarmRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(updated, got);
+        assert_eq!("max(cpu.usage) > 90 over 5m", got.expression);
+
+        let status = admin
+            .get_alarm_state(Request::new(GetAlarmStateRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(AlarmState::InsufficientData, status.state());
+
+        admin
+            .delete_alarm(Request::new(DeleteAlarmRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap();
+        let missing = admin
+            .get_alarm(Request::new(GetAlarmRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, missing.code());
+        let missing = admin
+            .update_alarm(Request::new(UpdateAlarmRequest {
+                alarm: alarm("cpu", "max(cpu.usage) > 90"),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, missing.code());
+    }
+
+    #[tokio::test]
+    async fn alarms_not_managed_through_the_api_are_read_only() {
+        let dir = TempDir::new().unwrap();
+        let config = dsl::parse("max(cpu.usage) > 80").unwrap();
+        let admin = admin_service_with(
+            &dir,
+            vec![alarm::new_alarm(
+                "cpu".to_string(),
+                config,
+                Box::new(alarm::NoOpNotifier {}),
+            )],
+        );
+
+        let error = create(&admin, "cpu", "max(cpu.usage) > 90")
+            .await
+            .unwrap_err();
+        assert_eq!(Code::AlreadyExists, error.code());
+        let error = admin
+            .update_alarm(Request::new(UpdateAlarmRequest {
+                alarm: alarm("cpu", "max(cpu.usage) > 90"),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::FailedPrecondition, error.code());
+        let error = admin
+            .delete_alarm(Request::new(DeleteAlarmRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::FailedPrecondition, error.code());
+
+        let got = admin
+            .get_alarm(Request::new(GetAlarmRequest {
+                id: "cpu".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!("max(cpu.usage) > 80 over 5m", got.expression);
+    }
+
+    #[tokio::test]
+    async fn invalid_alarms() {
+        let dir = TempDir::new().unwrap();
+        let admin = admin_service(&dir);
+
+        for (id, expression) in [
+            ("", "max(cpu.usage) > 80"),
+            ("cpu", "max(cpu.usage) >"),
+            ("cpu", "max(cpu.usage) > 80 over 0m"),
+            ("self", "ALARM(\"self\")"),
+        ] {
+            let error = create(&admin, id, expression).await.unwrap_err();
+            assert_eq!(Code::InvalidArgument, error.code(), "{}", expression);
+        }
+        assert!(list(&admin, ListAlarmsRequest::default())
+            .await
+            .alarms
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_alarms_with_filters_and_pages() {
+        let dir = TempDir::new().unwrap();
+        let admin = admin_service(&dir);
+        for id in ["db-2", "cpu", "db-1", "db-3"] {
+            create(&admin, id, "avg(latency) > 1").await.unwrap();
+        }
+
+        let first = list(
+            &admin,
+            ListAlarmsRequest {
+                id_prefix: "db-".to_string(),
+                page_size: 2,
+                ..Default::default()
+            },
+        )
+        .await;
+        let ids: Vec<_> = first.alarms.iter().map(|a| a.id.as_str()).collect();
+        assert_eq!(vec!["db-1", "db-2"], ids);
+
+        let second = list(
+            &admin,
+            ListAlarmsRequest {
+                id_prefix: "db-".to_string(),
+                page_size: 2,
+                page_token: first.next_page_token,
+                ..Default::default()
+            },
+        )
+        .await;
+        let ids: Vec<_> = second.alarms.iter().map(|a| a.id.as_str()).collect();
+        assert_eq!(vec!["db-3"], ids);
+        assert!(second.next_page_token.is_empty());
+
+        let alarming = list(
+            &admin,
+            ListAlarmsRequest {
+                states: vec![AlarmState::Alarm.into()],
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(alarming.alarms.is_empty());
+        assert_eq!(
+            4,
+            list(&admin, ListAlarmsRequest::default())
+                .await
+                .alarms
+                .len()
+        );
+    }
 }
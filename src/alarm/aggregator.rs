@@ -0,0 +1,544 @@
+use crate::model::alarm::Aggregation;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Aggregator accumulates all the values of a single data point (a minute)
+/// of an alarm.
+pub trait Aggregator: Send {
+    /// adds a value, `time` is in milliseconds since the epoch.
+    fn add(&mut self, value: f64, time: u64);
+
+    /// the aggregated value, None if nothing was added.
+    fn value(&self) -> Option<f64>;
+
+    /// what was aggregated so far, e.g. to keep it in a snapshot.
+    fn save(&self) -> AggregatorState;
+
+    /// adds the values of a saved aggregator of the same aggregation, as if
+    /// they were added to this one. States of other aggregations are ignored.
+    fn merge(&mut self, state: &AggregatorState);
+}
+
+/// AggregatorState is what an `Aggregator` aggregated, by aggregation.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum AggregatorState {
+    Avg(Avg),
+    /// max or min.
+    Extreme(Option<f64>),
+    Sum(Sum),
+    Count(Count),
+    Last(Last),
+    StdDev(StdDev),
+    Percentile(Sketch),
+    Rate(Rate),
+}
+
+/// creates an empty aggregator for the data point of an alarm.
+pub fn new_aggregator(agg: Aggregation) -> Box<dyn Aggregator> {
+    match agg {
+        Aggregation::Avg => Box::<Avg>::default(),
+        Aggregation::Max => Box::new(Extreme {
+            value: None,
+            f: f64::max,
+        }),
+        Aggregation::Min => Box::new(Extreme {
+            value: None,
+            f: f64::min,
+        }),
+        Aggregation::Sum => Box::<Sum>::default(),
+        Aggregation::Count => Box::<Count>::default(),
+        Aggregation::Last => Box::<Last>::default(),
+        Aggregation::StdDev => Box::<StdDev>::default(),
+        Aggregation::P50 => Box::new(Percentile::new(0.5)),
+        Aggregation::P90 => Box::new(Percentile::new(0.9)),
+        Aggregation::P99 => Box::new(Percentile::new(0.99)),
+        Aggregation::Rate => Box::<Rate>::default(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Avg {
+    count: u64,
+    sum: f64,
+}
+
+impl Aggregator for Avg {
+    fn add(&mut self, value: f64, _time: u64) {
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn value(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Avg(self.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Avg(other) = state {
+            self.count += other.count;
+            self.sum += other.sum;
+        }
+    }
+}
+
+/// max or min.
+struct Extreme {
+    value: Option<f64>,
+    f: fn(f64, f64) -> f64,
+}
+
+impl Aggregator for Extreme {
+    fn add(&mut self, value: f64, _time: u64) {
+        self.value = Some(match self.value {
+            Some(current) => (self.f)(current, value),
+            None => value,
+        });
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.value
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Extreme(self.value)
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Extreme(Some(value)) = state {
+            self.add(*value, 0);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Sum {
+    sum: Option<f64>,
+}
+
+impl Aggregator for Sum {
+    fn add(&mut self, value: f64, _time: u64) {
+        self.sum = Some(self.sum.unwrap_or_default() + value);
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.sum
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Sum(self.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Sum(Sum { sum: Some(sum) }) = state {
+            self.add(*sum, 0);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Count {
+    count: u64,
+}
+
+impl Aggregator for Count {
+    fn add(&mut self, _value: f64, _time: u64) {
+        self.count += 1;
+    }
+
+    fn value(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.count as f64)
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Count(self.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Count(other) = state {
+            self.count += other.count;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Last {
+    last: Option<(u64, f64)>,
+}
+
+impl Aggregator for Last {
+    fn add(&mut self, value: f64, time: u64) {
+        match self.last {
+            Some((last_time, _)) if last_time > time => {}
+            _ => self.last = Some((time, value)),
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.last.map(|(_, value)| value)
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Last(self.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Last(Last {
+            last: Some((time, value)),
+        }) = state
+        {
+            self.add(*value, *time);
+        }
+    }
+}
+
+/// population standard deviation, using Welford's algorithm.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct StdDev {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Aggregator for StdDev {
+    fn add(&mut self, value: f64, _time: u64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn value(&self) -> Option<f64> {
+        (self.count > 0).then(|| (self.m2 / self.count as f64).sqrt())
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::StdDev(self.clone())
+    }
+
+    /// combines the means and squared distances of both (Chan et al.).
+    fn merge(&mut self, state: &AggregatorState) {
+        let other = match state {
+            AggregatorState::StdDev(other) if other.count > 0 => other,
+            _ => return,
+        };
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * (self.count * other.count) as f64 / count as f64;
+        self.count = count;
+    }
+}
+
+/// per second rate of a counter during the minute: the difference between
+/// the values with the latest and the earliest times, divided by the seconds
+/// between them. None until two values with different times were added. A
+/// counter reset during the minute is not detected, cumulative sums are
+/// already turned into rates (see `ValueExtractor`).
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Rate {
+    /// (time, value) of the earliest value.
+    first: Option<(u64, f64)>,
+    /// (time, value) of the latest value.
+    last: Option<(u64, f64)>,
+}
+
+impl Aggregator for Rate {
+    fn add(&mut self, value: f64, time: u64) {
+        match self.first {
+            Some((first_time, _)) if first_time <= time => {}
+            _ => self.first = Some((time, value)),
+        }
+        match self.last {
+            Some((last_time, _)) if last_time >= time => {}
+            _ => self.last = Some((time, value)),
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        let ((first_time, first), (last_time, last)) = (self.first?, self.last?);
+        (last_time > first_time)
+            .then(|| (last - first) / ((last_time - first_time) as f64 / 1000.0))
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Rate(self.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Rate(other) = state {
+            for (time, value) in other.first.iter().chain(other.last.iter()) {
+                self.add(*value, *time);
+            }
+        }
+    }
+}
+
+struct Percentile {
+    q: f64,
+    sketch: Sketch,
+}
+
+impl Percentile {
+    fn new(q: f64) -> Self {
+        Self {
+            q,
+            sketch: Sketch::default(),
+        }
+    }
+}
+
+impl Aggregator for Percentile {
+    fn add(&mut self, value: f64, _time: u64) {
+        self.sketch.add(value);
+    }
+
+    fn value(&self) -> Option<f64> {
+        self.sketch.quantile(self.q)
+    }
+
+    fn save(&self) -> AggregatorState {
+        AggregatorState::Percentile(self.sketch.clone())
+    }
+
+    fn merge(&mut self, state: &AggregatorState) {
+        if let AggregatorState::Percentile(sketch) = state {
+            self.sketch.merge(sketch);
+        }
+    }
+}
+
+/// Sketch is a mergeable quantile sketch with relative error guarantees
+/// (DDSketch). Values are counted in logarithmic buckets, so any quantile
+/// is within `RELATIVE_ACCURACY` of the real value.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+pub struct Sketch {
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl Sketch {
+    const RELATIVE_ACCURACY: f64 = 0.01;
+    /// values closer to zero than this are counted as zero.
+    const MIN_VALUE: f64 = 1e-9;
+
+    fn gamma() -> f64 {
+        (1.0 + Self::RELATIVE_ACCURACY) / (1.0 - Self::RELATIVE_ACCURACY)
+    }
+
+    fn index(value: f64) -> i32 {
+        (value.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    /// the value represented by a bucket, the middle of its bounds.
+    fn bucket_value(index: i32) -> f64 {
+        let gamma = Self::gamma();
+        2.0 * gamma.powi(index) / (gamma + 1.0)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        if value > Self::MIN_VALUE {
+            *self.positive.entry(Self::index(value)).or_default() += 1;
+        } else if value < -Self::MIN_VALUE {
+            *self.negative.entry(Self::index(-value)).or_default() += 1;
+        } else {
+            self.zero += 1;
+        }
+    }
+
+    /// adds all the values of the other sketch to this one.
+    pub fn merge(&mut self, other: &Sketch) {
+        for (index, count) in &other.positive {
+            *self.positive.entry(*index).or_default() += count;
+        }
+        for (index, count) in &other.negative {
+            *self.negative.entry(*index).or_default() += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+    }
+
+    /// the value at the quantile q (between 0 and 1), None if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+
+        let mut seen = 0;
+        // from the smallest to the biggest value
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(-Self::bucket_value(*index));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (index, count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return Some(Self::bucket_value(*index));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn aggregate(agg: Aggregation, values: &[f64]) -> Option<f64> {
+        let mut aggregator = new_aggregator(agg);
+        for (i, value) in values.iter().enumerate() {
+            aggregator.add(*value, i as u64);
+        }
+        aggregator.value()
+    }
+
+    #[test]
+    fn aggregations() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+
+        assert_eq!(Some(5.0), aggregate(Aggregation::Avg, &values));
+        assert_eq!(Some(9.0), aggregate(Aggregation::Max, &values));
+        assert_eq!(Some(2.0), aggregate(Aggregation::Min, &values));
+        assert_eq!(Some(40.0), aggregate(Aggregation::Sum, &values));
+        assert_eq!(Some(8.0), aggregate(Aggregation::Count, &values));
+        assert_eq!(Some(9.0), aggregate(Aggregation::Last, &values));
+        assert_eq!(Some(2.0), aggregate(Aggregation::StdDev, &values));
+        assert_eq!(None, aggregate(Aggregation::Max, &[]));
+        assert_eq!(None, aggregate(Aggregation::Count, &[]));
+    }
+
+    #[test]
+    fn merged_aggregators_have_the_values_of_both() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        for agg in [
+            Aggregation::Avg,
+            Aggregation::Max,
+            Aggregation::Min,
+            Aggregation::Sum,
+            Aggregation::Count,
+            Aggregation::Last,
+            Aggregation::StdDev,
+            Aggregation::P50,
+            Aggregation::P90,
+            Aggregation::P99,
+            Aggregation::Rate,
+        ] {
+            let mut first = new_aggregator(agg);
+            let mut second = new_aggregator(agg);
+            // interleaved, like a snapshot and the metrics replayed after it
+            for (i, value) in values.iter().enumerate() {
+                let aggregator = if i % 2 == 0 { &mut first } else { &mut second };
+                aggregator.add(*value, i as u64 * 1000);
+            }
+            first.merge(&second.save());
+            let expected = aggregate(agg, &values).map(|value| match agg {
+                // `aggregate` adds a value per millisecond
+                Aggregation::Rate => value / 1000.0,
+                _ => value,
+            });
+            let merged = first.value().unwrap();
+            assert!(
+                (merged - expected.unwrap()).abs() < 1e-9,
+                "{:?} = {}",
+                agg,
+                merged
+            );
+            // states of other aggregations are ignored
+            first.merge(&new_aggregator(Aggregation::Count).save());
+            assert_eq!(Some(merged), first.value(), "{:?}", agg);
+        }
+    }
+
+    #[test]
+    fn last_uses_the_most_recent_value() {
+        let mut aggregator = new_aggregator(Aggregation::Last);
+        aggregator.add(1.0, 20);
+        aggregator.add(2.0, 10);
+        assert_eq!(Some(1.0), aggregator.value());
+    }
+
+    #[test]
+    fn rate_of_a_counter() {
+        let mut rate = new_aggregator(Aggregation::Rate);
+        rate.add(100.0, 60_000);
+        assert_eq!(None, rate.value());
+        // 2 per second, added out of order
+        rate.add(220.0, 120_000);
+        rate.add(130.0, 75_000);
+        rate.add(160.0, 90_000);
+        assert_eq!(Some(2.0), rate.value());
+    }
+
+    #[test]
+    fn percentiles_are_within_the_relative_accuracy() {
+        let values: Vec<f64> = (1..=1000).map(|v| v as f64).collect();
+
+        for (agg, expected) in [
+            (Aggregation::P50, 500.0),
+            (Aggregation::P90, 900.0),
+            (Aggregation::P99, 990.0),
+        ] {
+            let value = aggregate(agg, &values).unwrap();
+            assert!(
+                (value - expected).abs() <= expected * Sketch::RELATIVE_ACCURACY,
+                "{:?} = {}",
+                agg,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn sketches_count_negative_values_and_zero() {
+        let mut sketch = Sketch::default();
+        for v in 1..=50 {
+            sketch.add(v as f64);
+            sketch.add(-(v as f64));
+        }
+        sketch.add(0.0);
+
+        assert_eq!(101, sketch.count);
+        assert_eq!(Some(0.0), sketch.quantile(0.5));
+        assert!((sketch.quantile(0.0).unwrap() + 50.0).abs() <= 0.5);
+        assert!((sketch.quantile(1.0).unwrap() - 50.0).abs() <= 0.5);
+    }
+
+    #[test]
+    fn merged_sketches_have_the_quantiles_of_a_single_sketch() {
+        let mut combined = Sketch::default();
+        let mut first = Sketch::default();
+        let mut second = Sketch::default();
+        for v in -200..=1000 {
+            let value = v as f64 * 1.5;
+            combined.add(value);
+            if v % 3 == 0 {
+                first.add(value);
+            } else {
+                second.add(value);
+            }
+        }
+        first.merge(&second);
+
+        assert_eq!(combined, first);
+        for q in [0.0, 0.1, 0.5, 0.9, 0.99, 1.0] {
+            assert_eq!(combined.quantile(q), first.quantile(q), "{}", q);
+        }
+        first.merge(&Sketch::default());
+        assert_eq!(combined, first);
+    }
+}
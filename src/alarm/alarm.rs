@@ -1,127 +1,1037 @@
+use crate::alarm::aggregator::{new_aggregator, Aggregator, AggregatorState};
+use crate::alarm::extractor::ValueExtractor;
+use crate::alarm::state::{AlarmState, AlarmStates, Evaluation, StateMachine, Status};
 use crate::model::{
-    alarm::Aggregation, alarm::CombinationAlarmConfig, alarm::LogicalOperator, alarm::Matcher,
-    alarm::TagBasedAlarmConfig, alarm::ThresholdType, metrics,
+    alarm::attribute_value, alarm::AlarmConfig, alarm::CombinationAlarmConfig,
+    alarm::CompositeAlarmConfig, alarm::LogicalOperator, alarm::Matcher, alarm::MissingData,
+    alarm::TagBasedAlarmConfig, metrics,
 };
-use chrono::{DateTime, TimeDelta, Utc};
-use std::collections::BTreeMap;
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Mutex};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use tracing::{event, Level};
 
-pub(crate) trait Alarm {
+pub(crate) trait Alarm: Send {
     /// consume a new metric, metric: returns true if it consumed it
     fn consume(&self, metric: &metrics::Metric) -> bool;
 
+    /// if `consume` would consume the metric, without consuming it.
+    fn uses(&self, metric: &metrics::Metric) -> bool;
+
     /// checks if should alarm / disable alarm and also cleans
-    /// old metrics from memory
-    fn tick(&self);
+    /// old metrics from memory. `states` has the status of the
+    /// dependencies of this alarm, they are always evaluated first.
+    fn tick(&self, states: &AlarmStates);
+
+    /// ids of the alarms whose state is used by this alarm.
+    fn dependencies(&self) -> Vec<String> {
+        vec![]
+    }
 
     /// returns the alarm identifier
     fn identifier(&self) -> String;
 
+    /// returns the current state of the alarm
+    fn status(&self) -> Status;
+
+    /// the configuration the alarm was created with.
+    fn config(&self) -> AlarmConfig;
+
+    /// the window and the state of the alarm, to keep them in a snapshot.
+    /// Null if there is nothing to keep.
+    fn save(&self) -> Result<Value, serde_json::Error> {
+        Ok(Value::Null)
+    }
+
+    /// restores what `save` returned for an alarm with the same
+    /// configuration, the window is merged with what the alarm already has.
+    /// It does not notify, the state was notified before it was saved.
+    fn restore(&self, _saved: Value) -> Result<(), serde_json::Error> {
+        Ok(())
+    }
+
     /// returns all metrics used in the alarm. Only for tests,
     /// may not be kept in production to reduce memory usage.
+    #[cfg(test)]
     fn metrics(&self) -> Vec<metrics::Metric>;
 }
 
-pub trait Notifier {
+pub trait Notifier: Send + Sync {
     fn notify(&self, description: String);
 }
 
 pub struct NoOpNotifier {}
 impl Notifier for NoOpNotifier {
-    fn notify(&self, description: String) {
+    fn notify(&self, _description: String) {
         // no_op
     }
 }
 
-/// Allow us to configure the alarms as `A or B`.
-pub type CombinationAlarmLogicalOperator = LogicalOperator<CombinationAlarm>;
+/// writes the notifications to our logs.
+pub struct LogNotifier {
+    pub level: Level,
+}
+
+impl Notifier for LogNotifier {
+    fn notify(&self, description: String) {
+        match self.level {
+            Level::ERROR => event!(Level::ERROR, "{}", description),
+            Level::WARN => event!(Level::WARN, "{}", description),
+            _ => event!(Level::INFO, "{}", description),
+        }
+    }
+}
+
+/// creates the alarm for the configuration.
+pub(crate) fn new_alarm(
+    id: String,
+    config: AlarmConfig,
+    notifier: Box<dyn Notifier>,
+) -> Box<dyn Alarm> {
+    match config {
+        AlarmConfig::TagBased(config) => Box::new(DataPointAlarm::new(id, config, notifier)),
+        AlarmConfig::Combination(config) => Box::new(CombinationAlarm::new(id, config, notifier)),
+        AlarmConfig::Composite(config) => Box::new(CompositeAlarm::new(id, config, notifier)),
+    }
+}
 
-// uses other alarms as base
-// alarm if A and B are alarming
+/// CombinationAlarm combines conditions on different metrics, e.g.
+/// `cpu.usage > 80 AND memory.usage > 80`. Each condition is evaluated
+/// over its own window, a condition without data is unknown.
 pub struct CombinationAlarm {
     id: String,
-    data_point_alarm: DataPointAlarm,
+    config: CombinationAlarmConfig,
+    alarm: LogicalOperator<DataPointAlarm>,
+    state: Mutex<StateMachine>,
+    notifier: Box<dyn Notifier>,
+}
+
+impl CombinationAlarm {
+    pub fn new(id: String, config: CombinationAlarmConfig, notifier: Box<dyn Notifier>) -> Self {
+        let mut leaf = 0;
+        let alarm = config.alarm.clone().map(&mut |config| {
+            leaf += 1;
+            DataPointAlarm::new(
+                format!("{}/{}", id, leaf),
+                config,
+                Box::new(NoOpNotifier {}),
+            )
+        });
+        Self {
+            id,
+            config,
+            alarm,
+            state: Mutex::new(StateMachine::new(Utc::now())),
+            notifier,
+        }
+    }
+
+    /// evaluates every condition at `now`, notifying only if the state changed.
+    fn tick_at(&self, now: DateTime<Utc>) {
+        for leaf in self.alarm.items() {
+            leaf.tick_at(now);
+        }
+
+        let evaluation = Evaluation::from_alarming(
+            self.alarm
+                .evaluate(&|leaf| leaf.status().state.is_alarming()),
+        );
+        let transition = self.state.lock().unwrap().apply(&self.id, evaluation, now);
+
+        if let Some(transition) = transition {
+            self.notifier.notify(transition.to_string());
+        }
+    }
+}
+
+impl Alarm for CombinationAlarm {
+    fn consume(&self, metric: &metrics::Metric) -> bool {
+        let mut consumed = false;
+        for leaf in self.alarm.items() {
+            consumed = leaf.consume(metric) || consumed;
+        }
+        consumed
+    }
+
+    fn uses(&self, metric: &metrics::Metric) -> bool {
+        self.alarm.items().iter().any(|leaf| leaf.uses(metric))
+    }
+
+    fn tick(&self, _states: &AlarmStates) {
+        self.tick_at(Utc::now());
+    }
+
+    fn identifier(&self) -> String {
+        self.id.clone()
+    }
+
+    fn status(&self) -> Status {
+        self.state.lock().unwrap().status()
+    }
+
+    fn config(&self) -> AlarmConfig {
+        AlarmConfig::Combination(self.config.clone())
+    }
+
+    fn save(&self) -> Result<Value, serde_json::Error> {
+        let leaves = self
+            .alarm
+            .items()
+            .into_iter()
+            .map(|leaf| leaf.save())
+            .collect::<Result<_, _>>()?;
+        serde_json::to_value(SavedCombinationAlarm {
+            leaves,
+            status: self.status(),
+        })
+    }
+
+    fn restore(&self, saved: Value) -> Result<(), serde_json::Error> {
+        let saved: SavedCombinationAlarm = serde_json::from_value(saved)?;
+        for (leaf, saved) in self.alarm.items().into_iter().zip(saved.leaves) {
+            leaf.restore(saved)?;
+        }
+        *self.state.lock().unwrap() = StateMachine::restore(saved.status);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn metrics(&self) -> Vec<metrics::Metric> {
+        self.alarm
+            .items()
+            .into_iter()
+            .flat_map(|leaf| leaf.metrics())
+            .collect()
+    }
+}
+
+/// CompositeAlarm reacts to the state of other alarms, e.g.
+/// `ALARM("db-latency") AND NOT ALARM("db-maintenance")`.
+/// Alarms that are not registered are unknown.
+pub struct CompositeAlarm {
+    id: String,
+    config: CompositeAlarmConfig,
+    state: Mutex<StateMachine>,
+    notifier: Box<dyn Notifier>,
+}
+
+impl CompositeAlarm {
+    pub fn new(id: String, config: CompositeAlarmConfig, notifier: Box<dyn Notifier>) -> Self {
+        Self {
+            id,
+            config,
+            state: Mutex::new(StateMachine::new(Utc::now())),
+            notifier,
+        }
+    }
+
+    fn tick_at(&self, states: &AlarmStates, now: DateTime<Utc>) {
+        let evaluation = Evaluation::from_alarming(
+            self.config
+                .alarm
+                .evaluate(&|id| states.get(id).and_then(|status| status.state.is_alarming())),
+        );
+        let transition = self.state.lock().unwrap().apply(&self.id, evaluation, now);
+
+        if let Some(transition) = transition {
+            self.notifier.notify(transition.to_string());
+        }
+    }
+}
+
+impl Alarm for CompositeAlarm {
+    fn consume(&self, _metric: &metrics::Metric) -> bool {
+        false
+    }
+
+    fn uses(&self, _metric: &metrics::Metric) -> bool {
+        false
+    }
+
+    fn tick(&self, states: &AlarmStates) {
+        self.tick_at(states, Utc::now());
+    }
+
+    fn identifier(&self) -> String {
+        self.id.clone()
+    }
+
+    fn status(&self) -> Status {
+        self.state.lock().unwrap().status()
+    }
+
+    fn config(&self) -> AlarmConfig {
+        AlarmConfig::Composite(self.config.clone())
+    }
+
+    fn save(&self) -> Result<Value, serde_json::Error> {
+        serde_json::to_value(self.status())
+    }
+
+    fn restore(&self, saved: Value) -> Result<(), serde_json::Error> {
+        *self.state.lock().unwrap() = StateMachine::restore(serde_json::from_value(saved)?);
+        Ok(())
+    }
+
+    fn dependencies(&self) -> Vec<String> {
+        self.config.alarm.items().into_iter().cloned().collect()
+    }
+
+    #[cfg(test)]
+    fn metrics(&self) -> Vec<metrics::Metric> {
+        vec![]
+    }
+}
+
+/// what a `CombinationAlarm` keeps in a snapshot, the leaves in the order
+/// of `LogicalOperator::items`.
+#[derive(Serialize, Deserialize)]
+struct SavedCombinationAlarm {
+    leaves: Vec<Value>,
+    status: Status,
+}
+
+/// A group of a `DataPointAlarm` (e.g. a single host), with its own window and state.
+struct Group {
+    //btreemap of time(round by minute), aggregated value of the minute
+    metrics: BTreeMap<u64, Box<dyn Aggregator>>,
+    state: StateMachine,
+    /// time of the last metric received, in milliseconds.
+    last_seen: u64,
+}
+
+impl Group {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            metrics: BTreeMap::new(),
+            state: StateMachine::new(now),
+            last_seen: 0,
+        }
+    }
+}
+
+/// what a `DataPointAlarm` keeps in a snapshot.
+#[derive(Serialize, Deserialize)]
+struct SavedDataPointAlarm {
+    groups: BTreeMap<String, SavedGroup>,
+    status: Status,
+    /// the last data point of the cumulative series, see `ValueExtractor`.
+    series: HashMap<String, metrics::MetricData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGroup {
+    metrics: BTreeMap<u64, AggregatorState>,
+    status: Status,
+    last_seen: u64,
+}
+
+/// used to report the worst state of the groups of an alarm.
+fn severity(state: AlarmState) -> u8 {
+    match state {
+        AlarmState::InsufficientData => 0,
+        AlarmState::Ok => 1,
+        AlarmState::Pending => 2,
+        AlarmState::Alarm => 3,
+    }
 }
 
 pub struct DataPointAlarm {
     id: String,
     config: TagBasedAlarmConfig,
-    //btreemap of time(round by minute), (quantity_of_metrics, aggregated_value)
-    metrics: Mutex<BTreeMap<u64, (u64, f64)>>,
-    is_alarming: AtomicBool,
+    /// groups by key (e.g. `host=web-1`), the key is empty without group_by.
+    groups: Mutex<BTreeMap<String, Group>>,
+    /// the worst state of all the groups.
+    state: Mutex<StateMachine>,
+    extractor: Mutex<ValueExtractor>,
     notifier: Box<dyn Notifier>,
+    /// every metric consumed, only kept in the tests (see `Alarm::metrics`).
+    #[cfg(test)]
+    consumed: Mutex<Vec<metrics::Metric>>,
+}
+
+impl DataPointAlarm {
+    /// groups (and cumulative series, see `ValueExtractor::expire`) that did
+    /// not receive metrics for this many windows are removed.
+    const GROUP_EXPIRY_WINDOWS: i64 = 2;
+
+    pub fn new(id: String, config: TagBasedAlarmConfig, notifier: Box<dyn Notifier>) -> Self {
+        let extractor = Mutex::new(ValueExtractor::new(config.statistic));
+        let now = Utc::now();
+        let mut groups = BTreeMap::new();
+        if config.group_by.is_empty() {
+            groups.insert(String::new(), Group::new(now));
+        }
+        Self {
+            id,
+            config,
+            groups: Mutex::new(groups),
+            state: Mutex::new(StateMachine::new(now)),
+            extractor,
+            notifier,
+            #[cfg(test)]
+            consumed: Mutex::new(vec![]),
+        }
+    }
+
+    /// e.g. `host=web-1,env=prod`, attributes missing in the metric are empty.
+    fn group_key(&self, metric: &metrics::Metric) -> String {
+        self.config
+            .group_by
+            .iter()
+            .map(|attribute| {
+                format!(
+                    "{}={}",
+                    attribute,
+                    attribute_value(metric, attribute).unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn group_id(&self, key: &str) -> String {
+        if key.is_empty() {
+            self.id.clone()
+        } else {
+            format!("{}{{{}}}", self.id, key)
+        }
+    }
+
+    /// evaluates the window of every group ending at the last minute
+    /// completed before `now`, notifying only the groups whose state changed.
+    fn tick_at(&self, now: DateTime<Utc>) {
+        let expiry =
+            now.timestamp_millis() - Self::GROUP_EXPIRY_WINDOWS * self.config.time_window * 60_000;
+        self.extractor.lock().unwrap().expire(expiry.max(0) as u64);
+
+        let mut groups = self.groups.lock().unwrap();
+        if !self.config.group_by.is_empty() {
+            groups.retain(|key, group| {
+                let expired = (group.last_seen as i64) < expiry;
+                if expired {
+                    event!(Level::INFO, "group {} expired", self.group_id(key));
+                    // a group still alarming (e.g. ignoring missing data) is
+                    // resolved before it disappears
+                    if group.state.status().state != AlarmState::Ok {
+                        let id = self.group_id(key);
+                        if let Some(transition) = group.state.apply(&id, Evaluation::NoData, now) {
+                            self.notifier.notify(transition.to_string());
+                        }
+                    }
+                }
+                !expired
+            });
+        }
+
+        let mut worst = AlarmState::InsufficientData;
+        for (key, group) in groups.iter_mut() {
+            let evaluation = self.evaluate(&mut group.metrics, now);
+            let transition = group.state.apply(&self.group_id(key), evaluation, now);
+
+            if let Some(transition) = transition {
+                self.notifier.notify(transition.to_string());
+            }
+            if severity(group.state.status().state) > severity(worst) {
+                worst = group.state.status().state;
+            }
+        }
+        self.state.lock().unwrap().set(worst, now);
+    }
+
+    fn evaluate(
+        &self,
+        metrics: &mut BTreeMap<u64, Box<dyn Aggregator>>,
+        now: DateTime<Utc>,
+    ) -> Evaluation {
+        // the minute in progress is not evaluated until it is complete, it
+        // would only hold the first values of the minute.
+        let current_minute = now.timestamp_millis().max(0) as u64 / 60_000 * 60_000;
+        let oldest_minute = current_minute.saturating_sub(self.config.time_window as u64 * 60_000);
+
+        // remove entries that are not relevant for our alarm
+        metrics.retain(|&k, _| k >= oldest_minute);
+
+        // one slot per completed minute of the window, None for the minutes without data.
+        let values: Vec<Option<f64>> = (1..=self.config.time_window as u64)
+            .rev()
+            .filter_map(|i| current_minute.checked_sub(i * 60_000))
+            .filter_map(
+                |minute| match metrics.get(&minute).and_then(|d| d.value()) {
+                    Some(value) => Some(Some(value)),
+                    None => match self.config.missing_data {
+                        MissingData::Breaching | MissingData::NotBreaching => Some(None),
+                        MissingData::Ignore | MissingData::Missing => None,
+                    },
+                },
+            )
+            .collect();
+
+        if values.is_empty() {
+            return match self.config.missing_data {
+                MissingData::Ignore => Evaluation::Unchanged,
+                _ => Evaluation::NoData,
+            };
+        }
+
+        let breaches = |value: &Option<f64>, threshold: f64| match value {
+            Some(v) => self.config.value_comp.breaches(*v, threshold),
+            None => self.config.missing_data == MissingData::Breaching,
+        };
+
+        let breaching = values
+            .iter()
+            .filter(|v| breaches(v, self.config.value))
+            .count();
+
+        // by default we only alarm if all data points within
+        // this time window are infringing the threshold.
+        let datapoints_to_alarm = self.config.datapoints_to_alarm.unwrap_or(values.len());
+        if breaching >= datapoints_to_alarm {
+            return Evaluation::Breaching;
+        }
+
+        // and only recover when all of them are within the recovery threshold.
+        let datapoints_to_recover = self.config.datapoints_to_recover.unwrap_or(values.len());
+        let recovery_value = self.config.recovery_value.unwrap_or(self.config.value);
+        let recovered = values.len() >= datapoints_to_recover
+            && values[values.len() - datapoints_to_recover..]
+                .iter()
+                .all(|v| !breaches(v, recovery_value));
+
+        if recovered {
+            Evaluation::Recovered
+        } else if breaching > 0 {
+            Evaluation::PartiallyBreaching
+        } else {
+            Evaluation::NotBreaching
+        }
+    }
 }
 
 impl Alarm for DataPointAlarm {
     fn consume(&self, metric: &metrics::Metric) -> bool {
         if self.config.metric_matches(metric) {
-            let value = match &metric.data {
-                metrics::MetricData::Gauge(data) => data.value,
-                _ => todo!(),
+            #[cfg(test)]
+            self.consumed.lock().unwrap().push(metric.clone());
+            // the metric is still consumed (and saved in the WAL) without a value,
+            // cumulative metrics need the previous data point
+            let value = match self.extractor.lock().unwrap().value(metric) {
+                Some(value) => value,
+                None => return true,
             };
-            self.metrics
-                .lock()
-                // TODO make sure time is always round to the minute
-                .unwrap()
-                .entry(metric.time)
-                .and_modify(|v| {
-                    v.1 = match self.config.agg {
-                        Aggregation::Max => f64::max(v.1, value),
-                        Aggregation::Min => f64::min(v.1, value),
-                        Aggregation::Avg => value + v.1,
-                    };
-                    v.0 += 1;
-                })
-                .or_insert((1, value));
+            // each minute is one data point
+            let minute = metric.time - metric.time % 60_000;
+            let mut groups = self.groups.lock().unwrap();
+            let group = groups
+                .entry(self.group_key(metric))
+                .or_insert_with(|| Group::new(Utc::now()));
+            group.last_seen = group.last_seen.max(metric.time);
+            group
+                .metrics
+                .entry(minute)
+                .or_insert_with(|| new_aggregator(self.config.agg))
+                .add(value, metric.time);
             true
         } else {
             false
         }
     }
 
-    //TODO we need a configuration for the alarm to become green
-    // because we may want to alarm with 5 data points, and only mark
-    // green after 10 data points
-    fn tick(&self) {
-        let oldest_possible_metric =
-            Utc::now().checked_sub_signed(TimeDelta::minutes(self.config.time_window));
-        let mut metrics = self.metrics.lock().unwrap();
-        let mut should_alarm = true;
+    fn uses(&self, metric: &metrics::Metric) -> bool {
+        self.config.metric_matches(metric)
+    }
 
-        // remove entries that are not relevant for our alarm
-        metrics.retain(|&k, _| DateTime::from_timestamp_millis(k as i64) > oldest_possible_metric);
+    fn tick(&self, _states: &AlarmStates) {
+        self.tick_at(Utc::now());
+    }
 
-        for (_, datapoint) in metrics.iter() {
-            let alarm_val = match self.config.agg {
-                Aggregation::Avg => datapoint.1 / datapoint.0 as f64,
-                _ => datapoint.1,
-            };
+    fn identifier(&self) -> String {
+        self.id.clone()
+    }
 
-            // we should only alarm if all data points within
-            // this time window are infringing the threshold.
-            should_alarm &= match self.config.value_comp {
-                ThresholdType::Eq => alarm_val == self.config.value,
-                ThresholdType::NotEq => alarm_val != self.config.value,
-                ThresholdType::LessThan => alarm_val < self.config.value,
-                ThresholdType::GreaterThan => alarm_val > self.config.value,
-            };
+    fn status(&self) -> Status {
+        self.state.lock().unwrap().status()
+    }
+
+    fn config(&self) -> AlarmConfig {
+        AlarmConfig::TagBased(self.config.clone())
+    }
+
+    fn save(&self) -> Result<Value, serde_json::Error> {
+        let groups = self
+            .groups
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, group)| {
+                let saved = SavedGroup {
+                    metrics: group
+                        .metrics
+                        .iter()
+                        .map(|(minute, aggregator)| (*minute, aggregator.save()))
+                        .collect(),
+                    status: group.state.status(),
+                    last_seen: group.last_seen,
+                };
+                (key.clone(), saved)
+            })
+            .collect();
+        serde_json::to_value(SavedDataPointAlarm {
+            groups,
+            status: self.status(),
+            series: self.extractor.lock().unwrap().save(),
+        })
+    }
+
+    fn restore(&self, saved: Value) -> Result<(), serde_json::Error> {
+        let saved: SavedDataPointAlarm = serde_json::from_value(saved)?;
+        self.extractor.lock().unwrap().merge(saved.series);
+        let mut groups = self.groups.lock().unwrap();
+        for (key, saved) in saved.groups {
+            let group = groups.entry(key).or_insert_with(|| Group::new(Utc::now()));
+            for (minute, state) in saved.metrics {
+                group
+                    .metrics
+                    .entry(minute)
+                    .or_insert_with(|| new_aggregator(self.config.agg))
+                    .merge(&state);
+            }
+            group.state = StateMachine::restore(saved.status);
+            group.last_seen = group.last_seen.max(saved.last_seen);
+        }
+        *self.state.lock().unwrap() = StateMachine::restore(saved.status);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn metrics(&self) -> Vec<metrics::Metric> {
+        self.consumed.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::alarm::{
+        Aggregation, Match, MatchType, Statistic, ThresholdType, METRIC_NAME_ATTRIBUTE,
+    };
+    use chrono::TimeDelta;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct RecordingNotifier {
+        notifications: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, description: String) {
+            self.notifications.lock().unwrap().push(description);
         }
+    }
 
-        if should_alarm {
-            // https://doc.rust-lang.org/std/sync/atomic/enum.Ordering.html
-            self.is_alarming.store(true, Ordering::Relaxed);
-            self.notifier.notify(
-                //TODO
-                "should configure how to build this string later".to_string(),
-            );
+    fn gauge_at(name: &str, value: f64, time: DateTime<Utc>) -> metrics::Metric {
+        let time = time.timestamp_millis() as u64;
+        metrics::Metric {
+            name: name.to_string(),
+            unit: "%".to_string(),
+            data: metrics::MetricData::Gauge(metrics::DataPoint {
+                start_time: time,
+                time,
+                value,
+            }),
+            time,
+            attributes: HashMap::new(),
         }
     }
 
-    fn identifier(&self) -> String {
-        self.id.clone()
+    fn gauge(name: &str, value: f64) -> metrics::Metric {
+        gauge_at(name, value, DateTime::UNIX_EPOCH)
     }
 
-    fn metrics(&self) -> Vec<metrics::Metric> {
-        todo!()
+    /// metric_name = name, max > 80 over 5 minutes
+    fn above_80(name: &str) -> TagBasedAlarmConfig {
+        TagBasedAlarmConfig {
+            matchers: vec![Match::new(
+                METRIC_NAME_ATTRIBUTE.to_string(),
+                MatchType::Eq,
+                name.to_string(),
+            )
+            .unwrap()],
+            agg: Aggregation::Max,
+            value: 80.0,
+            value_comp: ThresholdType::GreaterThan,
+            time_window: 5,
+            datapoints_to_alarm: None,
+            datapoints_to_recover: None,
+            recovery_value: None,
+            missing_data: MissingData::Missing,
+            statistic: Statistic::Value,
+            group_by: vec![],
+        }
+    }
+
+    fn cpu_alarm(notifier: Box<dyn Notifier>) -> DataPointAlarm {
+        DataPointAlarm::new("cpu".to_string(), above_80("cpu.usage"), notifier)
+    }
+
+    #[test]
+    fn data_point_alarm_only_consumes_matching_metrics() {
+        let alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+
+        assert!(alarm.consume(&gauge("cpu.usage", 90.0)));
+        assert!(!alarm.consume(&gauge("mem.usage", 90.0)));
+        assert_eq!(1, alarm.groups.lock().unwrap()[""].metrics.len());
+        assert_eq!(vec![gauge("cpu.usage", 90.0)], alarm.metrics());
+    }
+
+    #[test]
+    fn data_point_alarm_state_machine() {
+        let notifier = RecordingNotifier::default();
+        let alarm = cpu_alarm(Box::new(notifier.clone()));
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+
+        // empty window
+        alarm.tick_at(start);
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+        assert!(notifier.notifications.lock().unwrap().is_empty());
+
+        alarm.consume(&gauge_at("cpu.usage", 50.0, minute(0)));
+        alarm.tick_at(minute(1));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+
+        alarm.consume(&gauge_at("cpu.usage", 90.0, minute(1)));
+        alarm.tick_at(minute(2));
+        assert_eq!(AlarmState::Pending, alarm.status().state);
+
+        // the non breaching data point leaves the window
+        alarm.consume(&gauge_at("cpu.usage", 95.0, minute(5)));
+        alarm.tick_at(minute(6));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+        assert_eq!(minute(6), alarm.status().since);
+
+        // no notification while nothing changes
+        alarm.tick_at(minute(6));
+        alarm.consume(&gauge_at("cpu.usage", 50.0, minute(6)));
+        alarm.tick_at(minute(7));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        alarm.consume(&gauge_at("cpu.usage", 50.0, minute(10)));
+        alarm.tick_at(minute(11));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+
+        alarm.tick_at(minute(21));
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+
+        let notifications = notifier.notifications.lock().unwrap();
+        assert_eq!(5, notifications.len());
+        assert!(notifications[2].contains("from PENDING to ALARM"));
+    }
+
+    #[test]
+    fn data_point_alarm_m_out_of_n_with_recovery_threshold() {
+        let mut alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+        alarm.config.datapoints_to_alarm = Some(2);
+        alarm.config.datapoints_to_recover = Some(2);
+        alarm.config.recovery_value = Some(70.0);
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+
+        alarm.consume(&gauge_at("cpu.usage", 90.0, minute(0)));
+        alarm.consume(&gauge_at("cpu.usage", 50.0, minute(1)));
+        alarm.tick_at(minute(2));
+        assert_eq!(AlarmState::Pending, alarm.status().state);
+
+        // 2 out of 3 data points are breaching
+        alarm.consume(&gauge_at("cpu.usage", 85.0, minute(2)));
+        alarm.tick_at(minute(3));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        // below the threshold but above the recovery threshold
+        alarm.consume(&gauge_at("cpu.usage", 75.0, minute(5)));
+        alarm.consume(&gauge_at("cpu.usage", 75.0, minute(6)));
+        alarm.tick_at(minute(7));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        alarm.consume(&gauge_at("cpu.usage", 60.0, minute(7)));
+        alarm.tick_at(minute(8));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        alarm.consume(&gauge_at("cpu.usage", 65.0, minute(8)));
+        alarm.tick_at(minute(9));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+    }
+
+    #[test]
+    fn data_point_alarm_only_evaluates_completed_minutes() {
+        let alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+
+        alarm.consume(&gauge_at("cpu.usage", 50.0, minute(0)));
+        alarm.consume(&gauge_at("cpu.usage", 95.0, minute(1)));
+        // one second into minute 1, only minute 0 is complete
+        alarm.tick_at(minute(1) + TimeDelta::seconds(1));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+
+        // the data point of minute 1 was kept until it completed
+        alarm.tick_at(minute(2) + TimeDelta::seconds(1));
+        assert_eq!(AlarmState::Pending, alarm.status().state);
+    }
+
+    #[test]
+    fn data_points_are_bucketed_by_minute() {
+        let alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+
+        alarm.consume(&gauge_at("cpu.usage", 90.0, start));
+        alarm.consume(&gauge_at("cpu.usage", 95.0, start + TimeDelta::seconds(30)));
+        alarm.consume(&gauge_at("cpu.usage", 95.0, start + TimeDelta::seconds(60)));
+        assert_eq!(2, alarm.groups.lock().unwrap()[""].metrics.len());
+    }
+
+    #[test]
+    fn data_point_alarm_missing_data() {
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+
+        // (policy, state with a single breaching data point, state with an empty window)
+        for (missing_data, with_gaps, empty) in [
+            (MissingData::Breaching, AlarmState::Alarm, AlarmState::Alarm),
+            (
+                MissingData::NotBreaching,
+                AlarmState::Pending,
+                AlarmState::Ok,
+            ),
+            (MissingData::Ignore, AlarmState::Alarm, AlarmState::Alarm),
+            (
+                MissingData::Missing,
+                AlarmState::Alarm,
+                AlarmState::InsufficientData,
+            ),
+        ] {
+            let mut alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+            alarm.config.missing_data = missing_data;
+
+            alarm.consume(&gauge_at("cpu.usage", 90.0, minute(0)));
+            alarm.tick_at(minute(1));
+            assert_eq!(with_gaps, alarm.status().state, "{:?}", missing_data);
+
+            alarm.tick_at(minute(11));
+            assert_eq!(empty, alarm.status().state, "{:?}", missing_data);
+        }
+    }
+
+    #[test]
+    fn combination_alarm() {
+        let notifier = RecordingNotifier::default();
+        let leaf = |name| Box::new(LogicalOperator::Identity(Box::new(above_80(name))));
+        let alarm = CombinationAlarm::new(
+            "cpu_and_mem".to_string(),
+            CombinationAlarmConfig {
+                alarm: LogicalOperator::And(leaf("cpu.usage"), leaf("mem.usage")),
+                time_window: 5,
+            },
+            Box::new(notifier.clone()),
+        );
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+
+        // alarming AND unknown is unknown
+        assert!(alarm.consume(&gauge_at("cpu.usage", 90.0, minute(0))));
+        alarm.tick_at(minute(1));
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+
+        alarm.consume(&gauge_at("mem.usage", 90.0, minute(1)));
+        alarm.tick_at(minute(2));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        // each leaf keeps its own window
+        alarm.consume(&gauge_at("mem.usage", 50.0, minute(6)));
+        alarm.tick_at(minute(7));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+
+        // ok AND unknown is ok
+        alarm.tick_at(minute(11));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+
+        assert!(!alarm.consume(&gauge_at("disk.usage", 90.0, minute(10))));
+        assert_eq!(2, notifier.notifications.lock().unwrap().len());
+
+        let names: Vec<String> = alarm.metrics().into_iter().map(|m| m.name).collect();
+        assert_eq!(vec!["cpu.usage", "mem.usage", "mem.usage"], names);
+    }
+
+    #[test]
+    fn composite_alarm() {
+        let notifier = RecordingNotifier::default();
+        let id = |id: &str| Box::new(LogicalOperator::Identity(Box::new(id.to_string())));
+        let alarm = CompositeAlarm::new(
+            "db".to_string(),
+            CompositeAlarmConfig {
+                alarm: LogicalOperator::And(
+                    id("db-latency"),
+                    Box::new(LogicalOperator::Not(id("db-maintenance"))),
+                ),
+            },
+            Box::new(notifier.clone()),
+        );
+        assert_eq!(vec!["db-latency", "db-maintenance"], alarm.dependencies());
+
+        let now = DateTime::from_timestamp(3600, 0).unwrap();
+        let status = |state| Status { state, since: now };
+        let mut states = AlarmStates::new();
+
+        states.insert("db-latency".to_string(), status(AlarmState::Alarm));
+        alarm.tick_at(&states, now);
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+
+        states.insert("db-maintenance".to_string(), status(AlarmState::Ok));
+        alarm.tick_at(&states, now);
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        states.insert("db-maintenance".to_string(), status(AlarmState::Alarm));
+        alarm.tick_at(&states, now);
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+        assert_eq!(2, notifier.notifications.lock().unwrap().len());
+    }
+
+    #[test]
+    fn data_point_alarm_group_by() {
+        let notifier = RecordingNotifier::default();
+        let mut config = above_80("cpu.usage");
+        config.group_by = vec!["host".to_string()];
+        let alarm = DataPointAlarm::new("cpu".to_string(), config, Box::new(notifier.clone()));
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+        let host = |host: &str, value, time| {
+            let mut metric = gauge_at("cpu.usage", value, time);
+            metric
+                .attributes
+                .insert("host".to_string(), host.to_string());
+            metric
+        };
+
+        alarm.tick_at(minute(1));
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+
+        alarm.consume(&host("web-1", 90.0, minute(0)));
+        alarm.consume(&host("web-2", 50.0, minute(0)));
+        alarm.tick_at(minute(1));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+        {
+            let notifications = notifier.notifications.lock().unwrap();
+            assert_eq!(2, notifications.len());
+            assert!(notifications[0].starts_with("alarm cpu{host=web-1} changed"));
+            assert!(notifications[0].contains("to ALARM"));
+            assert!(notifications[1].starts_with("alarm cpu{host=web-2} changed"));
+            assert!(notifications[1].contains("to OK"));
+        }
+
+        // web-1 stops reporting
+        alarm.consume(&host("web-2", 50.0, minute(9)));
+        alarm.tick_at(minute(10));
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+        assert_eq!(2, alarm.groups.lock().unwrap().len());
+
+        alarm.consume(&host("web-2", 50.0, minute(11)));
+        alarm.tick_at(minute(12));
+        assert_eq!(
+            vec!["host=web-2"],
+            alarm.groups.lock().unwrap().keys().collect::<Vec<_>>()
+        );
+        assert_eq!(AlarmState::Ok, alarm.status().state);
+    }
+
+    #[test]
+    fn expired_groups_notify_before_being_removed() {
+        let notifier = RecordingNotifier::default();
+        let mut config = above_80("cpu.usage");
+        config.group_by = vec!["host".to_string()];
+        // the group stays in ALARM once it stops reporting
+        config.missing_data = MissingData::Ignore;
+        let alarm = DataPointAlarm::new("cpu".to_string(), config, Box::new(notifier.clone()));
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+        let mut metric = gauge_at("cpu.usage", 90.0, minute(0));
+        metric
+            .attributes
+            .insert("host".to_string(), "web-1".to_string());
+
+        alarm.consume(&metric);
+        alarm.tick_at(minute(1));
+        alarm.tick_at(minute(10));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        alarm.tick_at(minute(12));
+        assert!(alarm.groups.lock().unwrap().is_empty());
+        assert_eq!(AlarmState::InsufficientData, alarm.status().state);
+        let notifications = notifier.notifications.lock().unwrap();
+        assert_eq!(2, notifications.len());
+        assert!(notifications[1].starts_with("alarm cpu{host=web-1} changed"));
+        assert!(notifications[1].contains("from ALARM to INSUFFICIENT_DATA"));
+    }
+
+    #[test]
+    fn saved_alarms_are_restored_without_notifying() {
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        let minute = |m| start + TimeDelta::minutes(m);
+        let alarm = cpu_alarm(Box::new(NoOpNotifier {}));
+        alarm.consume(&gauge_at("cpu.usage", 90.0, minute(0)));
+        alarm.consume(&gauge_at("cpu.usage", 95.0, minute(1)));
+        alarm.tick_at(minute(2));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+        let saved = alarm.save().unwrap();
+
+        let notifier = RecordingNotifier::default();
+        let restored = cpu_alarm(Box::new(notifier.clone()));
+        // replayed from the WAL after the snapshot
+        restored.consume(&gauge_at("cpu.usage", 85.0, minute(1)));
+        restored.restore(saved).unwrap();
+        assert_eq!(alarm.status(), restored.status());
+
+        restored.tick_at(minute(2));
+        assert_eq!(AlarmState::Alarm, restored.status().state);
+        assert_eq!(minute(2), restored.status().since);
+        assert!(notifier.notifications.lock().unwrap().is_empty());
+        // the data point of minute 1 has both values
+        let groups = restored.groups.lock().unwrap();
+        let minute_1 = minute(1).timestamp_millis() as u64;
+        assert_eq!(Some(95.0), groups[""].metrics[&minute_1].value());
+        assert!(restored.restore(Value::Null).is_err());
+    }
+
+    #[test]
+    fn saved_combination_alarms_are_restored() {
+        let leaf = |name| Box::new(LogicalOperator::Identity(Box::new(above_80(name))));
+        let config = CombinationAlarmConfig {
+            alarm: LogicalOperator::And(leaf("cpu.usage"), leaf("mem.usage")),
+            time_window: 5,
+        };
+        let alarm = CombinationAlarm::new(
+            "cpu_and_mem".to_string(),
+            config.clone(),
+            Box::new(NoOpNotifier {}),
+        );
+        let start = DateTime::from_timestamp(3600, 0).unwrap();
+        alarm.consume(&gauge_at("cpu.usage", 90.0, start));
+        alarm.consume(&gauge_at("mem.usage", 90.0, start));
+        alarm.tick_at(start + TimeDelta::minutes(1));
+        assert_eq!(AlarmState::Alarm, alarm.status().state);
+
+        let notifier = RecordingNotifier::default();
+        let restored = CombinationAlarm::new(
+            "cpu_and_mem".to_string(),
+            config,
+            Box::new(notifier.clone()),
+        );
+        restored.restore(alarm.save().unwrap()).unwrap();
+        restored.tick_at(start + TimeDelta::minutes(2));
+        assert_eq!(AlarmState::Alarm, restored.status().state);
+        assert!(notifier.notifications.lock().unwrap().is_empty());
     }
 }
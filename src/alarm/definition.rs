@@ -0,0 +1,478 @@
+use crate::alarm::alarm::{new_alarm, Alarm, LogNotifier, NoOpNotifier, Notifier};
+use crate::alarm::service::AlarmService;
+use crate::model::dsl;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{event, Level};
+
+// named like `wal::Error`
+#[allow(clippy::enum_variant_names)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Could not read alarm definitions {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid YAML alarm definitions {0}")]
+    YamlError(#[from] serde_yaml::Error),
+    #[error("Invalid TOML alarm definitions {0}")]
+    TomlError(#[from] toml::de::Error),
+    #[error("Unsupported alarm definitions file {0}, expected .yaml, .yml or .toml")]
+    UnsupportedFormat(String),
+    #[error("Invalid alarm {id}: {source}")]
+    InvalidAlarm { id: String, source: dsl::Error },
+    #[error("Alarm {0} is defined more than once")]
+    DuplicatedAlarm(String),
+    #[error("Alarm {id} uses the unknown notifier {notifier}")]
+    UnknownNotifier { id: String, notifier: String },
+}
+
+/// Definitions is the content of an alarm definitions file, e.g.
+///
+/// ```yaml
+/// notifiers:
+///   ops:
+///     type: log
+///     level: warn
+/// alarms:
+///   - id: cpu-high
+///     expression: max(cpu.usage, env=prod) > 80 over 5m by (host)
+///     notifiers: [ops]
+///     labels:
+///       team: infra
+/// ```
+#[derive(Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Definitions {
+    #[serde(default)]
+    pub notifiers: BTreeMap<String, NotifierDefinition>,
+    #[serde(default)]
+    pub alarms: Vec<AlarmDefinition>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AlarmDefinition {
+    pub id: String,
+    /// the alarm written in our DSL (see `model::dsl`).
+    pub expression: String,
+    /// names of the notifiers receiving the state changes of the alarm.
+    #[serde(default)]
+    pub notifiers: Vec<String>,
+    /// added to every notification of the alarm.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum NotifierDefinition {
+    /// writes the notifications to our logs.
+    Log {
+        #[serde(default)]
+        level: LogLevel,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Info,
+    #[default]
+    Warn,
+    Error,
+}
+
+impl Definitions {
+    /// reads the definitions, the format is based on the extension of the file.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Ok(serde_yaml::from_str(&content)?),
+            Some("toml") => Ok(toml::from_str(&content)?),
+            _ => Err(Error::UnsupportedFormat(path.display().to_string())),
+        }
+    }
+
+    /// validates every alarm, returning them by id together with their notifiers.
+    fn resolve(&self) -> Result<BTreeMap<String, ResolvedDefinition>, Error> {
+        let mut resolved = BTreeMap::new();
+        for alarm in &self.alarms {
+            dsl::parse(&alarm.expression).map_err(|source| Error::InvalidAlarm {
+                id: alarm.id.clone(),
+                source,
+            })?;
+            let notifiers = alarm
+                .notifiers
+                .iter()
+                .map(|name| {
+                    self.notifiers
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| Error::UnknownNotifier {
+                            id: alarm.id.clone(),
+                            notifier: name.clone(),
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+
+            let definition = ResolvedDefinition {
+                alarm: alarm.clone(),
+                notifiers,
+            };
+            if resolved.insert(alarm.id.clone(), definition).is_some() {
+                return Err(Error::DuplicatedAlarm(alarm.id.clone()));
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// an alarm and the notifiers it uses, an alarm changes if any of them changes.
+#[derive(Clone, PartialEq, Debug)]
+struct ResolvedDefinition {
+    alarm: AlarmDefinition,
+    notifiers: Vec<NotifierDefinition>,
+}
+
+impl ResolvedDefinition {
+    fn build(&self) -> Box<dyn Alarm> {
+        // the expression was validated when resolved
+        let config = dsl::parse(&self.alarm.expression).unwrap();
+        let targets = self
+            .notifiers
+            .iter()
+            .map(|notifier| match notifier {
+                NotifierDefinition::Log { level } => Arc::new(LogNotifier {
+                    level: match level {
+                        LogLevel::Info => Level::INFO,
+                        LogLevel::Warn => Level::WARN,
+                        LogLevel::Error => Level::ERROR,
+                    },
+                }) as Arc<dyn Notifier>,
+            })
+            .collect();
+        let notifier: Box<dyn Notifier> =
+            if self.notifiers.is_empty() && self.alarm.labels.is_empty() {
+                Box::new(NoOpNotifier {})
+            } else {
+                Box::new(RoutedNotifier {
+                    labels: self.alarm.labels.clone(),
+                    targets,
+                })
+            };
+        new_alarm(self.alarm.id.clone(), config, notifier)
+    }
+}
+
+/// adds the labels of the alarm to the notification and sends it to every notifier.
+struct RoutedNotifier {
+    labels: BTreeMap<String, String>,
+    targets: Vec<Arc<dyn Notifier>>,
+}
+
+impl Notifier for RoutedNotifier {
+    fn notify(&self, description: String) {
+        let description = if self.labels.is_empty() {
+            description
+        } else {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            format!("{} [{}]", description, labels.join(", "))
+        };
+        for target in &self.targets {
+            target.notify(description.clone());
+        }
+    }
+}
+
+/// what changed after reloading the definitions.
+#[derive(Default, PartialEq, Debug)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub replaced: Vec<String>,
+}
+
+/// AlarmDefinitions keeps track of the alarms loaded from a definitions file,
+/// so that reloading it only touches the alarms that changed.
+pub struct AlarmDefinitions {
+    path: PathBuf,
+    loaded: BTreeMap<String, ResolvedDefinition>,
+}
+
+impl AlarmDefinitions {
+    /// loads the file, returning the alarms to start the `AlarmService` with.
+    pub(crate) fn load(path: PathBuf) -> Result<(Self, Vec<Box<dyn Alarm>>), Error> {
+        let loaded = Definitions::from_file(&path)?.resolve()?;
+        let alarms = loaded.values().map(|d| d.build()).collect();
+        Ok((Self { path, loaded }, alarms))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// reads the file again and applies the differences to the service.
+    /// Alarms that did not change keep their windows and states. If the file is
+    /// invalid nothing changes.
+    pub fn reload(&mut self, alarm_service: &mut AlarmService) -> Result<Changes, Error> {
+        let definitions = Definitions::from_file(&self.path)?.resolve()?;
+        let mut changes = Changes::default();
+
+        let ids: HashSet<&String> = definitions.keys().collect();
+        for id in self.loaded.keys().filter(|id| !ids.contains(id)) {
+            // an alarm managed through the admin API is not ours to delete
+            if alarm_service.delete(id) {
+                changes.removed.push(id.clone());
+            }
+        }
+
+        let mut loaded = BTreeMap::new();
+        for (id, definition) in definitions {
+            if alarm_service.is_managed(&id) {
+                event!(
+                    Level::ERROR,
+                    "alarm {} is managed through the admin API, its definition is ignored",
+                    id
+                );
+                continue;
+            }
+            match self.loaded.get(&id) {
+                Some(previous) if *previous == definition => {
+                    loaded.insert(id, definition);
+                    continue;
+                }
+                Some(_) => changes.replaced.push(id.clone()),
+                None => changes.added.push(id.clone()),
+            }
+            match alarm_service.add(definition.build()) {
+                Ok(()) => {
+                    loaded.insert(id, definition);
+                }
+                // the previous version of the alarm is kept, but we forget its
+                // definition so that it is added again on the next reload
+                Err(e) => event!(Level::ERROR, "could not add alarm {}: {}", id, e),
+            }
+        }
+        self.loaded = loaded;
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alarm::service::Config;
+    use crate::alarm::state::AlarmState;
+    use crate::model::metrics::{DataPoint, Metric, MetricData};
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use temp_dir::TempDir;
+
+    const YAML: &str = r#"
+notifiers:
+  ops:
+    type: log
+    level: error
+alarms:
+  - id: cpu-high
+    expression: max(cpu.usage) > 80 over 5m
+    notifiers: [ops]
+    labels:
+      team: infra
+  - id: memory-high
+    expression: avg(memory.usage) > 90
+"#;
+
+    const TOML: &str = r#"
+[notifiers.ops]
+type = "log"
+
+[[alarms]]
+id = "cpu-high"
+expression = "max(cpu.usage) > 80 over 5m"
+notifiers = ["ops"]
+labels = { team = "infra" }
+"#;
+
+    fn write(dir: &TempDir, name: &str, content: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn alarm_service(dir: &TempDir, alarms: Vec<Box<dyn Alarm>>) -> AlarmService {
+        let storage_path = dir.path().join("wal");
+        std::fs::create_dir(&storage_path).unwrap();
+        AlarmService::new(Config::new(storage_path), alarms).unwrap()
+    }
+
+    /// a data point of the last completed minute, the only one evaluated.
+    fn cpu(value: f64) -> Metric {
+        let now = Utc::now().timestamp_millis() as u64 - 60_000;
+        Metric {
+            name: "cpu.usage".to_string(),
+            unit: "%".to_string(),
+            data: MetricData::Gauge(DataPoint {
+                start_time: 0,
+                time: now,
+                value,
+            }),
+            time: now,
+            attributes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_yaml_and_toml() {
+        let dir = TempDir::new().unwrap();
+
+        let yaml = Definitions::from_file(&write(&dir, "alarms.yaml", YAML)).unwrap();
+        assert_eq!(2, yaml.alarms.len());
+        assert_eq!(
+            Some(&NotifierDefinition::Log {
+                level: LogLevel::Error
+            }),
+            yaml.notifiers.get("ops")
+        );
+        assert_eq!(vec!["ops".to_string()], yaml.alarms[0].notifiers);
+        assert_eq!(
+            Some("infra"),
+            yaml.alarms[0].labels.get("team").map(|s| s.as_str())
+        );
+
+        let toml = Definitions::from_file(&write(&dir, "alarms.toml", TOML)).unwrap();
+        assert_eq!(yaml.alarms[0], toml.alarms[0]);
+        assert_eq!(
+            Some(&NotifierDefinition::Log {
+                level: LogLevel::Warn
+            }),
+            toml.notifiers.get("ops")
+        );
+
+        assert!(matches!(
+            Definitions::from_file(&write(&dir, "alarms.json", "{}")),
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        let dir = TempDir::new().unwrap();
+        let invalid = |content: &str| {
+            let path = write(&dir, "alarms.yaml", content);
+            AlarmDefinitions::load(path).err().unwrap()
+        };
+
+        assert!(matches!(
+            invalid("alarms:\n  - id: a\n    expression: max(cpu) >"),
+            Error::InvalidAlarm { .. }
+        ));
+        assert!(matches!(
+            invalid("alarms:\n  - id: a\n    expression: max(cpu) > 1\n    notifiers: [ops]"),
+            Error::UnknownNotifier { .. }
+        ));
+        assert!(matches!(
+            invalid("alarms:\n  - id: a\n    expression: max(cpu) > 1\n  - id: a\n    expression: max(cpu) > 2"),
+            Error::DuplicatedAlarm(_)
+        ));
+    }
+
+    #[test]
+    fn reload_only_replaces_changed_alarms() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "alarms.yaml", YAML);
+        let (mut definitions, alarms) =
+            AlarmDefinitions::load(dir.path().join("alarms.yaml")).unwrap();
+        let mut alarm_service = alarm_service(&dir, alarms);
+        alarm_service.consume(cpu(95.0)).unwrap();
+        alarm_service.tick();
+        let cpu_high = alarm_service.alarm("cpu-high").unwrap().status();
+        assert_ne!(AlarmState::InsufficientData, cpu_high.state);
+
+        // memory-high is removed, cpu-high is unchanged and disk-high is new
+        let yaml = YAML.replace(
+            "memory-high\n    expression: avg(memory.usage) > 90",
+            "disk-high\n    expression: avg(disk.usage) > 90",
+        );
+        write(&dir, "alarms.yaml", &yaml);
+        let changes = definitions.reload(&mut alarm_service).unwrap();
+        assert_eq!(
+            Changes {
+                added: vec!["disk-high".to_string()],
+                removed: vec!["memory-high".to_string()],
+                replaced: vec![],
+            },
+            changes
+        );
+        // the window of cpu-high was kept
+        alarm_service.tick();
+        assert_eq!(cpu_high, alarm_service.alarm("cpu-high").unwrap().status());
+
+        // changing the notifiers used by an alarm replaces it
+        write(
+            &dir,
+            "alarms.yaml",
+            &yaml.replace("level: error", "level: info"),
+        );
+        let changes = definitions.reload(&mut alarm_service).unwrap();
+        assert_eq!(vec!["cpu-high".to_string()], changes.replaced);
+        alarm_service.tick();
+        assert_eq!(
+            AlarmState::InsufficientData,
+            alarm_service.alarm("cpu-high").unwrap().status().state
+        );
+
+        // an invalid file changes nothing
+        write(&dir, "alarms.yaml", "alarms: [");
+        assert!(definitions.reload(&mut alarm_service).is_err());
+        assert!(alarm_service.alarm("disk-high").is_some());
+        assert!(alarm_service.alarm("cpu-high").is_some());
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_the_alarms_managed_through_the_admin_api() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "alarms.yaml", "alarms: []");
+        let (mut definitions, alarms) =
+            AlarmDefinitions::load(dir.path().join("alarms.yaml")).unwrap();
+        let mut alarm_service = alarm_service(&dir, alarms);
+        let expression = "max(cpu.usage) > 95 over 5m";
+        alarm_service
+            .create_alarm("cpu-high".to_string(), dsl::parse(expression).unwrap())
+            .unwrap()
+            .await
+            .unwrap();
+
+        // defined in the file too
+        write(&dir, "alarms.yaml", YAML);
+        let changes = definitions.reload(&mut alarm_service).unwrap();
+        assert_eq!(vec!["memory-high".to_string()], changes.added);
+        assert_eq!(
+            expression,
+            alarm_service
+                .alarm("cpu-high")
+                .unwrap()
+                .config()
+                .to_string()
+        );
+
+        write(&dir, "alarms.yaml", "alarms: []");
+        let changes = definitions.reload(&mut alarm_service).unwrap();
+        assert_eq!(vec!["memory-high".to_string()], changes.removed);
+        assert!(alarm_service.is_managed("cpu-high"));
+        assert_eq!(
+            expression,
+            alarm_service
+                .alarm("cpu-high")
+                .unwrap()
+                .config()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::alarm::service::AlarmService;
+use crate::server;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, RwLock};
+use tracing::{event, Level};
+
+/// Evaluator periodically calls `AlarmService::tick` so that alarms are
+/// evaluated (and old metrics cleaned) even when no metric is being ingested,
+/// and `AlarmService::compact` so that the WAL does not grow forever.
+#[derive(Clone)]
+pub struct Evaluator {
+    alarm_service: Arc<Mutex<AlarmService>>,
+    /// see `AlarmService::in_flight`.
+    in_flight: Arc<RwLock<()>>,
+    interval: Duration,
+    stop: watch::Sender<bool>,
+}
+
+impl Evaluator {
+    pub fn new(alarm_service: Arc<Mutex<AlarmService>>, interval: Duration) -> Self {
+        let (stop, _) = watch::channel(false);
+        let in_flight = alarm_service.lock().unwrap().in_flight();
+        Self {
+            alarm_service,
+            in_flight,
+            interval,
+            stop,
+        }
+    }
+
+    /// spawns the task evaluating the alarms on every interval,
+    /// the task runs until the evaluator is shutdown.
+    pub fn start(&self) {
+        let alarm_service = self.alarm_service.clone();
+        let in_flight = self.in_flight.clone();
+        let mut stop = self.stop.subscribe();
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let alarm_service = alarm_service.clone();
+                        let in_flight = in_flight.clone();
+                        // both wait on the WAL writer, which must not block the runtime
+                        let evaluated = tokio::task::spawn_blocking(move || {
+                            // waits for the metrics being ingested, see `compact`
+                            let _in_flight = in_flight.blocking_write();
+                            let mut alarm_service = alarm_service.lock().ok()?;
+                            alarm_service.tick();
+                            if let Err(e) = alarm_service.compact() {
+                                event!(Level::ERROR, "could not compact the WAL: {}", e);
+                            }
+                            Some(())
+                        })
+                        .await;
+                        if !matches!(evaluated, Ok(Some(()))) {
+                            event!(Level::ERROR, "alarm service is poisoned, stopping evaluation");
+                            break;
+                        }
+                    }
+                    _ = stop.changed() => break,
+                }
+            }
+        });
+    }
+}
+
+#[tonic::async_trait]
+impl server::Administrable for Evaluator {
+    async fn shutdown(&mut self) -> Result<(), server::ShutdownError> {
+        let _ = self.stop.send(true);
+
+        let alarm_service = self.alarm_service.clone();
+        tokio::task::spawn_blocking(move || {
+            alarm_service
+                .lock()
+                .map_err(|e| server::ShutdownError::UnknownCause(e.to_string()))?
+                .flush()
+                .map_err(|e| server::ShutdownError::UnknownCause(e.to_string()))
+        })
+        .await
+        .map_err(|e| server::ShutdownError::UnknownCause(e.to_string()))?
+    }
+
+    fn service_name(&self) -> &str {
+        "AlarmService"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alarm::alarm::Alarm;
+    use crate::alarm::service::Config;
+    use crate::alarm::state::{AlarmState, AlarmStates, Status};
+    use crate::model::alarm::AlarmConfig;
+    use crate::model::dsl;
+    use crate::model::metrics;
+    use crate::server::Administrable;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use temp_dir::TempDir;
+
+    struct CountTicksAlarm {
+        ticks: Arc<AtomicUsize>,
+    }
+
+    impl Alarm for CountTicksAlarm {
+        fn consume(&self, _metric: &metrics::Metric) -> bool {
+            false
+        }
+        fn uses(&self, _metric: &metrics::Metric) -> bool {
+            false
+        }
+        fn tick(&self, _states: &AlarmStates) {
+            self.ticks.fetch_add(1, Ordering::Relaxed);
+        }
+        fn identifier(&self) -> String {
+            "CountTicksAlarm".to_string()
+        }
+        fn status(&self) -> Status {
+            Status {
+                state: AlarmState::InsufficientData,
+                since: chrono::DateTime::UNIX_EPOCH,
+            }
+        }
+        fn config(&self) -> AlarmConfig {
+            dsl::parse("count(cpu.usage) > 0").unwrap()
+        }
+        fn metrics(&self) -> Vec<metrics::Metric> {
+            vec![]
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evaluates_alarms_until_shutdown() {
+        let path = TempDir::new().unwrap();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let alarm_service = AlarmService::new(
+            Config::new(path.path().to_owned()),
+            vec![Box::new(CountTicksAlarm {
+                ticks: ticks.clone(),
+            })],
+        )
+        .unwrap();
+
+        let mut evaluator =
+            Evaluator::new(Arc::new(Mutex::new(alarm_service)), Duration::from_secs(10));
+        evaluator.start();
+
+        // the first tick happens right away
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(3, ticks.load(Ordering::Relaxed));
+
+        evaluator.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(3, ticks.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::model::alarm::Statistic;
+use crate::model::metrics::{
+    AggregationTemporality, DataPoint, HistogramDataPoint, Metric, MetricData,
+};
+use std::collections::HashMap;
+
+/// ValueExtractor turns a metric into the value compared by an alarm.
+/// Cumulative metrics are converted to deltas, so it keeps the last
+/// data point of every cumulative series until it expires (see `expire`).
+pub struct ValueExtractor {
+    statistic: Statistic,
+    last_cumulative: HashMap<String, MetricData>,
+}
+
+impl ValueExtractor {
+    pub fn new(statistic: Statistic) -> Self {
+        Self {
+            statistic,
+            last_cumulative: HashMap::new(),
+        }
+    }
+
+    /// returns None if the metric does not have a value for the statistic,
+    /// e.g. the first data point of a cumulative counter.
+    pub fn value(&mut self, metric: &Metric) -> Option<f64> {
+        match &metric.data {
+            MetricData::Gauge(data) => self.number(data),
+            MetricData::Sum(data, AggregationTemporality::Cumulative, true) => {
+                let previous = self
+                    .last_cumulative
+                    .insert(series_key(metric), metric.data.clone());
+                let value = self.number(data)?;
+                match previous {
+                    Some(MetricData::Sum(previous, _, _))
+                        if previous.start_time == data.start_time && previous.value <= value =>
+                    {
+                        rate(value - previous.value, previous.time, data.time)
+                    }
+                    // the counter was reset, so the value is counted from the new start time
+                    Some(_) => rate(value, data.start_time, data.time),
+                    None => None,
+                }
+            }
+            MetricData::Sum(data, AggregationTemporality::Delta, true) => self
+                .number(data)
+                .and_then(|value| rate(value, data.start_time, data.time)),
+            // non monotonic sums (e.g. up down counters) are used as they are
+            MetricData::Sum(data, _, _) => self.number(data),
+            MetricData::Histogram(data, AggregationTemporality::Cumulative) => {
+                let previous = self
+                    .last_cumulative
+                    .insert(series_key(metric), metric.data.clone());
+                match previous {
+                    Some(MetricData::Histogram(previous, _)) => {
+                        match histogram_delta(&previous, data) {
+                            Some(delta) => self.histogram(&delta),
+                            // reset, the histogram is counted from the new start time
+                            None => self.histogram(data),
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            MetricData::Histogram(data, _) => self.histogram(data),
+        }
+    }
+
+    /// forgets the series whose last data point is older than `before` (in
+    /// milliseconds), their next data point is handled as the first one.
+    pub fn expire(&mut self, before: u64) {
+        self.last_cumulative
+            .retain(|_, data| data_time(data) >= before);
+    }
+
+    /// the last data point of every cumulative series, e.g. to keep it in a snapshot.
+    pub fn save(&self) -> HashMap<String, MetricData> {
+        self.last_cumulative.clone()
+    }
+
+    /// adds saved series (see `save`), keeping the most recent data point
+    /// of the series that are in both.
+    pub fn merge(&mut self, saved: HashMap<String, MetricData>) {
+        for (key, data) in saved {
+            match self.last_cumulative.get(&key) {
+                Some(current) if data_time(current) >= data_time(&data) => {}
+                _ => {
+                    self.last_cumulative.insert(key, data);
+                }
+            }
+        }
+    }
+
+    fn number(&self, data: &DataPoint) -> Option<f64> {
+        match self.statistic {
+            Statistic::Value => Some(data.value),
+            _ => None,
+        }
+    }
+
+    fn histogram(&self, data: &HistogramDataPoint) -> Option<f64> {
+        match self.statistic {
+            Statistic::Value => None,
+            Statistic::Count => Some(data.count as f64),
+            Statistic::Sum => Some(data.sum),
+            Statistic::Mean if data.count > 0 => Some(data.sum / data.count as f64),
+            Statistic::Mean => None,
+            Statistic::Quantile(q) => quantile(data, q),
+        }
+    }
+}
+
+fn data_time(data: &MetricData) -> u64 {
+    match data {
+        MetricData::Sum(data, _, _) | MetricData::Gauge(data) => data.time,
+        MetricData::Histogram(data, _) => data.time,
+    }
+}
+
+/// identifies a time series: name and attributes.
+fn series_key(metric: &Metric) -> String {
+    let mut attributes: Vec<_> = metric.attributes.iter().collect();
+    attributes.sort();
+    let mut key = metric.name.clone();
+    for (k, v) in attributes {
+        key.push_str(&format!(";{}={}", k, v));
+    }
+    key
+}
+
+/// per second rate of `value` between start and end (in milliseconds).
+fn rate(value: f64, start: u64, end: u64) -> Option<f64> {
+    if start == 0 || end <= start {
+        return None;
+    }
+    Some(value / ((end - start) as f64 / 1000.0))
+}
+
+/// returns the difference between two cumulative points of a histogram,
+/// or None if the histogram was reset (or its buckets changed).
+fn histogram_delta(
+    previous: &HistogramDataPoint,
+    current: &HistogramDataPoint,
+) -> Option<HistogramDataPoint> {
+    if previous.start_time != current.start_time
+        || previous.count > current.count
+        || previous.explicity_bouds != current.explicity_bouds
+        || previous.bucket_counts.len() != current.bucket_counts.len()
+    {
+        return None;
+    }
+
+    let bucket_counts = previous
+        .bucket_counts
+        .iter()
+        .zip(current.bucket_counts.iter())
+        .map(|(previous, current)| current.checked_sub(*previous))
+        .collect::<Option<Vec<u64>>>()?;
+
+    Some(HistogramDataPoint {
+        start_time: previous.time,
+        time: current.time,
+        count: current.count - previous.count,
+        sum: current.sum - previous.sum,
+        bucket_counts: bucket_counts.into_boxed_slice(),
+        explicity_bouds: current.explicity_bouds.clone(),
+    })
+}
+
+/// estimates the quantile (0 to 1) assuming the values are evenly distributed
+/// inside each bucket. The first bucket starts at 0 (if its bound is positive)
+/// and the last one, which is unbounded, returns its lower bound.
+fn quantile(data: &HistogramDataPoint, q: f64) -> Option<f64> {
+    let total: u64 = data.bucket_counts.iter().sum();
+    if total == 0 || data.bucket_counts.len() != data.explicity_bouds.len() + 1 {
+        return None;
+    }
+
+    let rank = q * total as f64;
+    let mut seen = 0_u64;
+    for (i, &count) in data.bucket_counts.iter().enumerate() {
+        if count == 0 || ((seen + count) as f64) < rank {
+            seen += count;
+            continue;
+        }
+
+        let upper = match data.explicity_bouds.get(i) {
+            Some(&upper) => upper,
+            // the value is in the +Inf bucket
+            None => return data.explicity_bouds.last().copied(),
+        };
+        let lower = match i {
+            0 if upper > 0.0 => 0.0,
+            0 => upper,
+            _ => data.explicity_bouds[i - 1],
+        };
+        return Some(lower + (upper - lower) * (rank - seen as f64) / count as f64);
+    }
+
+    data.explicity_bouds.last().copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metric(data: MetricData, time: u64) -> Metric {
+        Metric {
+            name: "requests".to_string(),
+            unit: "1".to_string(),
+            data,
+            time,
+            attributes: HashMap::from([("host".to_string(), "web-1".to_string())]),
+        }
+    }
+
+    fn counter(start_time: u64, time: u64, value: f64) -> Metric {
+        metric(
+            MetricData::Sum(
+                DataPoint {
+                    start_time,
+                    time,
+                    value,
+                },
+                AggregationTemporality::Cumulative,
+                true,
+            ),
+            time,
+        )
+    }
+
+    fn histogram(temporality: AggregationTemporality, time: u64, bucket_counts: &[u64]) -> Metric {
+        metric(
+            MetricData::Histogram(
+                HistogramDataPoint {
+                    start_time: 1_000,
+                    time,
+                    count: bucket_counts.iter().sum(),
+                    sum: bucket_counts.iter().sum::<u64>() as f64 * 10.0,
+                    bucket_counts: bucket_counts.into(),
+                    explicity_bouds: Box::new([10.0, 20.0, 50.0]),
+                },
+                temporality,
+            ),
+            time,
+        )
+    }
+
+    #[test]
+    fn cumulative_counters_are_rates() {
+        let mut extractor = ValueExtractor::new(Statistic::Value);
+
+        assert_eq!(None, extractor.value(&counter(1_000, 61_000, 100.0)));
+        assert_eq!(Some(2.0), extractor.value(&counter(1_000, 121_000, 220.0)));
+        // reset
+        assert_eq!(Some(0.5), extractor.value(&counter(130_000, 150_000, 10.0)));
+    }
+
+    #[test]
+    fn stale_series_expire() {
+        let mut extractor = ValueExtractor::new(Statistic::Value);
+
+        extractor.value(&counter(1_000, 61_000, 100.0));
+        extractor.expire(61_000);
+        assert_eq!(Some(2.0), extractor.value(&counter(1_000, 121_000, 220.0)));
+        extractor.expire(121_001);
+        assert!(extractor.last_cumulative.is_empty());
+        assert_eq!(None, extractor.value(&counter(1_000, 181_000, 340.0)));
+    }
+
+    #[test]
+    fn delta_counters_are_rates() {
+        let mut extractor = ValueExtractor::new(Statistic::Value);
+        let delta = metric(
+            MetricData::Sum(
+                DataPoint {
+                    start_time: 60_000,
+                    time: 120_000,
+                    value: 30.0,
+                },
+                AggregationTemporality::Delta,
+                true,
+            ),
+            120_000,
+        );
+        assert_eq!(Some(0.5), extractor.value(&delta));
+    }
+
+    #[test]
+    fn histogram_statistics() {
+        let delta = histogram(AggregationTemporality::Delta, 60_000, &[2, 4, 4, 0]);
+
+        assert_eq!(
+            Some(10.0),
+            ValueExtractor::new(Statistic::Count).value(&delta)
+        );
+        assert_eq!(
+            Some(100.0),
+            ValueExtractor::new(Statistic::Sum).value(&delta)
+        );
+        assert_eq!(
+            Some(10.0),
+            ValueExtractor::new(Statistic::Mean).value(&delta)
+        );
+        assert_eq!(
+            Some(15.0),
+            ValueExtractor::new(Statistic::Quantile(0.4)).value(&delta)
+        );
+        assert_eq!(
+            Some(5.0),
+            ValueExtractor::new(Statistic::Quantile(0.1)).value(&delta)
+        );
+        assert_eq!(None, ValueExtractor::new(Statistic::Value).value(&delta));
+
+        let overflow = histogram(AggregationTemporality::Delta, 60_000, &[0, 0, 0, 3]);
+        assert_eq!(
+            Some(50.0),
+            ValueExtractor::new(Statistic::Quantile(0.99)).value(&overflow)
+        );
+    }
+
+    #[test]
+    fn cumulative_histograms_are_deltas() {
+        let mut extractor = ValueExtractor::new(Statistic::Count);
+        let first = histogram(AggregationTemporality::Cumulative, 60_000, &[1, 1, 0, 0]);
+        let second = histogram(AggregationTemporality::Cumulative, 120_000, &[3, 2, 0, 0]);
+
+        assert_eq!(None, extractor.value(&first));
+        assert_eq!(Some(3.0), extractor.value(&second));
+    }
+}
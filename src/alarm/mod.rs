@@ -1,2 +1,11 @@
+pub mod aggregator;
+#[allow(clippy::module_inception)]
 pub mod alarm;
+pub mod definition;
+pub mod evaluator;
+pub mod extractor;
+pub mod record;
+pub mod reloader;
 pub mod service;
+pub mod snapshot;
+pub mod state;
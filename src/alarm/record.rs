@@ -0,0 +1,345 @@
+use crate::model::metrics::{
+    AggregationTemporality, DataPoint, HistogramDataPoint, Metric, MetricData,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+mod proto {
+    tonic::include_proto!("wal_record");
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid record {0}")]
+    InvalidRecord(#[from] serde_json::Error),
+    #[error("Invalid metric record {0}")]
+    InvalidMetricRecord(#[from] prost::DecodeError),
+    #[error("Metric record without data")]
+    MissingMetricData,
+    #[error("Unknown record type {0}, it was probably written by a newer version")]
+    UnknownRecordType(u8),
+    #[error("Unknown metric format {0}, it was probably written by a newer version")]
+    UnknownMetricFormat(u8),
+}
+
+/// Record is an entry of the alarm service WAL, the type of the record is
+/// kept in the WAL frame. Metrics, which are most of the WAL, are encoded
+/// with protobuf (see `proto/wal_record.proto`) after a format byte, the
+/// other records are serialized as JSON. Unknown record types are refused
+/// instead of misread. Pages written before records were framed only have
+/// metrics as JSON, see `decode_unframed`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Record {
+    /// a metric used by at least one alarm.
+    Metric(Metric),
+    /// the metrics of an ingestion request used by at least one alarm, they
+    /// are written together so that a request is never partially persisted.
+    Metrics(Vec<Metric>),
+    /// an alarm created through the admin API.
+    CreateAlarm(AlarmRecord),
+    /// an alarm replaced through the admin API.
+    UpdateAlarm(AlarmRecord),
+    /// an alarm deleted through the admin API, by id.
+    DeleteAlarm(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AlarmRecord {
+    pub id: String,
+    /// the alarm written in our DSL (see `model::dsl`).
+    pub expression: String,
+}
+
+impl Record {
+    // the record types can never change, only new ones can be added.
+    const METRIC: u8 = 1;
+    const CREATE_ALARM: u8 = 2;
+    const UPDATE_ALARM: u8 = 3;
+    const DELETE_ALARM: u8 = 4;
+    const METRICS: u8 = 5;
+
+    // first byte of a metric record.
+    const METRIC_FORMAT_PROTOBUF: u8 = 1;
+
+    /// returns the record type and its content.
+    pub fn encode(&self) -> Result<(u8, Vec<u8>), Error> {
+        Ok(match self {
+            Record::Metric(metric) => (Self::METRIC, Self::encode_metric(metric)),
+            Record::Metrics(metrics) => (Self::METRICS, Self::encode_metrics(metrics)),
+            Record::CreateAlarm(alarm) => (Self::CREATE_ALARM, serde_json::to_vec(alarm)?),
+            Record::UpdateAlarm(alarm) => (Self::UPDATE_ALARM, serde_json::to_vec(alarm)?),
+            Record::DeleteAlarm(id) => (Self::DELETE_ALARM, serde_json::to_vec(id)?),
+        })
+    }
+
+    pub fn decode(record_type: u8, data: &[u8]) -> Result<Self, Error> {
+        Ok(match record_type {
+            Self::METRIC => Record::Metric(Self::decode_metric(data)?),
+            Self::METRICS => Record::Metrics(Self::decode_metrics(data)?),
+            Self::CREATE_ALARM => Record::CreateAlarm(serde_json::from_slice(data)?),
+            Self::UPDATE_ALARM => Record::UpdateAlarm(serde_json::from_slice(data)?),
+            Self::DELETE_ALARM => Record::DeleteAlarm(serde_json::from_slice(data)?),
+            record_type => return Err(Error::UnknownRecordType(record_type)),
+        })
+    }
+
+    /// decodes an entry of a page written before records were framed (see
+    /// `WAL::iter_unframed`), those were all metrics encoded as JSON.
+    pub fn decode_unframed(data: &[u8]) -> Result<Self, Error> {
+        Ok(Record::Metric(serde_json::from_slice(data)?))
+    }
+
+    fn encode_metric(metric: &Metric) -> Vec<u8> {
+        let metric = proto::Metric::from(metric);
+        let mut data = Vec::with_capacity(1 + metric.encoded_len());
+        data.push(Self::METRIC_FORMAT_PROTOBUF);
+        metric.encode(&mut data).unwrap();
+        data
+    }
+
+    fn encode_metrics(metrics: &[Metric]) -> Vec<u8> {
+        let metrics = proto::Metrics {
+            metrics: metrics.iter().map(proto::Metric::from).collect(),
+        };
+        let mut data = Vec::with_capacity(1 + metrics.encoded_len());
+        data.push(Self::METRIC_FORMAT_PROTOBUF);
+        metrics.encode(&mut data).unwrap();
+        data
+    }
+
+    fn decode_metrics(data: &[u8]) -> Result<Vec<Metric>, Error> {
+        match data.first() {
+            Some(&Self::METRIC_FORMAT_PROTOBUF) => proto::Metrics::decode(&data[1..])?
+                .metrics
+                .into_iter()
+                .map(Metric::try_from)
+                .collect(),
+            Some(&format) => Err(Error::UnknownMetricFormat(format)),
+            None => Err(prost::DecodeError::new("empty metrics record").into()),
+        }
+    }
+
+    fn decode_metric(data: &[u8]) -> Result<Metric, Error> {
+        match data.first() {
+            Some(&Self::METRIC_FORMAT_PROTOBUF) => proto::Metric::decode(&data[1..])?.try_into(),
+            Some(&format) => Err(Error::UnknownMetricFormat(format)),
+            None => Err(prost::DecodeError::new("empty metric record").into()),
+        }
+    }
+}
+
+impl From<&AggregationTemporality> for proto::AggregationTemporality {
+    fn from(temporality: &AggregationTemporality) -> Self {
+        match temporality {
+            AggregationTemporality::None => proto::AggregationTemporality::None,
+            AggregationTemporality::Delta => proto::AggregationTemporality::Delta,
+            AggregationTemporality::Cumulative => proto::AggregationTemporality::Cumulative,
+        }
+    }
+}
+
+impl From<proto::AggregationTemporality> for AggregationTemporality {
+    fn from(temporality: proto::AggregationTemporality) -> Self {
+        match temporality {
+            proto::AggregationTemporality::None => AggregationTemporality::None,
+            proto::AggregationTemporality::Delta => AggregationTemporality::Delta,
+            proto::AggregationTemporality::Cumulative => AggregationTemporality::Cumulative,
+        }
+    }
+}
+
+impl From<&DataPoint> for proto::NumberDataPoint {
+    fn from(data: &DataPoint) -> Self {
+        Self {
+            start_time: data.start_time,
+            time: data.time,
+            value: data.value,
+        }
+    }
+}
+
+impl From<proto::NumberDataPoint> for DataPoint {
+    fn from(data: proto::NumberDataPoint) -> Self {
+        Self {
+            start_time: data.start_time,
+            time: data.time,
+            value: data.value,
+        }
+    }
+}
+
+impl From<&Metric> for proto::Metric {
+    fn from(metric: &Metric) -> Self {
+        let data = match &metric.data {
+            MetricData::Sum(data, temporality, monotonic) => proto::metric::Data::Sum(proto::Sum {
+                data_point: Some(data.into()),
+                temporality: proto::AggregationTemporality::from(temporality).into(),
+                monotonic: *monotonic,
+            }),
+            MetricData::Gauge(data) => proto::metric::Data::Gauge(data.into()),
+            MetricData::Histogram(data, temporality) => {
+                proto::metric::Data::Histogram(proto::Histogram {
+                    start_time: data.start_time,
+                    time: data.time,
+                    count: data.count,
+                    sum: data.sum,
+                    bucket_counts: data.bucket_counts.to_vec(),
+                    explicit_bounds: data.explicity_bouds.to_vec(),
+                    temporality: proto::AggregationTemporality::from(temporality).into(),
+                })
+            }
+        };
+        Self {
+            name: metric.name.clone(),
+            unit: metric.unit.clone(),
+            time: metric.time,
+            attributes: metric.attributes.clone(),
+            data: Some(data),
+        }
+    }
+}
+
+impl TryFrom<proto::Metric> for Metric {
+    type Error = Error;
+
+    fn try_from(metric: proto::Metric) -> Result<Self, Error> {
+        let data = match metric.data.ok_or(Error::MissingMetricData)? {
+            proto::metric::Data::Sum(sum) => {
+                let temporality = sum.temporality().into();
+                MetricData::Sum(
+                    sum.data_point.ok_or(Error::MissingMetricData)?.into(),
+                    temporality,
+                    sum.monotonic,
+                )
+            }
+            proto::metric::Data::Gauge(data) => MetricData::Gauge(data.into()),
+            proto::metric::Data::Histogram(histogram) => {
+                let temporality = histogram.temporality().into();
+                MetricData::Histogram(
+                    HistogramDataPoint {
+                        start_time: histogram.start_time,
+                        time: histogram.time,
+                        count: histogram.count,
+                        sum: histogram.sum,
+                        bucket_counts: histogram.bucket_counts.into_boxed_slice(),
+                        explicity_bouds: histogram.explicit_bounds.into_boxed_slice(),
+                    },
+                    temporality,
+                )
+            }
+        };
+        Ok(Self {
+            name: metric.name,
+            unit: metric.unit,
+            data,
+            time: metric.time,
+            attributes: metric.attributes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn metric() -> Metric {
+        Metric {
+            name: "cpu.usage".to_string(),
+            unit: "%".to_string(),
+            data: MetricData::Gauge(DataPoint {
+                start_time: 0,
+                time: 1_000,
+                value: 0.5,
+            }),
+            time: 1_000,
+            attributes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for record in [
+            Record::Metric(metric()),
+            Record::Metrics(vec![metric(), metric()]),
+            Record::CreateAlarm(AlarmRecord {
+                id: "cpu".to_string(),
+                expression: "max(cpu.usage) > 80".to_string(),
+            }),
+            Record::UpdateAlarm(AlarmRecord {
+                id: "cpu".to_string(),
+                expression: "max(cpu.usage) > 90".to_string(),
+            }),
+            Record::DeleteAlarm("cpu".to_string()),
+        ] {
+            let (record_type, data) = record.encode().unwrap();
+            assert_eq!(record, Record::decode(record_type, &data).unwrap());
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_records() {
+        assert!(matches!(
+            Record::decode(6, b"\"cpu\""),
+            Err(Error::UnknownRecordType(6))
+        ));
+        assert!(matches!(
+            Record::decode_unframed(b"{\"name\""),
+            Err(Error::InvalidRecord(_))
+        ));
+        assert!(matches!(
+            Record::decode(Record::METRIC, b"\"cpu\""),
+            Err(Error::UnknownMetricFormat(b'"'))
+        ));
+        assert!(matches!(
+            Record::decode(Record::METRIC, &[Record::METRIC_FORMAT_PROTOBUF, 0xFF]),
+            Err(Error::InvalidMetricRecord(_))
+        ));
+    }
+
+    #[test]
+    fn metrics_are_binary_and_json_is_only_read_unframed() {
+        let histogram = Metric {
+            data: MetricData::Histogram(
+                HistogramDataPoint {
+                    start_time: 0,
+                    time: 1_000,
+                    count: 3,
+                    sum: 30.0,
+                    bucket_counts: Box::new([1, 2, 0]),
+                    explicity_bouds: Box::new([10.0, 20.0]),
+                },
+                AggregationTemporality::Cumulative,
+            ),
+            attributes: HashMap::from([("host".to_string(), "web-1".to_string())]),
+            ..metric()
+        };
+        let sum = Metric {
+            data: MetricData::Sum(
+                DataPoint {
+                    start_time: 0,
+                    time: 1_000,
+                    value: 12.0,
+                },
+                AggregationTemporality::Delta,
+                true,
+            ),
+            ..metric()
+        };
+
+        for metric in [metric(), histogram, sum] {
+            let record = Record::Metric(metric.clone());
+            let (record_type, data) = record.encode().unwrap();
+            let json = serde_json::to_vec(&metric).unwrap();
+            assert_eq!(Record::METRIC_FORMAT_PROTOBUF, data[0]);
+            assert!(data.len() < json.len());
+            assert_eq!(record, Record::decode(record_type, &data).unwrap());
+            // written before the binary encoding, only in unframed pages
+            assert_eq!(record, Record::decode_unframed(&json).unwrap());
+            assert!(matches!(
+                Record::decode(record_type, &json),
+                Err(Error::UnknownMetricFormat(b'{'))
+            ));
+        }
+    }
+}
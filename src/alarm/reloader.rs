@@ -0,0 +1,123 @@
+use crate::alarm::definition::AlarmDefinitions;
+use crate::alarm::service::AlarmService;
+use crate::server;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{event, Level};
+
+/// Reloader reloads the alarm definitions when the file changes (checked on
+/// every interval) or when the process receives a SIGHUP.
+#[derive(Clone)]
+pub struct Reloader {
+    alarm_service: Arc<Mutex<AlarmService>>,
+    definitions: Arc<Mutex<AlarmDefinitions>>,
+    interval: Duration,
+    stop: watch::Sender<bool>,
+}
+
+impl Reloader {
+    pub fn new(
+        alarm_service: Arc<Mutex<AlarmService>>,
+        definitions: AlarmDefinitions,
+        interval: Duration,
+    ) -> Self {
+        let (stop, _) = watch::channel(false);
+        Self {
+            alarm_service,
+            definitions: Arc::new(Mutex::new(definitions)),
+            interval,
+            stop,
+        }
+    }
+
+    /// spawns the task watching the definitions, it runs until the reloader
+    /// is shutdown.
+    pub fn start(&self) -> Result<(), std::io::Error> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reloader = self.clone();
+        let mut stop = self.stop.subscribe();
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        tokio::spawn(async move {
+            let mut modified = reloader.modified();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let current = reloader.modified();
+                        if current == modified {
+                            continue;
+                        }
+                        modified = current;
+                    }
+                    _ = hangup.recv() => {
+                        modified = reloader.modified();
+                    }
+                    _ = stop.changed() => break,
+                }
+                let blocking = reloader.clone();
+                // reads the file and waits on the alarm service and the WAL
+                // writer, which must not block the runtime
+                let reloaded = tokio::task::spawn_blocking(move || blocking.reload()).await;
+                if !matches!(reloaded, Ok(true)) {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// last modification of the definitions file, None if it can't be read.
+    fn modified(&self) -> Option<SystemTime> {
+        let definitions = self.definitions.lock().ok()?;
+        modified(definitions.path())
+    }
+
+    /// returns false if the alarm service is poisoned. It blocks, see `start`.
+    fn reload(&self) -> bool {
+        let (mut definitions, mut alarm_service) =
+            match (self.definitions.lock(), self.alarm_service.lock()) {
+                (Ok(definitions), Ok(alarm_service)) => (definitions, alarm_service),
+                _ => {
+                    event!(Level::ERROR, "alarm service is poisoned, stopping reloads");
+                    return false;
+                }
+            };
+        match definitions.reload(&mut alarm_service) {
+            Ok(changes) => event!(
+                Level::INFO,
+                "reloaded alarms from {}, added {:?}, removed {:?}, replaced {:?}",
+                definitions.path().display(),
+                changes.added,
+                changes.removed,
+                changes.replaced
+            ),
+            Err(e) => event!(
+                Level::ERROR,
+                "could not reload alarms from {}: {}",
+                definitions.path().display(),
+                e
+            ),
+        }
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[tonic::async_trait]
+impl server::Administrable for Reloader {
+    async fn shutdown(&mut self) -> Result<(), server::ShutdownError> {
+        let _ = self.stop.send(true);
+        Ok(())
+    }
+
+    fn service_name(&self) -> &str {
+        "AlarmDefinitionsReloader"
+    }
+}
@@ -1,35 +1,89 @@
-use crate::alarm::alarm::Alarm;
-use crate::model::metrics;
-use crate::wal::{Config as WALConfig, Error as WALError, WAL};
-use std::collections::HashMap;
+use crate::alarm::alarm::{new_alarm, Alarm, LogNotifier};
+use crate::alarm::record::{AlarmRecord, Error as RecordError, Record};
+use crate::alarm::snapshot::{AlarmWindow, Error as SnapshotError, Snapshot};
+use crate::alarm::state::AlarmStates;
+use crate::model::{alarm::AlarmConfig, dsl, metrics};
+use crate::wal::{
+    Append, Compression, Config as WALConfig, Durability, Error as WALError, Position, Writer, WAL,
+};
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{event, Level};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Error while setting up WAL {0}")]
     WALError(#[from] WALError),
-    #[error("Could not serialize object {0}")]
-    SerializeError(#[from] serde_json::Error),
-    #[error("Invalid entry in log {0}")]
-    InvalidEntryInLog(#[from] std::str::Utf8Error),
+    #[error("Alarm {0} depends on itself")]
+    DependencyCycle(String),
+    #[error("Invalid record in log {0}")]
+    InvalidRecord(#[from] RecordError),
+    #[error("Invalid alarm {id} in log: {source}")]
+    InvalidAlarmInLog { id: String, source: dsl::Error },
+    #[error("Error while handling the snapshot {0}")]
+    SnapshotError(#[from] SnapshotError),
+    #[error("Alarm {0} is not managed through the admin API")]
+    NotManaged(String),
 }
 
 pub struct AlarmService {
-    wal: WAL,
+    wal: Writer,
+    storage_path: PathBuf,
     alarms: HashMap<String, Box<dyn Alarm>>,
+    /// ids of the alarms, every alarm comes after its dependencies.
+    evaluation_order: Vec<String>,
+    /// ids of the alarms created through the admin API, they are kept in the WAL.
+    managed: HashSet<String>,
+    /// time of the newest metric of the sealed WAL pages (see `page_time`).
+    page_times: BTreeMap<usize, u64>,
+    /// see `in_flight`.
+    in_flight: Arc<RwLock<()>>,
+}
+
+/// what was restored from the snapshot during the recovery.
+#[derive(Default)]
+struct Restored {
+    /// the end of the WAL when the snapshot was taken.
+    position: Position,
+    /// ids of the alarms whose window was restored.
+    alarms: HashSet<String>,
 }
 
 #[derive(Clone)]
 pub struct Config {
     pub max_size_per_page_wal: usize,
     pub storage_path: PathBuf,
+    /// when the WAL is synced to disk.
+    pub durability: Durability,
+    /// how the full WAL pages are compressed.
+    pub compression: Compression,
+}
+
+/// used by the tests of the modules that need a service.
+#[cfg(test)]
+impl Config {
+    /// the defaults of the WAL, see `wal::Config::new`.
+    pub fn new(storage_path: PathBuf) -> Self {
+        let wal = WALConfig::new(storage_path.clone());
+        Self {
+            max_size_per_page_wal: wal.max_size_per_page,
+            storage_path,
+            durability: wal.durability,
+            compression: wal.compression,
+        }
+    }
 }
 
 impl AlarmService {
-    fn new(config: Config, alarms: Vec<Box<dyn Alarm>>) -> Result<Self, Error> {
+    pub fn new(config: Config, alarms: Vec<Box<dyn Alarm>>) -> Result<Self, Error> {
         let wal_config = WALConfig {
-            dir: config.storage_path,
+            dir: config.storage_path.clone(),
             max_size_per_page: config.max_size_per_page_wal,
+            durability: config.durability,
+            compression: config.compression,
         };
 
         let mut map = HashMap::new();
@@ -37,92 +91,499 @@ impl AlarmService {
         for alarm in alarms {
             map.insert(alarm.identifier(), alarm);
         }
+        let evaluation_order = Self::evaluation_order(&map)?;
 
-        let wal = WAL::new(wal_config)?;
-        let mut service = Self { wal, alarms: map };
+        let wal = Writer::new(WAL::new(wal_config)?)?;
+        let mut service = Self {
+            wal,
+            storage_path: config.storage_path,
+            alarms: map,
+            evaluation_order,
+            managed: HashSet::new(),
+            page_times: BTreeMap::new(),
+            in_flight: Arc::new(RwLock::new(())),
+        };
 
         service.recover()?;
 
         Ok(service)
     }
 
-    fn add(&mut self, alarm: Box<dyn Alarm>) {
-        self.alarms.insert(alarm.identifier(), alarm);
+    /// adds (or replaces) an alarm, fails if the alarm would
+    /// depend on itself through other alarms.
+    pub fn add(&mut self, alarm: Box<dyn Alarm>) -> Result<(), Error> {
+        let id = alarm.identifier();
+        let previous = self.alarms.insert(id.clone(), alarm);
+        match Self::evaluation_order(&self.alarms) {
+            Ok(evaluation_order) => {
+                self.evaluation_order = evaluation_order;
+                Ok(())
+            }
+            Err(e) => {
+                match previous {
+                    Some(previous) => self.alarms.insert(id, previous),
+                    None => self.alarms.remove(&id),
+                };
+                Err(e)
+            }
+        }
+    }
+
+    pub fn alarm(&self, alarm_id: &str) -> Option<&dyn Alarm> {
+        self.alarms.get(alarm_id).map(|alarm| alarm.as_ref())
+    }
+
+    /// all the alarms, in no particular order.
+    pub fn alarms(&self) -> impl Iterator<Item = &dyn Alarm> {
+        self.alarms.values().map(|alarm| alarm.as_ref())
+    }
+
+    /// held for reading from `persist` until `apply`, and for writing by
+    /// `compact`, so that the snapshot has every metric that is in the WAL.
+    /// It is taken before the lock of the service.
+    pub fn in_flight(&self) -> Arc<RwLock<()>> {
+        self.in_flight.clone()
+    }
+
+    /// if the alarm was created through the admin API (see `create_alarm`).
+    pub fn is_managed(&self, alarm_id: &str) -> bool {
+        self.managed.contains(alarm_id)
+    }
+
+    /// deletes an alarm that is not managed through the admin API (those are
+    /// deleted with `delete_alarm`), returns false if there is none.
+    pub fn delete(&mut self, alarm_id: &str) -> bool {
+        if self.is_managed(alarm_id) {
+            return false;
+        }
+        let deleted = self.alarms.remove(alarm_id).is_some();
+        self.evaluation_order.retain(|id| id != alarm_id);
+        deleted
+    }
+
+    /// adds the alarm and records it in the WAL, so that it is created again
+    /// on recovery. Used for the alarms managed through the admin API, the
+    /// returned future resolves once the record is durable.
+    pub fn create_alarm(&mut self, id: String, config: AlarmConfig) -> Result<Append, Error> {
+        self.check_managed(&id)?;
+        let record = Record::CreateAlarm(AlarmRecord {
+            id: id.clone(),
+            expression: config.to_string(),
+        });
+        self.add(Self::managed_alarm(id.clone(), config))?;
+        self.managed.insert(id);
+        self.write(&record)
     }
 
-    fn delete(&mut self, alarm_id: &str) -> bool {
-        self.alarms.remove(alarm_id).is_some()
+    /// replaces the alarm and records it in the WAL, the alarm starts from scratch.
+    pub fn update_alarm(&mut self, id: String, config: AlarmConfig) -> Result<Append, Error> {
+        self.check_managed(&id)?;
+        let record = Record::UpdateAlarm(AlarmRecord {
+            id: id.clone(),
+            expression: config.to_string(),
+        });
+        self.add(Self::managed_alarm(id.clone(), config))?;
+        self.managed.insert(id);
+        self.write(&record)
     }
 
-    /// Check if the metric is needed for any alarm
-    /// and if so, consumes it.
-    /// Any metric that is used by an alarm is saved into our WAL,
-    /// otherwise we just drop it since no one is using the data.
-    fn consume(&mut self, metric: metrics::Metric, recover_mode: bool) -> Result<(), Error> {
-        let mut should_save_in_wal = false;
-        for (_, alarm) in &mut self.alarms {
-            should_save_in_wal = alarm.consume(&metric) || should_save_in_wal
+    /// deletes the alarm and records it in the WAL, returns None if
+    /// the alarm does not exist.
+    pub fn delete_alarm(&mut self, alarm_id: &str) -> Result<Option<Append>, Error> {
+        self.check_managed(alarm_id)?;
+        self.managed.remove(alarm_id);
+        if !self.delete(alarm_id) {
+            return Ok(None);
         }
-        if should_save_in_wal && !recover_mode {
-            //TODO for now using json, but in the future we should use something better
-            let mut serialized = serde_json::to_vec(&metric)?;
-            let mut data = vec![];
-            data.extend_from_slice(&serialized.len().to_ne_bytes());
-            data.append(&mut serialized);
-            self.wal.write(&data)?;
+        self.write(&Record::DeleteAlarm(alarm_id.to_string()))
+            .map(Some)
+    }
+
+    /// the alarms given when the service was created (e.g. loaded from the
+    /// alarm definitions) cannot be changed through the admin API, since
+    /// they would be overwritten when they are loaded again.
+    fn check_managed(&self, alarm_id: &str) -> Result<(), Error> {
+        if self.alarms.contains_key(alarm_id) && !self.is_managed(alarm_id) {
+            return Err(Error::NotManaged(alarm_id.to_string()));
         }
         Ok(())
     }
 
+    /// alarms created through the admin API notify through our logs.
+    fn managed_alarm(id: String, config: AlarmConfig) -> Box<dyn Alarm> {
+        new_alarm(id, config, Box::new(LogNotifier { level: Level::WARN }))
+    }
+
+    /// sorts the alarms so that every alarm comes after the alarms it depends on
+    /// (depth first search), dependencies that are not registered are ignored.
+    fn evaluation_order(alarms: &HashMap<String, Box<dyn Alarm>>) -> Result<Vec<String>, Error> {
+        fn visit(
+            id: &str,
+            alarms: &HashMap<String, Box<dyn Alarm>>,
+            visiting: &mut HashSet<String>,
+            order: &mut Vec<String>,
+        ) -> Result<(), Error> {
+            if order.iter().any(|visited| visited == id) {
+                return Ok(());
+            }
+            let alarm = match alarms.get(id) {
+                Some(alarm) => alarm,
+                None => return Ok(()),
+            };
+            if !visiting.insert(id.to_string()) {
+                return Err(Error::DependencyCycle(id.to_string()));
+            }
+            for dependency in alarm.dependencies() {
+                visit(&dependency, alarms, visiting, order)?;
+            }
+            visiting.remove(id);
+            order.push(id.to_string());
+            Ok(())
+        }
+
+        let mut ids: Vec<&String> = alarms.keys().collect();
+        ids.sort();
+        let mut order = Vec::with_capacity(ids.len());
+        for id in ids {
+            visit(id, alarms, &mut HashSet::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// persists and applies the metric at once, used by the tests.
+    #[cfg(test)]
+    pub fn consume(&mut self, metric: metrics::Metric) -> Result<Option<Append>, Error> {
+        let metrics = [metric];
+        let append = self.persist(&metrics)?;
+        self.apply(&metrics);
+        Ok(append)
+    }
+
+    /// saves the metrics used by any alarm into our WAL, without consuming
+    /// them, the others are dropped since no one is using the data. They are
+    /// written in a single record, so either all of them are persisted or
+    /// none is. Once the returned future resolved, the metrics should be
+    /// consumed with `apply`: metrics that could not be saved are never
+    /// counted, so they can be sent again. The future should be awaited
+    /// without holding the lock of the service.
+    pub fn persist(&mut self, metrics: &[metrics::Metric]) -> Result<Option<Append>, Error> {
+        let used: Vec<metrics::Metric> = metrics
+            .iter()
+            .filter(|metric| self.alarms.values().any(|alarm| alarm.uses(metric)))
+            .cloned()
+            .collect();
+        if used.is_empty() {
+            return Ok(None);
+        }
+        self.write(&Record::Metrics(used)).map(Some)
+    }
+
+    /// hands the metrics to the alarms without writing them to the WAL,
+    /// for metrics that are already in it (see `persist`).
+    pub fn apply(&mut self, metrics: &[metrics::Metric]) {
+        self.apply_except(metrics, &HashSet::new());
+    }
+
+    /// like `apply`, except for the alarms in `skipped`.
+    fn apply_except(&mut self, metrics: &[metrics::Metric], skipped: &HashSet<String>) {
+        for metric in metrics {
+            for (id, alarm) in &self.alarms {
+                if !skipped.contains(id) {
+                    alarm.consume(metric);
+                }
+            }
+        }
+    }
+
+    /// the next record written to the WAL fails.
+    #[cfg(test)]
+    pub(crate) fn fail_next_write(&self) {
+        self.wal.wal().fail_next_write = true;
+    }
+
+    fn write(&mut self, record: &Record) -> Result<Append, Error> {
+        let (record_type, data) = record.encode()?;
+        Ok(self.wal.append(record_type, data))
+    }
+
+    fn record_written(&mut self, page: usize, record: &Record) {
+        let time = self.page_times.entry(page).or_default();
+        match record {
+            Record::Metric(metric) => *time = (*time).max(metric.time),
+            Record::Metrics(metrics) => {
+                *time = metrics
+                    .iter()
+                    .map(|metric| metric.time)
+                    .fold(*time, u64::max)
+            }
+            _ => {}
+        }
+    }
+
+    /// time of the newest metric of a sealed page, 0 if it has none.
+    /// Sealed pages never change, so they are only read once.
+    fn page_time(&mut self, page: usize) -> Result<u64, Error> {
+        if let Some(time) = self.page_times.get(&page) {
+            return Ok(*time);
+        }
+        let frames = self.wal.wal().iter_from(Position::new(page, 0))?;
+        for entry in frames {
+            let (position, frame) = entry?;
+            if position.page != page {
+                break;
+            }
+            self.record_written(page, &Record::decode(frame.record_type, &frame.data)?);
+        }
+        Ok(*self.page_times.entry(page).or_default())
+    }
+
+    /// writes a snapshot with the alarms created through the admin API and
+    /// the windows of all the alarms (see `Snapshot`), then deletes the WAL
+    /// pages whose metrics are all older than the longest window of our
+    /// alarms. No metric may be persisted without being applied meanwhile,
+    /// see `in_flight`. Reads the pages, so it should not run on the async
+    /// runtime. Returns how many pages were deleted.
+    pub fn compact(&mut self) -> Result<usize, Error> {
+        self.compact_at(Utc::now().timestamp_millis() as u64)
+    }
+
+    fn compact_at(&mut self, now: u64) -> Result<usize, Error> {
+        let retention = self
+            .alarms
+            .values()
+            .map(|alarm| alarm.config().time_window())
+            .max()
+            .unwrap_or(0);
+        // metrics are bucketed by minute, so we keep an extra one
+        let cutoff = now.saturating_sub((retention.max(0) as u64 + 1) * 60_000);
+
+        let (first_page, active_page) = {
+            let wal = self.wal.wal();
+            (wal.first_page(), wal.last_page())
+        };
+        let mut first_live_page = active_page;
+        for page in first_page..active_page {
+            if self.page_time(page)? >= cutoff {
+                first_live_page = page;
+                break;
+            }
+        }
+
+        // everything written so far was applied to the alarms
+        let position = {
+            let wal = self.wal.wal();
+            Position::new(wal.last_page(), wal.curr_page_size() as u64)
+        };
+        let mut alarms: Vec<AlarmRecord> = self
+            .managed
+            .iter()
+            .filter_map(|id| self.alarms.get(id))
+            .map(|alarm| AlarmRecord {
+                id: alarm.identifier(),
+                expression: alarm.config().to_string(),
+            })
+            .collect();
+        alarms.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut windows = BTreeMap::new();
+        for alarm in self.alarms.values() {
+            let saved = alarm.save().map_err(SnapshotError::from)?;
+            if !saved.is_null() {
+                let window = AlarmWindow {
+                    expression: alarm.config().to_string(),
+                    saved,
+                };
+                windows.insert(alarm.identifier(), window);
+            }
+        }
+        Snapshot {
+            first_page: first_live_page,
+            alarms,
+            position,
+            windows,
+        }
+        .write(&self.storage_path)?;
+
+        if first_live_page == first_page {
+            return Ok(0);
+        }
+
+        let removed = self.wal.wal().remove_pages_before(first_live_page)?;
+        self.page_times.retain(|page, _| *page >= first_live_page);
+        Ok(removed)
+    }
+
     /// checks if any alarm should alarm / disable alarm and also cleans
     /// old metrics from memory
-    fn tick(&self) {
-        for (_, alarm) in &self.alarms {
-            alarm.tick();
+    pub fn tick(&self) {
+        let mut states = AlarmStates::with_capacity(self.alarms.len());
+        for id in &self.evaluation_order {
+            if let Some(alarm) = self.alarms.get(id) {
+                alarm.tick(&states);
+                states.insert(id.clone(), alarm.status());
+            }
         }
     }
 
+    /// makes sure everything written to the WAL reached the disk.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.wal.wal().sync()?;
+        Ok(())
+    }
+
     /// recover tries to recover the configuration and metrics
-    /// from disk in case of a restart
+    /// from disk in case of a restart. The log is truncated at the first
+    /// corrupted record, everything after it is lost. Pages written before
+    /// records were framed come first, they are replayed as they were
+    /// written and sealed, so that frames are never appended to them.
+    ///
+    /// The alarms whose window is in the snapshot are restored from it, they
+    /// only get the metrics written after the snapshot.
     fn recover(&mut self) -> Result<(), Error> {
-        if self.wal.is_empty_wal() {
-            return Ok(());
-        }
-        let mut page = 0;
-        let mut offset = 0;
-        let mut size = [0 as u8; 8];
-        loop {
-            let read = match self.wal.read(page, offset, &mut size) {
-                Ok(read) => read,
-                Err(WALError::PageIndexOutOfRange) => break,
+        let mut restored = Restored::default();
+        if let Some(snapshot) = Snapshot::read(&self.storage_path)? {
+            for alarm in snapshot.alarms {
+                self.replay(Record::CreateAlarm(alarm))?;
+            }
+            for (id, window) in snapshot.windows {
+                // the alarm may have changed since, e.g. in the definitions
+                match self.alarms.get(&id) {
+                    Some(alarm) if alarm.config().to_string() == window.expression => {
+                        match alarm.restore(window.saved) {
+                            Ok(()) => {
+                                restored.alarms.insert(id);
+                            }
+                            Err(e) => event!(
+                                Level::ERROR,
+                                "could not restore the window of alarm {}: {}",
+                                id,
+                                e
+                            ),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            restored.position = snapshot.position;
+            // in case we crashed before deleting them
+            self.wal.wal().remove_pages_before(snapshot.first_page)?;
+        }
+        let (mut first_page, active_page) = {
+            let wal = self.wal.wal();
+            if wal.is_empty_wal() {
+                return Ok(());
+            }
+            (wal.first_page(), wal.last_page())
+        };
+        while self.wal.wal().is_unframed(first_page)? {
+            if !self.recover_unframed(first_page, &restored)? || first_page == active_page {
+                self.wal.wal().seal()?;
+                return Ok(());
+            }
+            first_page += 1;
+        }
+
+        let mut frames = self.wal.wal().iter_from(Position::new(first_page, 0))?;
+        while let Some(entry) = frames.next() {
+            let (position, frame) = match entry {
+                Ok(entry) => entry,
+                Err(WALError::CorruptedLogFile) => {
+                    let position = frames.position();
+                    self.truncate_corrupted(position)?;
+                    // the page is written again
+                    self.page_times.retain(|page, _| *page < position.page);
+                    break;
+                }
                 Err(e) => return Err(Error::WALError(e)),
             };
 
-            if read == 0 {
-                page += 1;
-                offset = 0;
-                continue;
-            } else {
-                // should always be 8
-                offset += read as u64;
+            let record = Record::decode(frame.record_type, &frame.data)?;
+            if position.page < active_page {
+                self.record_written(position.page, &record);
             }
+            self.replay_since(record, position, &restored)?;
+        }
 
-            let size = usize::from_ne_bytes(size);
-
-            let mut entry = Vec::with_capacity(size);
+        Ok(())
+    }
 
-            //FIXME work around, check if there is a better way
-            for _i in 0..size {
-                entry.push(0);
+    /// replays a page written before records were framed, returns false if
+    /// it was truncated, which also removes the pages after it.
+    fn recover_unframed(&mut self, page: usize, restored: &Restored) -> Result<bool, Error> {
+        let mut entries = self.wal.wal().iter_unframed(page)?;
+        while let Some(entry) = entries.next() {
+            match entry {
+                Ok((position, data)) => {
+                    let record = Record::decode_unframed(&data)?;
+                    // the page is sealed once recovered
+                    self.record_written(page, &record);
+                    self.replay_since(record, position, restored)?;
+                }
+                Err(WALError::CorruptedLogFile) => {
+                    self.truncate_corrupted(entries.position())?;
+                    return Ok(false);
+                }
+                Err(e) => return Err(Error::WALError(e)),
             }
+        }
+        Ok(true)
+    }
 
-            let read = self.wal.read(page, offset, &mut entry)?;
-            offset += read as u64;
-            let metric: metrics::Metric = serde_json::from_str(std::str::from_utf8(&entry)?)?;
+    /// drops the corrupted record at `position` and everything after it.
+    fn truncate_corrupted(&mut self, position: Position) -> Result<(), Error> {
+        event!(
+            Level::WARN,
+            "corrupted WAL record at page {} offset {}, truncating the log",
+            position.page,
+            position.offset
+        );
+        self.wal.wal().truncate(position.page, position.offset)?;
+        Ok(())
+    }
 
-            let _ = self.consume(metric, true)?;
+    /// replays the record read at `position`, unless it is already in the
+    /// snapshot: the records before its position are only replayed for the
+    /// alarms that were not restored from it.
+    fn replay_since(
+        &mut self,
+        record: Record,
+        position: Position,
+        restored: &Restored,
+    ) -> Result<(), Error> {
+        if position >= restored.position {
+            return self.replay(record);
+        }
+        match record {
+            Record::Metric(metric) => self.apply_except(&[metric], &restored.alarms),
+            Record::Metrics(metrics) => self.apply_except(&metrics, &restored.alarms),
+            // the alarms created through the admin API are in the snapshot
+            Record::CreateAlarm(_) | Record::UpdateAlarm(_) | Record::DeleteAlarm(_) => {}
         }
+        Ok(())
+    }
 
+    fn replay(&mut self, record: Record) -> Result<(), Error> {
+        match record {
+            Record::Metric(metric) => self.apply(&[metric]),
+            Record::Metrics(metrics) => self.apply(&metrics),
+            Record::CreateAlarm(alarm) | Record::UpdateAlarm(alarm) => {
+                let config =
+                    dsl::parse(&alarm.expression).map_err(|source| Error::InvalidAlarmInLog {
+                        id: alarm.id.clone(),
+                        source,
+                    })?;
+                // the alarms loaded from the definitions may have changed since
+                match self.add(Self::managed_alarm(alarm.id.clone(), config)) {
+                    Ok(()) => {
+                        self.managed.insert(alarm.id);
+                    }
+                    Err(e) => event!(Level::ERROR, "could not recover alarm {}: {}", alarm.id, e),
+                }
+            }
+            Record::DeleteAlarm(id) => {
+                self.managed.remove(&id);
+                self.delete(&id);
+            }
+        }
         Ok(())
     }
 }
@@ -130,6 +591,10 @@ impl AlarmService {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::alarm::alarm::{CompositeAlarm, NoOpNotifier};
+    use crate::alarm::state::{AlarmState, Status};
+    use crate::model::alarm::{AlarmConfig, CompositeAlarmConfig, LogicalOperator};
+    use crate::model::dsl;
     use std::sync::Mutex;
     use temp_dir::TempDir;
 
@@ -142,12 +607,25 @@ mod test {
             self.metrics.lock().unwrap().push(metric.clone());
             true
         }
-        fn tick(&self) {
+        fn uses(&self, _metric: &metrics::Metric) -> bool {
+            true
+        }
+        fn tick(&self, _states: &AlarmStates) {
             //no_op
         }
         fn identifier(&self) -> String {
             "AlarmForTest".to_string()
         }
+        fn status(&self) -> Status {
+            Status {
+                state: AlarmState::InsufficientData,
+                since: chrono::DateTime::UNIX_EPOCH,
+            }
+        }
+
+        fn config(&self) -> AlarmConfig {
+            dsl::parse("count(MetricName) > 0").unwrap()
+        }
 
         fn metrics(&self) -> Vec<metrics::Metric> {
             self.metrics.lock().unwrap().clone()
@@ -174,10 +652,7 @@ mod test {
         // and if consumed it should write to the WAL.
         // after dropping and restarting the service we should get back the same state as before
         let path = TempDir::new().unwrap();
-        let config = Config {
-            max_size_per_page_wal: 500,
-            storage_path: path.path().to_owned(),
-        };
+        let config = Config::new(path.path().to_owned());
         let mut alarm_service = AlarmService::new(
             config.clone(),
             vec![Box::new(ConsumeAllMetricsAlarm {
@@ -188,8 +663,8 @@ mod test {
 
         let number_of_metrics = 3;
 
-        for i in 0..number_of_metrics {
-            let _ = alarm_service.consume(fake_metric(), false);
+        for _ in 0..number_of_metrics {
+            let _ = alarm_service.consume(fake_metric());
         }
         assert_eq!(
             3,
@@ -202,7 +677,7 @@ mod test {
         );
         drop(alarm_service);
 
-        let mut alarm_service = AlarmService::new(
+        let alarm_service = AlarmService::new(
             config.clone(),
             vec![Box::new(ConsumeAllMetricsAlarm {
                 metrics: Mutex::new(vec![]),
@@ -220,4 +695,238 @@ mod test {
                 .len()
         );
     }
+
+    fn composite(id: &str, dependency: &str) -> Box<dyn Alarm> {
+        Box::new(CompositeAlarm::new(
+            id.to_string(),
+            CompositeAlarmConfig {
+                alarm: LogicalOperator::Identity(Box::new(dependency.to_string())),
+            },
+            Box::new(NoOpNotifier {}),
+        ))
+    }
+
+    #[test]
+    fn alarms_are_evaluated_after_their_dependencies() {
+        let path = TempDir::new().unwrap();
+        let mut alarm_service = AlarmService::new(
+            Config::new(path.path().to_owned()),
+            vec![composite("a", "b"), composite("b", "c")],
+        )
+        .unwrap();
+        assert_eq!(vec!["b", "a"], alarm_service.evaluation_order);
+
+        alarm_service.add(composite("c", "d")).unwrap();
+        assert_eq!(vec!["c", "b", "a"], alarm_service.evaluation_order);
+
+        // c -> a -> b -> c
+        assert!(matches!(
+            alarm_service.add(composite("c", "a")),
+            Err(Error::DependencyCycle(_))
+        ));
+        assert!(matches!(
+            alarm_service.add(composite("d", "d")),
+            Err(Error::DependencyCycle(_))
+        ));
+        assert_eq!(vec!["c", "b", "a"], alarm_service.evaluation_order);
+        assert_eq!(3, alarm_service.alarms.len());
+
+        assert!(alarm_service.delete("b"));
+        assert_eq!(vec!["c", "a"], alarm_service.evaluation_order);
+        alarm_service.tick();
+    }
+
+    #[tokio::test]
+    async fn managed_alarms_are_recovered() {
+        let path = TempDir::new().unwrap();
+        let config = Config::new(path.path().to_owned());
+        let expression = |id: &str| {
+            let alarm_service = AlarmService::new(config.clone(), vec![]).unwrap();
+            alarm_service
+                .alarm(id)
+                .map(|alarm| alarm.config().to_string())
+        };
+
+        let mut alarm_service = AlarmService::new(config.clone(), vec![]).unwrap();
+        let parse = |expression: &str| dsl::parse(expression).unwrap();
+        alarm_service
+            .create_alarm("cpu".to_string(), parse("max(cpu.usage) > 80"))
+            .unwrap()
+            .await
+            .unwrap();
+        alarm_service
+            .create_alarm("memory".to_string(), parse("max(memory.usage) > 80"))
+            .unwrap()
+            .await
+            .unwrap();
+        alarm_service
+            .update_alarm("cpu".to_string(), parse("max(cpu.usage) > 90"))
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(alarm_service.delete_alarm("memory").unwrap().is_some());
+        assert!(alarm_service.delete_alarm("memory").unwrap().is_none());
+        drop(alarm_service);
+
+        assert_eq!(
+            Some("max(cpu.usage) > 90 over 5m".to_string()),
+            expression("cpu")
+        );
+        assert_eq!(None, expression("memory"));
+    }
+
+    #[test]
+    fn recovery_truncates_torn_writes() {
+        let path = TempDir::new().unwrap();
+        let config = Config::new(path.path().to_owned());
+        let alarm = || -> Box<dyn Alarm> {
+            Box::new(ConsumeAllMetricsAlarm {
+                metrics: Mutex::new(vec![]),
+            })
+        };
+        let mut alarm_service = AlarmService::new(config.clone(), vec![alarm()]).unwrap();
+        alarm_service.consume(fake_metric()).unwrap();
+        alarm_service.consume(fake_metric()).unwrap();
+        drop(alarm_service);
+
+        let log = path.path().join("log_page_0");
+        let valid = std::fs::metadata(&log).unwrap().len();
+        let mut file = std::fs::OpenOptions::new().append(true).open(&log).unwrap();
+        std::io::Write::write_all(&mut file, &[0xB1, 1, 200, 0, 0]).unwrap();
+
+        let alarm_service = AlarmService::new(config, vec![alarm()]).unwrap();
+        let recovered = alarm_service.alarm("AlarmForTest").unwrap().metrics();
+        assert_eq!(2, recovered.len());
+        assert_eq!(valid, std::fs::metadata(&log).unwrap().len());
+    }
+
+    #[test]
+    fn recovery_reads_unframed_logs() {
+        let path = TempDir::new().unwrap();
+        let config = Config::new(path.path().to_owned());
+        let alarm = || -> Box<dyn Alarm> {
+            Box::new(ConsumeAllMetricsAlarm {
+                metrics: Mutex::new(vec![]),
+            })
+        };
+        // metrics as written before records were framed
+        let mut legacy = vec![];
+        for _ in 0..2 {
+            let metric = serde_json::to_vec(&fake_metric()).unwrap();
+            legacy.extend_from_slice(&metric.len().to_ne_bytes());
+            legacy.extend_from_slice(&metric);
+        }
+        let log = path.path().join("log_page_0");
+        std::fs::write(&log, &legacy).unwrap();
+
+        let mut alarm_service = AlarmService::new(config.clone(), vec![alarm()]).unwrap();
+        assert_eq!(
+            2,
+            alarm_service.alarm("AlarmForTest").unwrap().metrics().len()
+        );
+        // the page was sealed, new records are framed on the next one
+        alarm_service.consume(fake_metric()).unwrap();
+        assert_eq!(1, alarm_service.wal.wal().last_page());
+        drop(alarm_service);
+        assert_eq!(legacy, std::fs::read(&log).unwrap());
+
+        let alarm_service = AlarmService::new(config, vec![alarm()]).unwrap();
+        assert_eq!(
+            3,
+            alarm_service.alarm("AlarmForTest").unwrap().metrics().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_managed_alarms_and_recent_metrics() {
+        let path = TempDir::new().unwrap();
+        let config = Config {
+            max_size_per_page_wal: 100,
+            ..Config::new(path.path().to_owned())
+        };
+        let alarm = || -> Box<dyn Alarm> {
+            Box::new(ConsumeAllMetricsAlarm {
+                metrics: Mutex::new(vec![]),
+            })
+        };
+        let mut alarm_service = AlarmService::new(config.clone(), vec![alarm()]).unwrap();
+        alarm_service.consume(fake_metric()).unwrap();
+        alarm_service
+            .create_alarm(
+                "cpu".to_string(),
+                dsl::parse("max(cpu.usage) > 80").unwrap(),
+            )
+            .unwrap()
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            alarm_service.consume(fake_metric()).unwrap();
+        }
+        let now = 60 * 60 * 1000;
+        let mut recent = fake_metric();
+        recent.time = now;
+        alarm_service.consume(recent).unwrap();
+        let pages = {
+            let wal = alarm_service.wal.wal();
+            wal.last_page() - wal.first_page()
+        };
+        assert!(pages > 1);
+
+        // the old metrics are still inside the window
+        assert_eq!(0, alarm_service.compact_at(5 * 60 * 1000).unwrap());
+        let removed = alarm_service.compact_at(now).unwrap();
+        assert!(removed > 0);
+        assert!(!path.path().join("log_page_0").exists());
+        drop(alarm_service);
+
+        let alarm_service = AlarmService::new(config, vec![alarm()]).unwrap();
+        assert_eq!(
+            "max(cpu.usage) > 80 over 5m",
+            alarm_service.alarm("cpu").unwrap().config().to_string()
+        );
+        let recovered = alarm_service.alarm("AlarmForTest").unwrap().metrics();
+        assert!(recovered.iter().any(|metric| metric.time == now));
+        assert!(recovered.len() < 6);
+    }
+
+    #[test]
+    fn alarm_windows_survive_a_restart() {
+        let path = TempDir::new().unwrap();
+        let config = Config::new(path.path().to_owned());
+        let alarm = || {
+            AlarmService::managed_alarm(
+                "cpu".to_string(),
+                dsl::parse("count(cpu.usage) > 2").unwrap(),
+            )
+        };
+        // in the last complete minute
+        let cpu = || metrics::Metric {
+            name: "cpu.usage".to_string(),
+            time: Utc::now().timestamp_millis() as u64 - 60_000,
+            ..fake_metric()
+        };
+        let mut alarm_service = AlarmService::new(config.clone(), vec![alarm()]).unwrap();
+        for _ in 0..3 {
+            alarm_service.consume(cpu()).unwrap();
+        }
+        alarm_service.tick();
+        let status = alarm_service.alarm("cpu").unwrap().status();
+        assert_eq!(AlarmState::Alarm, status.state);
+        alarm_service.compact().unwrap();
+        alarm_service.consume(cpu()).unwrap();
+        drop(alarm_service);
+
+        let alarm_service = AlarmService::new(config, vec![alarm()]).unwrap();
+        let alarm = alarm_service.alarm("cpu").unwrap();
+        assert_eq!(status, alarm.status());
+        // only the metric written after the snapshot was replayed
+        assert_eq!(1, alarm.metrics().len());
+        let saved = alarm.save().unwrap();
+        let buckets: Vec<_> = saved["groups"][""]["metrics"]
+            .as_object()
+            .unwrap()
+            .values()
+            .collect();
+        assert_eq!(vec![&serde_json::json!({"Count": {"count": 4}})], buckets);
+    }
 }
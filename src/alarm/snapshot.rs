@@ -0,0 +1,125 @@
+use crate::alarm::record::AlarmRecord;
+use crate::wal::{Error as WALError, Frame, Position};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+// named like `wal::Error`
+#[allow(clippy::enum_variant_names)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Could not access the snapshot {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid snapshot {0}")]
+    InvalidSnapshot(#[from] serde_json::Error),
+    #[error("Corrupted snapshot {0}")]
+    CorruptedSnapshot(#[from] WALError),
+}
+
+/// Snapshot keeps what the alarm service needs from the WAL pages it deletes:
+/// the alarms created through the admin API. It also keeps the windows and
+/// states of the alarms as they were at `position`, so that an alarm does
+/// not start over (and notify again) after a restart. Only pages older than
+/// the longest window are deleted, the alarms without a window in the
+/// snapshot are rebuilt by replaying the pages from `first_page` onwards.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Snapshot {
+    /// the oldest WAL page that must be replayed after the snapshot.
+    pub first_page: usize,
+    pub alarms: Vec<AlarmRecord>,
+    /// the end of the WAL when the snapshot was taken, the records before
+    /// it are already in `alarms` and `windows`.
+    #[serde(default)]
+    pub position: Position,
+    /// what the alarms saved (see `Alarm::save`), by alarm id.
+    #[serde(default)]
+    pub windows: BTreeMap<String, AlarmWindow>,
+}
+
+/// the window and state of an alarm, only restored if the alarm still has
+/// the same expression.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AlarmWindow {
+    /// the alarm written in our DSL (see `model::dsl`).
+    pub expression: String,
+    pub saved: serde_json::Value,
+}
+
+impl Snapshot {
+    const FILE_NAME: &'static str = "snapshot";
+    const RECORD_TYPE: u8 = 1;
+
+    /// replaces the snapshot atomically, a crash leaves the previous one.
+    pub fn write(&self, dir: &Path) -> Result<(), Error> {
+        let frame = Frame::new(Self::RECORD_TYPE, serde_json::to_vec(self)?);
+        let tmp = dir.join(format!("{}.tmp", Self::FILE_NAME));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&frame.encode())?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(Self::FILE_NAME))?;
+        // makes the rename durable
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// None if no snapshot was written yet.
+    pub fn read(dir: &Path) -> Result<Option<Self>, Error> {
+        let data = match fs::read(dir.join(Self::FILE_NAME)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() < Frame::HEADER_SIZE {
+            return Err(WALError::CorruptedLogFile.into());
+        }
+        let (header, content) = data.split_at(Frame::HEADER_SIZE);
+        let (record_type, length, checksum) = Frame::decode_header(header.try_into().unwrap())?;
+        if record_type != Self::RECORD_TYPE || length != content.len() {
+            return Err(WALError::CorruptedLogFile.into());
+        }
+        let frame = Frame::verify(record_type, content.to_vec(), checksum)?;
+        Ok(Some(serde_json::from_slice(&frame.data)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn snapshots_round_trip() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(None, Snapshot::read(dir.path()).unwrap());
+
+        let snapshot = Snapshot {
+            first_page: 3,
+            alarms: vec![AlarmRecord {
+                id: "cpu".to_string(),
+                expression: "max(cpu.usage) > 80".to_string(),
+            }],
+            position: Position::new(4, 120),
+            windows: BTreeMap::from([(
+                "cpu".to_string(),
+                AlarmWindow {
+                    expression: "max(cpu.usage) > 80".to_string(),
+                    saved: serde_json::json!({"status": "Ok"}),
+                },
+            )]),
+        };
+        snapshot.write(dir.path()).unwrap();
+        assert_eq!(Some(snapshot), Snapshot::read(dir.path()).unwrap());
+
+        let path = dir.path().join(Snapshot::FILE_NAME);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(matches!(
+            Snapshot::read(dir.path()),
+            Err(Error::CorruptedSnapshot(WALError::CorruptedLogFile))
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// current status of the alarms, by alarm id.
+pub type AlarmStates = HashMap<String, Status>;
+
+/// State of an alarm, every alarm starts as `InsufficientData`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AlarmState {
+    /// the metrics are within the threshold.
+    Ok,
+    /// the metrics are breaching the threshold, but not for long enough to alarm.
+    Pending,
+    /// the metrics are breaching the threshold.
+    Alarm,
+    /// there is no data to decide.
+    InsufficientData,
+}
+
+impl fmt::Display for AlarmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            AlarmState::Ok => "OK",
+            AlarmState::Pending => "PENDING",
+            AlarmState::Alarm => "ALARM",
+            AlarmState::InsufficientData => "INSUFFICIENT_DATA",
+        };
+        f.write_str(state)
+    }
+}
+
+/// Result of checking the window of an alarm against its thresholds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Evaluation {
+    /// enough data points are breaching the threshold to alarm.
+    Breaching,
+    /// some data points are breaching, but not enough to alarm.
+    PartiallyBreaching,
+    /// no data point is breaching, but the window is not recovered yet
+    /// (e.g. the values are between the threshold and the recovery threshold).
+    NotBreaching,
+    /// enough recent data points are within the recovery threshold.
+    Recovered,
+    /// the window has no data.
+    NoData,
+    /// the window has no data, but the alarm should keep its state.
+    Unchanged,
+}
+
+impl AlarmState {
+    /// the state as a boolean for logical expressions, None if unknown.
+    /// An alarm that is pending is not alarming yet.
+    pub fn is_alarming(&self) -> Option<bool> {
+        match self {
+            AlarmState::Alarm => Some(true),
+            AlarmState::Ok | AlarmState::Pending => Some(false),
+            AlarmState::InsufficientData => None,
+        }
+    }
+}
+
+impl Evaluation {
+    /// evaluation of a logical expression over alarms, None if unknown.
+    pub fn from_alarming(alarming: Option<bool>) -> Self {
+        match alarming {
+            Some(true) => Evaluation::Breaching,
+            Some(false) => Evaluation::Recovered,
+            None => Evaluation::NoData,
+        }
+    }
+}
+
+/// Current state of an alarm and since when it is in that state.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Status {
+    pub state: AlarmState,
+    pub since: DateTime<Utc>,
+}
+
+/// A change of state, alarms only notify on transitions.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Transition {
+    pub alarm_id: String,
+    pub from: AlarmState,
+    pub to: AlarmState,
+    pub at: DateTime<Utc>,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "alarm {} changed from {} to {} at {}",
+            self.alarm_id,
+            self.from,
+            self.to,
+            self.at.to_rfc3339()
+        )
+    }
+}
+
+/// StateMachine keeps the state of a single alarm.
+#[derive(Debug)]
+pub struct StateMachine {
+    status: Status,
+}
+
+impl StateMachine {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            status: Status {
+                state: AlarmState::InsufficientData,
+                since: now,
+            },
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// all the transitions of our alarms. An alarm that is alarming stays so
+    /// until it is `Recovered`, otherwise `PartiallyBreaching` moves it to
+    /// `Pending` and `NotBreaching` to `Ok`.
+    pub fn next_state(current: AlarmState, evaluation: Evaluation) -> AlarmState {
+        match (current, evaluation) {
+            (_, Evaluation::NoData) => AlarmState::InsufficientData,
+            (current, Evaluation::Unchanged) => current,
+            (_, Evaluation::Breaching) => AlarmState::Alarm,
+            (_, Evaluation::Recovered) => AlarmState::Ok,
+            (AlarmState::Alarm, Evaluation::PartiallyBreaching | Evaluation::NotBreaching) => {
+                AlarmState::Alarm
+            }
+            (_, Evaluation::PartiallyBreaching) => AlarmState::Pending,
+            (_, Evaluation::NotBreaching) => AlarmState::Ok,
+        }
+    }
+
+    /// the state machine as it was saved, e.g. in a snapshot.
+    pub fn restore(status: Status) -> Self {
+        Self { status }
+    }
+
+    /// forces the state, for states derived from other states.
+    pub fn set(&mut self, state: AlarmState, now: DateTime<Utc>) {
+        if self.status.state != state {
+            self.status = Status { state, since: now };
+        }
+    }
+
+    /// applies the evaluation, returning the transition if the state changed.
+    pub fn apply(
+        &mut self,
+        alarm_id: &str,
+        evaluation: Evaluation,
+        now: DateTime<Utc>,
+    ) -> Option<Transition> {
+        let from = self.status.state;
+        let to = Self::next_state(from, evaluation);
+        if from == to {
+            return None;
+        }
+
+        self.status = Status {
+            state: to,
+            since: now,
+        };
+        Some(Transition {
+            alarm_id: alarm_id.to_string(),
+            from,
+            to,
+            at: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        use AlarmState::*;
+        use Evaluation::*;
+
+        for (current, evaluation, expected) in [
+            (InsufficientData, NoData, InsufficientData),
+            (InsufficientData, PartiallyBreaching, Pending),
+            (InsufficientData, Breaching, Alarm),
+            (InsufficientData, NotBreaching, Ok),
+            (InsufficientData, Recovered, Ok),
+            (Ok, PartiallyBreaching, Pending),
+            (Ok, Breaching, Alarm),
+            (Ok, NoData, InsufficientData),
+            (Pending, Breaching, Alarm),
+            (Pending, NotBreaching, Ok),
+            (Pending, Recovered, Ok),
+            (Pending, PartiallyBreaching, Pending),
+            (Alarm, PartiallyBreaching, Alarm),
+            (Alarm, NotBreaching, Alarm),
+            (Alarm, Recovered, Ok),
+            (Alarm, Breaching, Alarm),
+            (Alarm, NoData, InsufficientData),
+            (Alarm, Unchanged, Alarm),
+            (InsufficientData, Unchanged, InsufficientData),
+        ] {
+            assert_eq!(
+                expected,
+                StateMachine::next_state(current, evaluation),
+                "{} with {:?}",
+                current,
+                evaluation
+            );
+        }
+    }
+
+    #[test]
+    fn apply_only_reports_changes() {
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        let later = DateTime::from_timestamp(60, 0).unwrap();
+        let mut state_machine = StateMachine::new(start);
+
+        assert_eq!(None, state_machine.apply("a", Evaluation::NoData, later));
+        assert_eq!(start, state_machine.status().since);
+
+        let transition = state_machine
+            .apply("a", Evaluation::Breaching, later)
+            .unwrap();
+        assert_eq!(AlarmState::InsufficientData, transition.from);
+        assert_eq!(AlarmState::Alarm, transition.to);
+        assert_eq!(
+            Status {
+                state: AlarmState::Alarm,
+                since: later
+            },
+            state_machine.status()
+        );
+        assert_eq!(
+            "alarm a changed from INSUFFICIENT_DATA to ALARM at 1970-01-01T00:01:00+00:00",
+            transition.to_string()
+        );
+    }
+}
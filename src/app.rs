@@ -1,8 +1,18 @@
 use crate::admin::server::AdminService;
+use crate::alarm::definition::{AlarmDefinitions, Error as DefinitionError};
+use crate::alarm::evaluator::Evaluator;
+use crate::alarm::reloader::Reloader;
+use crate::alarm::service::{
+    AlarmService, Config as AlarmServiceConfig, Error as AlarmServiceError,
+};
+use crate::metrics::http;
 use crate::metrics::server::MetricsService;
 use crate::server;
+use crate::wal::{Compression, Durability};
 use std::net::AddrParseError;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -15,6 +25,33 @@ pub enum AppError {
     InvalidPort(#[from] AddrParseError),
     #[error("Could not start serving the grpc on port")]
     GrpcStartError(#[from] tonic::transport::Error),
+    #[error("Could not start serving the http on port")]
+    HttpStartError(#[from] hyper::Error),
+    #[error("Could not start the alarm service {0}")]
+    AlarmServiceError(#[from] AlarmServiceError),
+    #[error("Could not load the alarm definitions {0}")]
+    AlarmDefinitionsError(#[from] DefinitionError),
+    #[error("Could not watch the alarm definitions {0}")]
+    ReloaderError(#[from] std::io::Error),
+}
+
+/// Config of the whole application
+pub struct Config {
+    pub grpc_server_port: u16,
+    /// port of the OTLP/HTTP ingestion
+    pub http_server_port: u16,
+    pub logs_dir: PathBuf,
+    /// where the WAL of the alarm service is kept
+    pub storage_path: PathBuf,
+    pub max_size_per_page_wal: usize,
+    /// when the WAL is synced to disk
+    pub wal_durability: Durability,
+    /// how the full WAL pages are compressed
+    pub wal_compression: Compression,
+    /// how often the alarms are evaluated
+    pub evaluation_interval: Duration,
+    /// YAML or TOML file with the alarm definitions, reloaded when it changes
+    pub alarms_path: Option<PathBuf>,
 }
 
 /// App manages the state of the whole application
@@ -22,33 +59,84 @@ pub enum AppError {
 pub struct App {}
 
 impl App {
+    /// how often the alarm definitions file is checked for changes.
+    const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
     /// starts all the services belonging to the grpc server
-    /// including the health_service.
-    pub async fn run_server(grpc_server_port: u16, logs_dir: &Path) -> Result<(), AppError> {
-        init_tracing(logs_dir);
+    /// including the health_service, and the OTLP/HTTP ingestion
+    /// next to it.
+    pub async fn run_server(config: Config) -> Result<(), AppError> {
+        init_tracing(&config.logs_dir);
 
         // creates a channel to warn when server should shutdown
         let (tx, rx): (Sender<bool>, Receiver<bool>) = mpsc::channel(1);
         let (health_reporter, health_service) = tonic_health::server::health_reporter();
 
-        let mut metrics_service = MetricsService::new(health_reporter.clone());
-        let mut admin_service = AdminService::new(health_reporter.clone(), tx);
-        let services: Vec<Box<dyn server::Administrable + Send>> = vec![
+        // alarms are loaded before the WAL is recovered, so their windows
+        // are filled with the metrics we already received.
+        let (definitions, alarms) = match config.alarms_path {
+            Some(path) => {
+                let (definitions, alarms) = AlarmDefinitions::load(path)?;
+                (Some(definitions), alarms)
+            }
+            None => (None, vec![]),
+        };
+        let alarm_service = AlarmService::new(
+            AlarmServiceConfig {
+                max_size_per_page_wal: config.max_size_per_page_wal,
+                storage_path: config.storage_path,
+                durability: config.wal_durability,
+                compression: config.wal_compression,
+            },
+            alarms,
+        )?;
+        let alarm_service = Arc::new(Mutex::new(alarm_service));
+
+        let evaluator = Evaluator::new(alarm_service.clone(), config.evaluation_interval);
+        evaluator.start();
+
+        let reloader = match definitions {
+            Some(definitions) => {
+                let reloader =
+                    Reloader::new(alarm_service.clone(), definitions, Self::RELOAD_INTERVAL);
+                reloader.start()?;
+                Some(reloader)
+            }
+            None => None,
+        };
+
+        let mut metrics_service =
+            MetricsService::new(health_reporter.clone(), alarm_service.clone());
+        let mut admin_service = AdminService::new(health_reporter.clone(), tx, alarm_service);
+        // the evaluator is the last one, so that the WAL is flushed only
+        // after we stopped receiving metrics.
+        let mut services: Vec<Box<dyn server::Administrable + Send>> = vec![
             Box::new(metrics_service.clone()),
             Box::new(admin_service.clone()),
         ];
+        if let Some(reloader) = reloader {
+            services.push(Box::new(reloader));
+        }
+        services.push(Box::new(evaluator));
         watch_server(rx, services);
 
-        let addr = format!("127.0.0.1:{0}", grpc_server_port).parse()?;
+        let addr = format!("127.0.0.1:{0}", config.grpc_server_port).parse()?;
+        let http_addr = format!("127.0.0.1:{0}", config.http_server_port).parse()?;
 
-        event!(Level::INFO, "starting grpc server");
+        event!(Level::INFO, "starting http server");
+        let http_server = axum::Server::try_bind(&http_addr)?
+            .serve(http::router(metrics_service.clone()).into_make_service());
 
-        Server::builder()
+        event!(Level::INFO, "starting grpc server");
+        let grpc_server = Server::builder()
             .add_service(health_service)
             .add_service(metrics_service.ingestion_server().await)
             .add_service(admin_service.admin_server().await)
-            .serve(addr)
-            .await?;
+            .serve(addr);
+
+        tokio::try_join!(async { grpc_server.await.map_err(AppError::from) }, async {
+            http_server.await.map_err(AppError::from)
+        },)?;
 
         Ok(())
     }
@@ -4,4 +4,7 @@ pub mod app;
 mod metrics;
 pub mod model;
 mod server;
-mod wal;
+pub mod wal;
+
+/// the records of the alarm service WAL, public for the benchmarks.
+pub use alarm::record;
@@ -1,25 +1,49 @@
 use clap::Parser;
+use guardian_bell::app;
+use guardian_bell::wal::{Compression, Durability};
 use std::path::PathBuf;
-
-mod admin;
-mod alarm;
-mod app;
-mod metrics;
-mod model;
-mod server;
-mod wal;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     grpc_server_port: u16,
+    #[arg(long, default_value_t = 4318)]
+    http_server_port: u16,
     #[arg(short, long)]
     log_path: PathBuf,
+    #[arg(short, long)]
+    storage_path: PathBuf,
+    #[arg(short, long, default_value_t = 64 * 1024 * 1024)]
+    max_size_per_page_wal: usize,
+    /// when the WAL is synced to disk: always, os, <N>ms or <N>bytes
+    #[arg(long, default_value = "os")]
+    wal_durability: Durability,
+    /// how the full WAL pages are compressed: none, lz4 or zstd
+    #[arg(long, default_value = "none")]
+    wal_compression: Compression,
+    /// how often, in seconds, the alarms are evaluated
+    #[arg(short, long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    evaluation_interval: u64,
+    /// YAML or TOML file with the alarm definitions, reloaded on changes or SIGHUP
+    #[arg(long)]
+    alarms: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), app::AppError> {
     let args = Args::parse();
-    app::App::run_server(args.grpc_server_port, &args.log_path).await
+    app::App::run_server(app::Config {
+        grpc_server_port: args.grpc_server_port,
+        http_server_port: args.http_server_port,
+        logs_dir: args.log_path,
+        storage_path: args.storage_path,
+        max_size_per_page_wal: args.max_size_per_page_wal,
+        wal_durability: args.wal_durability,
+        wal_compression: args.wal_compression,
+        evaluation_interval: Duration::from_secs(args.evaluation_interval),
+        alarms_path: args.alarms,
+    })
+    .await
 }
@@ -0,0 +1,303 @@
+use crate::metrics::server::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::metrics::server::{Error, MetricsService};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use flate2::read::GzDecoder;
+use prost::Message;
+use std::io::Read;
+use tonic::Code;
+use tracing::{event, Level};
+
+/// max size of a request body, before and after decompression.
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// google.rpc.Status, OTLP/HTTP uses it as the body of any failed request.
+#[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Encoding {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Protobuf => PROTOBUF_CONTENT_TYPE,
+            Encoding::Json => JSON_CONTENT_TYPE,
+        }
+    }
+}
+
+/// OTLP/HTTP ingestion, it accepts `POST /v1/metrics` encoded as protobuf or JSON
+/// and optionally compressed with gzip.
+pub fn router(metrics_service: MetricsService) -> Router {
+    Router::new()
+        .route("/v1/metrics", post(export))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(metrics_service)
+}
+
+async fn export(
+    State(metrics_service): State<MetricsService>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let encoding = match content_type(&headers) {
+        Some(encoding) => encoding,
+        None => {
+            return error_response(
+                Encoding::Protobuf,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Code::InvalidArgument,
+                "content-type must be application/x-protobuf or application/json",
+            )
+        }
+    };
+
+    let body = match decompress(&headers, body) {
+        Ok(body) => body,
+        Err((status, message)) => {
+            return error_response(encoding, status, Code::InvalidArgument, message)
+        }
+    };
+
+    let request = match encoding {
+        Encoding::Protobuf => ExportMetricsServiceRequest::decode(body.as_slice())
+            .map_err(|e| format!("invalid protobuf payload: {}", e)),
+        Encoding::Json => serde_json::from_slice::<ExportMetricsServiceRequest>(&body)
+            .map_err(|e| format!("invalid json payload: {}", e)),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(message) => {
+            return error_response(
+                encoding,
+                StatusCode::BAD_REQUEST,
+                Code::InvalidArgument,
+                &message,
+            )
+        }
+    };
+
+    match metrics_service.ingest(request).await {
+        Ok(response) => {
+            let body = match encoding {
+                Encoding::Protobuf => response.encode_to_vec(),
+                Encoding::Json => serde_json::to_vec(&response).unwrap_or_default(),
+            };
+            encoded_response(encoding, StatusCode::OK, body)
+        }
+        Err(Error::AlarmServiceUnavailable) => error_response(
+            encoding,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Code::Internal,
+            "alarm service is unavailable",
+        ),
+        // 503 tells the OTLP client that it should retry later
+        Err(Error::PersistError(e)) => {
+            event!(Level::ERROR, "error while persisting metrics {:0}", e);
+            error_response(
+                encoding,
+                StatusCode::SERVICE_UNAVAILABLE,
+                Code::Unavailable,
+                "error while persisting metrics",
+            )
+        }
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> Option<Encoding> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    // ignores parameters such as charset
+    match content_type.split(';').next()?.trim() {
+        PROTOBUF_CONTENT_TYPE => Some(Encoding::Protobuf),
+        JSON_CONTENT_TYPE => Some(Encoding::Json),
+        _ => None,
+    }
+}
+
+fn decompress(headers: &HeaderMap, body: Bytes) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    let content_encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+
+    match content_encoding.as_deref() {
+        None | Some("identity") => Ok(body.to_vec()),
+        Some("gzip") => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(MAX_BODY_SIZE as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|_| (StatusCode::BAD_REQUEST, "invalid gzip payload"))?;
+            if decompressed.len() > MAX_BODY_SIZE {
+                return Err((StatusCode::PAYLOAD_TOO_LARGE, "payload is too large"));
+            }
+            Ok(decompressed)
+        }
+        Some(_) => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "content-encoding must be gzip or identity",
+        )),
+    }
+}
+
+fn error_response(encoding: Encoding, status: StatusCode, code: Code, message: &str) -> Response {
+    let rpc_status = RpcStatus {
+        code: code as i32,
+        message: message.to_string(),
+    };
+    let body = match encoding {
+        Encoding::Protobuf => rpc_status.encode_to_vec(),
+        Encoding::Json => serde_json::to_vec(&rpc_status).unwrap_or_default(),
+    };
+    encoded_response(encoding, status, body)
+}
+
+fn encoded_response(encoding: Encoding, status: StatusCode, body: Vec<u8>) -> Response {
+    (
+        status,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(encoding.content_type()),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alarm::service::{AlarmService, Config};
+    use crate::metrics::server::proto::collector::metrics::v1::ExportMetricsServiceResponse;
+    use axum::body::Body;
+    use axum::http::Request;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use temp_dir::TempDir;
+    use tower::ServiceExt;
+
+    const JSON_REQUEST: &str = r#"{
+        "resourceMetrics": [{
+            "resource": {"attributes": [{"key": "service", "value": {"stringValue": "api"}}]},
+            "scopeMetrics": [{
+                "metrics": [{
+                    "name": "cpu.usage",
+                    "unit": "%",
+                    "gauge": {"dataPoints": [
+                        {"timeUnixNano": "1000000", "asDouble": 80.5},
+                        {"timeUnixNano": "1000000"}
+                    ]}
+                }]
+            }]
+        }]
+    }"#;
+
+    fn router_for_test(path: &TempDir) -> Router {
+        let alarm_service = AlarmService::new(Config::new(path.path().to_owned()), vec![]).unwrap();
+        let (health_reporter, _) = tonic_health::server::health_reporter();
+        router(MetricsService::new(
+            health_reporter,
+            Arc::new(Mutex::new(alarm_service)),
+        ))
+    }
+
+    async fn post(
+        router: Router,
+        content_type: &str,
+        content_encoding: Option<&str>,
+        body: Vec<u8>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::post("/v1/metrics").header(header::CONTENT_TYPE, content_type);
+        if let Some(content_encoding) = content_encoding {
+            request = request.header(header::CONTENT_ENCODING, content_encoding);
+        }
+        let response = router
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn accepts_json_requests() {
+        let path = TempDir::new().unwrap();
+        let (status, body) = post(
+            router_for_test(&path),
+            "application/json; charset=utf-8",
+            None,
+            JSON_REQUEST.as_bytes().to_vec(),
+        )
+        .await;
+
+        assert_eq!(StatusCode::OK, status);
+        let response: ExportMetricsServiceResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, response.partial_success.unwrap().rejected_data_points);
+    }
+
+    #[tokio::test]
+    async fn accepts_gzip_protobuf_requests() {
+        let path = TempDir::new().unwrap();
+        let request: ExportMetricsServiceRequest = serde_json::from_str(JSON_REQUEST).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&request.encode_to_vec()).unwrap();
+
+        let (status, body) = post(
+            router_for_test(&path),
+            PROTOBUF_CONTENT_TYPE,
+            Some("gzip"),
+            encoder.finish().unwrap(),
+        )
+        .await;
+
+        assert_eq!(StatusCode::OK, status);
+        let response = ExportMetricsServiceResponse::decode(body.as_slice()).unwrap();
+        assert_eq!(1, response.partial_success.unwrap().rejected_data_points);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests_as_permanent_failures() {
+        let path = TempDir::new().unwrap();
+        let (status, body) = post(
+            router_for_test(&path),
+            PROTOBUF_CONTENT_TYPE,
+            None,
+            vec![0xff, 0xff, 0xff],
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        let rpc_status = RpcStatus::decode(body.as_slice()).unwrap();
+        assert_eq!(Code::InvalidArgument as i32, rpc_status.code);
+
+        let (status, _) = post(router_for_test(&path), "text/plain", None, vec![]).await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, status);
+
+        let (status, _) = post(
+            router_for_test(&path),
+            JSON_CONTENT_TYPE,
+            Some("gzip"),
+            b"not gzip".to_vec(),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+}
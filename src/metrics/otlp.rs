@@ -0,0 +1,437 @@
+use crate::metrics::server::proto::collector::metrics::v1::{
+    ExportMetricsPartialSuccess, ExportMetricsServiceRequest,
+};
+use crate::metrics::server::proto::common::v1::{any_value, AnyValue, KeyValue};
+use crate::metrics::server::proto::metrics::v1 as otlp;
+use crate::model::metrics;
+use std::collections::HashMap;
+
+/// Result of converting an OTLP export request into our own metrics model.
+/// Each OTLP data point becomes one `metrics::Metric`, data points that
+/// could not be converted are only counted so that we can report them back
+/// to the client as a partial success.
+#[derive(Debug, Default)]
+pub struct Conversion {
+    pub metrics: Vec<metrics::Metric>,
+    pub rejected_data_points: i64,
+    pub errors: Vec<String>,
+}
+
+impl Conversion {
+    fn reject(&mut self, count: usize, reason: String) {
+        if count == 0 {
+            return;
+        }
+        self.rejected_data_points += count as i64;
+        self.errors.push(reason);
+    }
+
+    /// returns the partial success as expected by OTLP, which must be unset
+    /// if the request was fully accepted.
+    pub fn partial_success(&self) -> Option<ExportMetricsPartialSuccess> {
+        if self.rejected_data_points == 0 {
+            return None;
+        }
+        Some(ExportMetricsPartialSuccess {
+            rejected_data_points: self.rejected_data_points,
+            error_message: self.errors.join("; "),
+        })
+    }
+}
+
+/// converts every ResourceMetrics/ScopeMetrics/Metric of the request into
+/// `metrics::Metric`. Attributes are flattened with the data point attributes
+/// taking precedence over the scope and resource ones.
+/// Times are converted from nanoseconds to milliseconds.
+pub fn convert_request(request: ExportMetricsServiceRequest) -> Conversion {
+    let mut conversion = Conversion::default();
+
+    for resource_metrics in request.resource_metrics {
+        let mut resource_attributes = HashMap::new();
+        if let Some(resource) = resource_metrics.resource {
+            extend_attributes(&mut resource_attributes, resource.attributes);
+        }
+
+        for scope_metrics in resource_metrics.scope_metrics {
+            let mut scope_attributes = resource_attributes.clone();
+            if let Some(scope) = scope_metrics.scope {
+                extend_attributes(&mut scope_attributes, scope.attributes);
+            }
+
+            for metric in scope_metrics.metrics {
+                convert_metric(&mut conversion, metric, &scope_attributes);
+            }
+        }
+    }
+
+    conversion
+}
+
+fn convert_metric(
+    conversion: &mut Conversion,
+    mut metric: otlp::Metric,
+    attributes: &HashMap<String, String>,
+) {
+    let data = match metric.data.take() {
+        Some(data) => data,
+        None => {
+            // a metric without data has no data points to be rejected
+            return;
+        }
+    };
+
+    match data {
+        otlp::metric::Data::Gauge(gauge) => {
+            for point in gauge.data_points {
+                let data_point = match number_data_point(conversion, &metric.name, &point) {
+                    Some(data_point) => data_point,
+                    None => continue,
+                };
+                conversion.metrics.push(build_metric(
+                    &metric,
+                    attributes,
+                    point.attributes,
+                    metrics::MetricData::Gauge(data_point),
+                ));
+            }
+        }
+        otlp::metric::Data::Sum(sum) => {
+            let temporality = aggregation_temporality(sum.aggregation_temporality);
+            for point in sum.data_points {
+                let data_point = match number_data_point(conversion, &metric.name, &point) {
+                    Some(data_point) => data_point,
+                    None => continue,
+                };
+                conversion.metrics.push(build_metric(
+                    &metric,
+                    attributes,
+                    point.attributes,
+                    metrics::MetricData::Sum(data_point, temporality.clone(), sum.is_monotonic),
+                ));
+            }
+        }
+        otlp::metric::Data::Histogram(histogram) => {
+            let temporality = aggregation_temporality(histogram.aggregation_temporality);
+            for point in histogram.data_points {
+                if has_no_recorded_value(point.flags) {
+                    continue;
+                }
+                if point.time_unix_nano == 0 {
+                    conversion.reject(1, format!("{}: data point without time", metric.name));
+                    continue;
+                }
+                let data_point = metrics::HistogramDataPoint {
+                    start_time: nanos_to_millis(point.start_time_unix_nano),
+                    time: nanos_to_millis(point.time_unix_nano),
+                    count: point.count,
+                    sum: point.sum.unwrap_or_default(),
+                    bucket_counts: point.bucket_counts.into_boxed_slice(),
+                    explicity_bouds: point.explicit_bounds.into_boxed_slice(),
+                };
+                conversion.metrics.push(build_metric(
+                    &metric,
+                    attributes,
+                    point.attributes,
+                    metrics::MetricData::Histogram(data_point, temporality.clone()),
+                ));
+            }
+        }
+        otlp::metric::Data::ExponentialHistogram(histogram) => conversion.reject(
+            histogram.data_points.len(),
+            format!("{}: exponential histograms are not supported", metric.name),
+        ),
+        otlp::metric::Data::Summary(summary) => conversion.reject(
+            summary.data_points.len(),
+            format!("{}: summaries are not supported", metric.name),
+        ),
+    }
+}
+
+fn number_data_point(
+    conversion: &mut Conversion,
+    name: &str,
+    point: &otlp::NumberDataPoint,
+) -> Option<metrics::DataPoint> {
+    if has_no_recorded_value(point.flags) {
+        return None;
+    }
+    if point.time_unix_nano == 0 {
+        conversion.reject(1, format!("{}: data point without time", name));
+        return None;
+    }
+    let value = match point.value {
+        Some(otlp::number_data_point::Value::AsDouble(value)) => value,
+        Some(otlp::number_data_point::Value::AsInt(value)) => value as f64,
+        None => {
+            conversion.reject(1, format!("{}: data point without value", name));
+            return None;
+        }
+    };
+
+    Some(metrics::DataPoint {
+        start_time: nanos_to_millis(point.start_time_unix_nano),
+        time: nanos_to_millis(point.time_unix_nano),
+        value,
+    })
+}
+
+fn build_metric(
+    metric: &otlp::Metric,
+    attributes: &HashMap<String, String>,
+    point_attributes: Vec<KeyValue>,
+    data: metrics::MetricData,
+) -> metrics::Metric {
+    let mut attributes = attributes.clone();
+    extend_attributes(&mut attributes, point_attributes);
+
+    let time = match &data {
+        metrics::MetricData::Gauge(point) | metrics::MetricData::Sum(point, _, _) => point.time,
+        metrics::MetricData::Histogram(point, _) => point.time,
+    };
+
+    metrics::Metric {
+        name: metric.name.clone(),
+        unit: metric.unit.clone(),
+        data,
+        time,
+        attributes,
+    }
+}
+
+/// points flagged with "no recorded value" are explicit markers of missing data
+/// and do not carry anything we can use, they are neither consumed nor rejected.
+fn has_no_recorded_value(flags: u32) -> bool {
+    flags & otlp::DataPointFlags::NoRecordedValueMask as u32 != 0
+}
+
+fn aggregation_temporality(value: i32) -> metrics::AggregationTemporality {
+    match otlp::AggregationTemporality::try_from(value) {
+        Ok(otlp::AggregationTemporality::Delta) => metrics::AggregationTemporality::Delta,
+        Ok(otlp::AggregationTemporality::Cumulative) => metrics::AggregationTemporality::Cumulative,
+        _ => metrics::AggregationTemporality::None,
+    }
+}
+
+fn nanos_to_millis(nanos: u64) -> u64 {
+    nanos / 1_000_000
+}
+
+fn extend_attributes(attributes: &mut HashMap<String, String>, key_values: Vec<KeyValue>) {
+    for key_value in key_values {
+        let value = key_value
+            .value
+            .map(|v| any_value_to_string(&v))
+            .unwrap_or_default();
+        attributes.insert(key_value.key, value);
+    }
+}
+
+/// our attributes are plain strings, so complex values are flattened into
+/// a readable representation.
+fn any_value_to_string(value: &AnyValue) -> String {
+    match &value.value {
+        Some(any_value::Value::StringValue(v)) => v.clone(),
+        Some(any_value::Value::BoolValue(v)) => v.to_string(),
+        Some(any_value::Value::IntValue(v)) => v.to_string(),
+        Some(any_value::Value::DoubleValue(v)) => v.to_string(),
+        Some(any_value::Value::BytesValue(v)) => v.iter().map(|b| format!("{:02x}", b)).collect(),
+        Some(any_value::Value::ArrayValue(array)) => format!(
+            "[{}]",
+            array
+                .values
+                .iter()
+                .map(any_value_to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        Some(any_value::Value::KvlistValue(list)) => format!(
+            "{{{}}}",
+            list.values
+                .iter()
+                .map(|kv| format!(
+                    "{}={}",
+                    kv.key,
+                    kv.value
+                        .as_ref()
+                        .map(any_value_to_string)
+                        .unwrap_or_default()
+                ))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::server::proto::common::v1::InstrumentationScope;
+    use crate::metrics::server::proto::resource::v1::Resource;
+
+    fn key_value(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn number_point(time_unix_nano: u64, value: Option<f64>) -> otlp::NumberDataPoint {
+        otlp::NumberDataPoint {
+            attributes: vec![key_value("host", "web-1")],
+            start_time_unix_nano: 0,
+            time_unix_nano,
+            value: value.map(otlp::number_data_point::Value::AsDouble),
+            exemplars: vec![],
+            flags: 0,
+        }
+    }
+
+    fn request(metrics: Vec<otlp::Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![otlp::ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![key_value("service", "api"), key_value("host", "unknown")],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![otlp::ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "test".to_string(),
+                        version: "1".to_string(),
+                        attributes: vec![key_value("scope", "tests")],
+                        dropped_attributes_count: 0,
+                    }),
+                    metrics,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn metric(name: &str, data: otlp::metric::Data) -> otlp::Metric {
+        otlp::Metric {
+            name: name.to_string(),
+            description: String::new(),
+            unit: "%".to_string(),
+            data: Some(data),
+            metadata: vec![],
+        }
+    }
+
+    #[test]
+    fn converts_gauges_and_merges_attributes() {
+        let conversion = convert_request(request(vec![metric(
+            "cpu.usage",
+            otlp::metric::Data::Gauge(otlp::Gauge {
+                data_points: vec![number_point(2_000_000, Some(80.0))],
+            }),
+        )]));
+
+        assert_eq!(0, conversion.rejected_data_points);
+        assert!(conversion.partial_success().is_none());
+        assert_eq!(1, conversion.metrics.len());
+
+        let metric = &conversion.metrics[0];
+        assert_eq!("cpu.usage", metric.name);
+        assert_eq!(2, metric.time);
+        assert_eq!("web-1", metric.attributes["host"]);
+        assert_eq!("api", metric.attributes["service"]);
+        assert_eq!("tests", metric.attributes["scope"]);
+        match &metric.data {
+            metrics::MetricData::Gauge(point) => assert_eq!(80.0, point.value),
+            _ => panic!("expected a gauge"),
+        }
+    }
+
+    #[test]
+    fn converts_sums_and_histograms() {
+        let conversion = convert_request(request(vec![
+            metric(
+                "requests",
+                otlp::metric::Data::Sum(otlp::Sum {
+                    data_points: vec![number_point(1_000_000, Some(3.0))],
+                    aggregation_temporality: otlp::AggregationTemporality::Cumulative as i32,
+                    is_monotonic: true,
+                }),
+            ),
+            metric(
+                "latency",
+                otlp::metric::Data::Histogram(otlp::Histogram {
+                    data_points: vec![otlp::HistogramDataPoint {
+                        attributes: vec![],
+                        start_time_unix_nano: 0,
+                        time_unix_nano: 1_000_000,
+                        count: 3,
+                        sum: Some(12.0),
+                        bucket_counts: vec![1, 2],
+                        explicit_bounds: vec![5.0],
+                        exemplars: vec![],
+                        flags: 0,
+                        min: None,
+                        max: None,
+                    }],
+                    aggregation_temporality: otlp::AggregationTemporality::Delta as i32,
+                }),
+            ),
+        ]));
+
+        assert_eq!(2, conversion.metrics.len());
+        assert!(matches!(
+            conversion.metrics[0].data,
+            metrics::MetricData::Sum(_, metrics::AggregationTemporality::Cumulative, true)
+        ));
+        match &conversion.metrics[1].data {
+            metrics::MetricData::Histogram(point, metrics::AggregationTemporality::Delta) => {
+                assert_eq!(3, point.count);
+                assert_eq!(12.0, point.sum);
+                assert_eq!(&[1, 2], &*point.bucket_counts);
+            }
+            _ => panic!("expected a delta histogram"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_and_unsupported_points() {
+        let conversion = convert_request(request(vec![
+            metric(
+                "cpu.usage",
+                otlp::metric::Data::Gauge(otlp::Gauge {
+                    data_points: vec![
+                        number_point(0, Some(1.0)),
+                        number_point(1_000_000, None),
+                        number_point(1_000_000, Some(1.0)),
+                    ],
+                }),
+            ),
+            metric(
+                "latency",
+                otlp::metric::Data::Summary(otlp::Summary {
+                    data_points: vec![otlp::SummaryDataPoint::default()],
+                }),
+            ),
+        ]));
+
+        assert_eq!(1, conversion.metrics.len());
+        let partial_success = conversion.partial_success().unwrap();
+        assert_eq!(3, partial_success.rejected_data_points);
+        assert!(partial_success.error_message.contains("not supported"));
+    }
+
+    #[test]
+    fn skips_points_without_recorded_value() {
+        let mut point = number_point(1_000_000, None);
+        point.flags = otlp::DataPointFlags::NoRecordedValueMask as u32;
+        let conversion = convert_request(request(vec![metric(
+            "cpu.usage",
+            otlp::metric::Data::Gauge(otlp::Gauge {
+                data_points: vec![point],
+            }),
+        )]));
+
+        assert!(conversion.metrics.is_empty());
+        assert_eq!(0, conversion.rejected_data_points);
+    }
+}
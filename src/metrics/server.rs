@@ -1,51 +1,137 @@
+use crate::alarm::service::{AlarmService, Error as AlarmServiceError};
+use crate::metrics::otlp;
 use crate::server;
-use proto::ingestion_server::{Ingestion, IngestionServer};
-use proto::{PutRequest, PutResponse};
+use proto::collector::metrics::v1::metrics_service_server::{
+    MetricsService as OtlpMetricsService, MetricsServiceServer,
+};
+use proto::collector::metrics::v1::{ExportMetricsServiceRequest, ExportMetricsServiceResponse};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
 use tonic::{Request, Response, Status};
 use tonic_health::server::HealthReporter;
-use tracing::instrument;
+use tracing::{event, instrument, Level};
 
+#[allow(clippy::all, dead_code)]
 pub mod proto {
-    tonic::include_proto!("metrics_service");
     pub mod common {
         pub mod v1 {
             tonic::include_proto!("opentelemetry.proto.common.v1");
+            include!(concat!(
+                env!("OUT_DIR"),
+                "/opentelemetry.proto.common.v1.serde.rs"
+            ));
         }
     }
     pub mod resource {
         pub mod v1 {
             tonic::include_proto!("opentelemetry.proto.resource.v1");
+            include!(concat!(
+                env!("OUT_DIR"),
+                "/opentelemetry.proto.resource.v1.serde.rs"
+            ));
         }
     }
     pub mod metrics {
         pub mod v1 {
             tonic::include_proto!("opentelemetry.proto.metrics.v1");
+            include!(concat!(
+                env!("OUT_DIR"),
+                "/opentelemetry.proto.metrics.v1.serde.rs"
+            ));
         }
     }
     pub mod collector {
         pub mod metrics {
             pub mod v1 {
                 tonic::include_proto!("opentelemetry.proto.collector.metrics.v1");
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.collector.metrics.v1.serde.rs"
+                ));
             }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Alarm service is unavailable")]
+    AlarmServiceUnavailable,
+    #[error("Could not persist metrics {0}")]
+    PersistError(#[from] AlarmServiceError),
+}
+
+/// MetricsService implements the OpenTelemetry (OTLP) metrics ingestion
+/// and forwards every data point to the alarms.
+#[derive(Clone)]
 pub struct MetricsService {
     health_reporter: HealthReporter,
+    alarm_service: Arc<Mutex<AlarmService>>,
+    /// see `AlarmService::in_flight`.
+    in_flight: Arc<RwLock<()>>,
+}
+
+impl fmt::Debug for MetricsService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsService").finish_non_exhaustive()
+    }
 }
 
 impl MetricsService {
-    pub fn new(health_reporter: HealthReporter) -> Self {
-        Self { health_reporter }
+    pub fn new(health_reporter: HealthReporter, alarm_service: Arc<Mutex<AlarmService>>) -> Self {
+        let in_flight = alarm_service.lock().unwrap().in_flight();
+        Self {
+            health_reporter,
+            alarm_service,
+            in_flight,
+        }
+    }
+
+    /// converts the OTLP request into our metrics and hands every one of them
+    /// to the alarms. Shared by the gRPC and the HTTP ingestion.
+    /// The response is only sent once the metrics are durable.
+    pub async fn ingest(
+        &self,
+        request: ExportMetricsServiceRequest,
+    ) -> Result<ExportMetricsServiceResponse, Error> {
+        let conversion = otlp::convert_request(request);
+
+        // the metrics must not be compacted away between `persist` and `apply`
+        let _in_flight = self.in_flight.read().await;
+        let persisted = {
+            let mut alarm_service = self
+                .alarm_service
+                .lock()
+                .map_err(|_| Error::AlarmServiceUnavailable)?;
+            alarm_service
+                .persist(&conversion.metrics)
+                .inspect_err(|e| event!(Level::ERROR, "error while persisting metrics {:0}", e))?
+        };
+        // other requests are ingested while the WAL writer persists ours
+        if let Some(append) = persisted {
+            if let Err(e) = append.await {
+                event!(Level::ERROR, "error while persisting metrics {:0}", e);
+                return Err(Error::PersistError(e.into()));
+            }
+            // only durable metrics are consumed, so that a request sent again
+            // after an error is not counted twice
+            self.alarm_service
+                .lock()
+                .map_err(|_| Error::AlarmServiceUnavailable)?
+                .apply(&conversion.metrics);
+        }
+
+        Ok(ExportMetricsServiceResponse {
+            partial_success: conversion.partial_success(),
+        })
     }
 
-    pub async fn ingestion_server(&mut self) -> IngestionServer<MetricsService> {
+    pub async fn ingestion_server(&mut self) -> MetricsServiceServer<MetricsService> {
         self.health_reporter
-            .set_serving::<IngestionServer<MetricsService>>()
+            .set_serving::<MetricsServiceServer<MetricsService>>()
             .await;
-        IngestionServer::new(self.clone())
+        MetricsServiceServer::new(self.clone())
     }
 }
 
@@ -54,7 +140,7 @@ impl server::Administrable for MetricsService {
     async fn shutdown(&mut self) -> Result<(), server::ShutdownError> {
         let mut health_reporter = self.health_reporter.clone();
         health_reporter
-            .set_not_serving::<IngestionServer<MetricsService>>()
+            .set_not_serving::<MetricsServiceServer<MetricsService>>()
             .await;
 
         Ok(())
@@ -65,11 +151,170 @@ impl server::Administrable for MetricsService {
     }
 }
 
-//TODO stop usign our mock ingestion grpc service and start using open telemetry
 #[tonic::async_trait]
-impl Ingestion for MetricsService {
-    #[instrument]
-    async fn put(&self, _req: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
-        Ok(Response::new(PutResponse {}))
+impl OtlpMetricsService for MetricsService {
+    #[instrument(skip(req))]
+    async fn export(
+        &self,
+        req: Request<ExportMetricsServiceRequest>,
+    ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+        match self.ingest(req.into_inner()).await {
+            Ok(response) => Ok(Response::new(response)),
+            Err(Error::AlarmServiceUnavailable) => {
+                Err(Status::internal("alarm service is unavailable"))
+            }
+            // OTLP clients retry on UNAVAILABLE
+            Err(Error::PersistError(_)) => {
+                Err(Status::unavailable("error while persisting metrics"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alarm::alarm::Alarm;
+    use crate::alarm::service::Config;
+    use crate::alarm::state::{AlarmState, AlarmStates, Status};
+    use crate::model::alarm::AlarmConfig;
+    use crate::model::{dsl, metrics};
+    use proto::common::v1::{any_value, AnyValue, KeyValue};
+    use proto::metrics::v1 as otlp_metrics;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use temp_dir::TempDir;
+
+    struct CountMetricsAlarm {
+        consumed: Arc<AtomicUsize>,
+    }
+
+    impl Alarm for CountMetricsAlarm {
+        fn consume(&self, _metric: &metrics::Metric) -> bool {
+            self.consumed.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        fn uses(&self, _metric: &metrics::Metric) -> bool {
+            true
+        }
+        fn tick(&self, _states: &AlarmStates) {}
+        fn identifier(&self) -> String {
+            "CountMetricsAlarm".to_string()
+        }
+        fn status(&self) -> Status {
+            Status {
+                state: AlarmState::InsufficientData,
+                since: chrono::DateTime::UNIX_EPOCH,
+            }
+        }
+        fn config(&self) -> AlarmConfig {
+            dsl::parse("count(cpu.usage) > 0").unwrap()
+        }
+        fn metrics(&self) -> Vec<metrics::Metric> {
+            vec![]
+        }
+    }
+
+    fn export_request(values: Vec<Option<f64>>) -> ExportMetricsServiceRequest {
+        let data_points = values
+            .into_iter()
+            .map(|value| otlp_metrics::NumberDataPoint {
+                attributes: vec![KeyValue {
+                    key: "host".to_string(),
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::StringValue("web-1".to_string())),
+                    }),
+                }],
+                start_time_unix_nano: 0,
+                time_unix_nano: 1_000_000,
+                value: value.map(otlp_metrics::number_data_point::Value::AsDouble),
+                exemplars: vec![],
+                flags: 0,
+            })
+            .collect();
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![otlp_metrics::ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![otlp_metrics::ScopeMetrics {
+                    scope: None,
+                    metrics: vec![otlp_metrics::Metric {
+                        name: "cpu.usage".to_string(),
+                        description: String::new(),
+                        unit: "%".to_string(),
+                        data: Some(otlp_metrics::metric::Data::Gauge(otlp_metrics::Gauge {
+                            data_points,
+                        })),
+                        metadata: vec![],
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn export_reports_rejected_data_points() {
+        let path = TempDir::new().unwrap();
+        let alarm_service = AlarmService::new(Config::new(path.path().to_owned()), vec![]).unwrap();
+        let (health_reporter, _) = tonic_health::server::health_reporter();
+        let service = MetricsService::new(health_reporter, Arc::new(Mutex::new(alarm_service)));
+
+        let response = service
+            .export(Request::new(export_request(vec![Some(1.0)])))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.partial_success.is_none());
+
+        let response = service
+            .export(Request::new(export_request(vec![Some(1.0), None])))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(1, response.partial_success.unwrap().rejected_data_points);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_consumed_once_persisted() {
+        let path = TempDir::new().unwrap();
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let alarm_service = AlarmService::new(
+            Config::new(path.path().to_owned()),
+            vec![Box::new(CountMetricsAlarm {
+                consumed: consumed.clone(),
+            })],
+        )
+        .unwrap();
+        let alarm_service = Arc::new(Mutex::new(alarm_service));
+        let (health_reporter, _) = tonic_health::server::health_reporter();
+        let service = MetricsService::new(health_reporter, alarm_service.clone());
+
+        // the request is written in one record, a failure persists none of it
+        alarm_service.lock().unwrap().fail_next_write();
+        let status = service
+            .export(Request::new(export_request(vec![Some(1.0), Some(2.0)])))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unavailable, status.code());
+        assert_eq!(0, consumed.load(Ordering::Relaxed));
+
+        // the client sends it again
+        service
+            .export(Request::new(export_request(vec![Some(1.0), Some(2.0)])))
+            .await
+            .unwrap();
+        assert_eq!(2, consumed.load(Ordering::Relaxed));
+        drop(service);
+        drop(alarm_service);
+
+        let recovered = Arc::new(AtomicUsize::new(0));
+        AlarmService::new(
+            Config::new(path.path().to_owned()),
+            vec![Box::new(CountMetricsAlarm {
+                consumed: recovered.clone(),
+            })],
+        )
+        .unwrap();
+        assert_eq!(2, recovered.load(Ordering::Relaxed));
     }
 }
@@ -1,11 +1,22 @@
 use crate::model::metrics::Metric;
+use regex::Regex;
+use std::collections::HashSet;
 // TODO: this is really messy right now,
 // we are at the moment forced to mirror
 // the shape of this structures inside alarm service
 // if we are coupled that tight we are doing something
 // very wrong.
 
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid pattern {0}")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("Invalid configuration {0}")]
+    InvalidConfig(String),
+}
+
 /// AlarmConfig as setup by the user.
+#[derive(Clone)]
 pub enum AlarmConfig {
     /// Combination allow users to write things like:
     /// cpu.usage > 80 AND memory.usage > 80
@@ -13,9 +24,29 @@ pub enum AlarmConfig {
     /// TagBased is the most basic type of alarm:
     /// metric_name=cpu.usage service=my_nice_service > 80
     TagBased(TagBasedAlarmConfig),
+    /// Composite uses the state of other alarms:
+    /// ALARM("db-latency") AND NOT ALARM("db-maintenance")
+    Composite(CompositeAlarmConfig),
+}
+
+impl AlarmConfig {
+    /// minutes of metrics needed to evaluate the alarm, 0 if it does not use metrics.
+    pub fn time_window(&self) -> i64 {
+        match self {
+            AlarmConfig::TagBased(config) => config.time_window,
+            AlarmConfig::Combination(config) => config
+                .alarm
+                .items()
+                .iter()
+                .map(|condition| condition.time_window)
+                .fold(config.time_window, i64::max),
+            AlarmConfig::Composite(_) => 0,
+        }
+    }
 }
 
 /// TagBasedAlarmConfig represents the configuration as setup by the user.
+#[derive(Clone)]
 pub struct TagBasedAlarmConfig {
     /// Matchers for the tags: metric_name=cpu.usage; env=prod
     pub matchers: Vec<Match>,
@@ -26,31 +57,136 @@ pub struct TagBasedAlarmConfig {
     pub value: f64,
     /// How we should compare the threshold to the data point.
     pub value_comp: ThresholdType,
-    /// size of the window in minutes.
+    /// size of the window in minutes, each minute is one data point.
     pub time_window: i64,
-    //TODO: Maybe in the future add a "number of data points before cleaning alarm"
+    /// How many data points (M) out of the window (N) must breach `value`
+    /// before alarming. None means all the data points in the window.
+    pub datapoints_to_alarm: Option<usize>,
+    /// How many of the most recent data points must be within the recovery
+    /// threshold before going back to OK. None means all the data points in the window.
+    pub datapoints_to_recover: Option<usize>,
+    /// Threshold used to recover (hysteresis), e.g. alarm above 80 but only
+    /// recover below 70. None means `value`.
+    pub recovery_value: Option<f64>,
+    /// How minutes without data (gaps or an empty window) are treated.
+    pub missing_data: MissingData,
+    /// Which value of the metric is compared, e.g. the mean of a histogram.
+    pub statistic: Statistic,
+    /// Attributes that split the alarm into independent groups (e.g. one per host),
+    /// each group has its own window and state. Empty means a single group.
+    pub group_by: Vec<String>,
+}
+
+impl TagBasedAlarmConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.time_window <= 0 {
+            return Err(Error::InvalidConfig(
+                "time_window must be positive".to_string(),
+            ));
+        }
+        if let Statistic::Quantile(q) = self.statistic {
+            if !(0.0..=1.0).contains(&q) {
+                return Err(Error::InvalidConfig(
+                    "quantile must be between 0 and 1".to_string(),
+                ));
+            }
+        }
+        for (name, datapoints) in [
+            ("datapoints_to_alarm", self.datapoints_to_alarm),
+            ("datapoints_to_recover", self.datapoints_to_recover),
+        ] {
+            if let Some(datapoints) = datapoints {
+                if datapoints == 0 || datapoints as i64 > self.time_window {
+                    return Err(Error::InvalidConfig(format!(
+                        "{} must be between 1 and the time_window",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 /// CombinationAlarmConfig represents the configuration as setup by the user.
+#[derive(Clone)]
 pub struct CombinationAlarmConfig {
     pub alarm: AlarmLogicalOperator,
     pub time_window: i64,
 }
 
+/// CompositeAlarmConfig represents the configuration as setup by the user.
+#[derive(Clone)]
+pub struct CompositeAlarmConfig {
+    /// expression over the ids of other alarms, each one is true while alarming.
+    pub alarm: LogicalOperator<String>,
+}
+
 /// Represents our alarm logical operators (so that we can aggregate alarms)
 pub type AlarmLogicalOperator = LogicalOperator<TagBasedAlarmConfig>;
 
 /// Generic LogicalOperator
+#[derive(Clone)]
 pub enum LogicalOperator<I> {
     /// The item itself
     Identity(Box<I>),
     /// And logical operator
-    And(Box<AlarmLogicalOperator>, Box<AlarmLogicalOperator>),
+    And(Box<LogicalOperator<I>>, Box<LogicalOperator<I>>),
     /// Or logical Operator
-    Or(Box<AlarmLogicalOperator>, Box<AlarmLogicalOperator>),
+    Or(Box<LogicalOperator<I>>, Box<LogicalOperator<I>>),
     /// Not logical Operator
-    Not(Box<AlarmLogicalOperator>),
+    Not(Box<LogicalOperator<I>>),
+}
+
+impl<I> LogicalOperator<I> {
+    /// builds the same expression with every item converted by `f`.
+    pub fn map<T>(self, f: &mut impl FnMut(I) -> T) -> LogicalOperator<T> {
+        match self {
+            LogicalOperator::Identity(item) => LogicalOperator::Identity(Box::new(f(*item))),
+            LogicalOperator::And(left, right) => {
+                LogicalOperator::And(Box::new(left.map(f)), Box::new(right.map(f)))
+            }
+            LogicalOperator::Or(left, right) => {
+                LogicalOperator::Or(Box::new(left.map(f)), Box::new(right.map(f)))
+            }
+            LogicalOperator::Not(item) => LogicalOperator::Not(Box::new(item.map(f))),
+        }
+    }
+
+    /// all the items of the expression, from left to right.
+    pub fn items(&self) -> Vec<&I> {
+        match self {
+            LogicalOperator::Identity(item) => vec![item],
+            LogicalOperator::And(left, right) | LogicalOperator::Or(left, right) => {
+                let mut items = left.items();
+                items.extend(right.items());
+                items
+            }
+            LogicalOperator::Not(item) => item.items(),
+        }
+    }
+
+    /// evaluates the expression using three-valued logic, None means unknown
+    /// (e.g. an alarm without data). `false AND unknown` is false and
+    /// `true OR unknown` is true, anything else with unknown is unknown.
+    pub fn evaluate(&self, f: &impl Fn(&I) -> Option<bool>) -> Option<bool> {
+        match self {
+            LogicalOperator::Identity(item) => f(item),
+            LogicalOperator::And(left, right) => match (left.evaluate(f), right.evaluate(f)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            LogicalOperator::Or(left, right) => match (left.evaluate(f), right.evaluate(f)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            LogicalOperator::Not(item) => item.evaluate(f).map(|v| !v),
+        }
+    }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ThresholdType {
     Eq,
     NotEq,
@@ -58,34 +194,502 @@ pub enum ThresholdType {
     GreaterThan,
 }
 
+impl ThresholdType {
+    /// checks if the value breaches the threshold.
+    pub fn breaches(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            ThresholdType::Eq => value == threshold,
+            ThresholdType::NotEq => value != threshold,
+            ThresholdType::LessThan => value < threshold,
+            ThresholdType::GreaterThan => value > threshold,
+        }
+    }
+}
+
+/// Match a single attribute of the metric, the name of the metric
+/// is available as the `metric_name` attribute.
+#[derive(Clone)]
 pub struct Match {
-    pub attribute: String,
-    pub match_type: MatchType,
-    pub value: String,
+    attribute: String,
+    match_type: MatchType,
+    value: String,
+    /// values of `In` and `NotIn`, empty for the other match types.
+    values: Vec<String>,
+    /// value compiled once, so that matching a metric stays cheap.
+    pattern: Pattern,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum MatchType {
     Eq,
+    /// also matches metrics without the attribute.
     NotEq,
+    /// `=~`, the value is a regular expression that must match the whole attribute.
+    Regex,
+    /// `!~`, also matches metrics without the attribute.
+    NotRegex,
+    /// the value is a glob, `*` matches any sequence of characters and `?` a
+    /// single character (e.g. `host=web-*`).
+    Glob,
+    /// the attribute is one of the values: `in (a, b, c)`.
+    In,
+    /// the attribute is none of the values: `not in (a, b, c)`,
+    /// also matches metrics without the attribute.
+    NotIn,
+    /// the attribute is present, the value is ignored.
+    Exists,
+    /// the attribute is absent, the value is ignored.
+    Absent,
+}
+
+#[derive(Clone)]
+enum Pattern {
+    Value,
+    Regex(Regex),
+    Set(HashSet<String>),
 }
 
+/// attribute used to match against the metric name.
+pub const METRIC_NAME_ATTRIBUTE: &str = "metric_name";
+
+impl Match {
+    /// `In` and `NotIn` match the value alone, see `Match::list`.
+    pub fn new(attribute: String, match_type: MatchType, value: String) -> Result<Self, Error> {
+        let pattern = match match_type {
+            MatchType::Eq | MatchType::NotEq | MatchType::Exists | MatchType::Absent => {
+                Pattern::Value
+            }
+            MatchType::Regex | MatchType::NotRegex => {
+                Pattern::Regex(Regex::new(&format!("^(?:{})$", value))?)
+            }
+            MatchType::Glob => Pattern::Regex(Regex::new(&glob_to_regex(&value))?),
+            MatchType::In | MatchType::NotIn => {
+                return Self::list(attribute, match_type, vec![value])
+            }
+        };
+
+        Ok(Self {
+            attribute,
+            match_type,
+            value,
+            values: vec![],
+            pattern,
+        })
+    }
+
+    /// `In` or `NotIn` the values, the values are kept as given.
+    pub fn list(
+        attribute: String,
+        match_type: MatchType,
+        values: Vec<String>,
+    ) -> Result<Self, Error> {
+        if !matches!(match_type, MatchType::In | MatchType::NotIn) {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} does not match a list of values",
+                match_type
+            )));
+        }
+        Ok(Self {
+            attribute,
+            match_type,
+            value: String::new(),
+            pattern: Pattern::Set(values.iter().cloned().collect()),
+            values,
+        })
+    }
+
+    pub fn attribute(&self) -> &str {
+        &self.attribute
+    }
+
+    pub fn match_type(&self) -> MatchType {
+        self.match_type
+    }
+
+    /// empty for `In` and `NotIn`, see `values`.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn values(&self) -> &[String] {
+        &self.values
+    }
+
+    fn pattern_matches(&self, value: &str) -> bool {
+        match &self.pattern {
+            Pattern::Value => value == self.value,
+            Pattern::Regex(regex) => regex.is_match(value),
+            Pattern::Set(set) => set.contains(value),
+        }
+    }
+
+    fn attribute_value<'a>(&self, metric: &'a Metric) -> Option<&'a str> {
+        attribute_value(metric, &self.attribute)
+    }
+}
+
+/// value of an attribute of the metric, `METRIC_NAME_ATTRIBUTE` is the name of the metric.
+pub fn attribute_value<'a>(metric: &'a Metric, attribute: &str) -> Option<&'a str> {
+    if attribute == METRIC_NAME_ATTRIBUTE {
+        Some(&metric.name)
+    } else {
+        metric.attributes.get(attribute).map(|v| v.as_str())
+    }
+}
+
+/// Treatment of missing data points, both for gaps inside the window
+/// and for a window without any data.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum MissingData {
+    /// missing data points are breaching the threshold.
+    Breaching,
+    /// missing data points are within the threshold.
+    NotBreaching,
+    /// gaps are skipped and an empty window keeps the last state.
+    Ignore,
+    /// gaps are skipped and an empty window is INSUFFICIENT_DATA.
+    #[default]
+    Missing,
+}
+
+/// Value extracted from each metric before aggregating it.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Statistic {
+    /// value of a gauge or a sum, monotonic sums are converted to a rate per second.
+    #[default]
+    Value,
+    /// number of values recorded by a histogram.
+    Count,
+    /// sum of the values recorded by a histogram.
+    Sum,
+    /// mean of the values recorded by a histogram.
+    Mean,
+    /// quantile (between 0 and 1) estimated from the buckets of a histogram.
+    Quantile(f64),
+}
+
+/// How the values received in the same minute are aggregated into one data point.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Aggregation {
     Avg,
     Max,
     Min,
+    Sum,
+    Count,
+    /// most recent value of the minute.
+    Last,
+    /// population standard deviation.
+    StdDev,
+    /// percentiles, estimated with a sketch.
+    P50,
+    P90,
+    P99,
+    /// per second increase of a counter, from its first and last values of the minute.
+    Rate,
 }
 
 pub trait Matcher {
     fn metric_matches(&self, metric: &Metric) -> bool;
 }
 
-impl Matcher for AlarmLogicalOperator {
+impl Matcher for Match {
     fn metric_matches(&self, metric: &Metric) -> bool {
-        todo!()
+        let value = self.attribute_value(metric);
+        match self.match_type {
+            MatchType::Eq | MatchType::Regex | MatchType::Glob | MatchType::In => {
+                value.is_some_and(|v| self.pattern_matches(v))
+            }
+            MatchType::NotEq | MatchType::NotRegex | MatchType::NotIn => {
+                !value.is_some_and(|v| self.pattern_matches(v))
+            }
+            MatchType::Exists => value.is_some(),
+            MatchType::Absent => value.is_none(),
+        }
+    }
+}
+
+impl<I: Matcher> Matcher for LogicalOperator<I> {
+    fn metric_matches(&self, metric: &Metric) -> bool {
+        match self {
+            LogicalOperator::Identity(item) => item.metric_matches(metric),
+            LogicalOperator::And(left, right) => {
+                left.metric_matches(metric) && right.metric_matches(metric)
+            }
+            LogicalOperator::Or(left, right) => {
+                left.metric_matches(metric) || right.metric_matches(metric)
+            }
+            LogicalOperator::Not(item) => !item.metric_matches(metric),
+        }
+    }
+}
+
+/// translates a glob into an anchored regular expression.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::with_capacity(glob.len() + 8);
+    regex.push('^');
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
     }
+    regex.push('$');
+    regex
 }
+
+/// all the matchers must match the metric.
 impl Matcher for TagBasedAlarmConfig {
     fn metric_matches(&self, metric: &Metric) -> bool {
-        todo!()
+        self.matchers.iter().all(|m| m.metric_matches(metric))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::metrics::{DataPoint, MetricData};
+    use std::collections::HashMap;
+
+    fn metric(name: &str, attributes: &[(&str, &str)]) -> Metric {
+        Metric {
+            name: name.to_string(),
+            unit: "%".to_string(),
+            data: MetricData::Gauge(DataPoint {
+                start_time: 0,
+                time: 0,
+                value: 1.0,
+            }),
+            time: 0,
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    fn matcher(attribute: &str, match_type: MatchType, value: &str) -> Match {
+        Match::new(attribute.to_string(), match_type, value.to_string()).unwrap()
+    }
+
+    fn list(attribute: &str, match_type: MatchType, values: &[&str]) -> Match {
+        Match::list(
+            attribute.to_string(),
+            match_type,
+            values.iter().map(|v| v.to_string()).collect(),
+        )
+        .unwrap()
+    }
+
+    fn config(matchers: Vec<Match>) -> TagBasedAlarmConfig {
+        TagBasedAlarmConfig {
+            matchers,
+            agg: Aggregation::Avg,
+            value: 80.0,
+            value_comp: ThresholdType::GreaterThan,
+            time_window: 5,
+            datapoints_to_alarm: None,
+            datapoints_to_recover: None,
+            recovery_value: None,
+            missing_data: MissingData::Missing,
+            statistic: Statistic::Value,
+            group_by: vec![],
+        }
+    }
+
+    fn leaf(name: &str) -> Box<AlarmLogicalOperator> {
+        Box::new(LogicalOperator::Identity(Box::new(config(vec![matcher(
+            METRIC_NAME_ATTRIBUTE,
+            MatchType::Eq,
+            name,
+        )]))))
+    }
+
+    #[test]
+    fn eq_matches_metric_name_and_attributes() {
+        let cpu = metric("cpu.usage", &[("service", "api")]);
+
+        assert!(matcher(METRIC_NAME_ATTRIBUTE, MatchType::Eq, "cpu.usage").metric_matches(&cpu));
+        assert!(!matcher(METRIC_NAME_ATTRIBUTE, MatchType::Eq, "mem.usage").metric_matches(&cpu));
+        assert!(matcher("service", MatchType::Eq, "api").metric_matches(&cpu));
+        assert!(!matcher("service", MatchType::Eq, "web").metric_matches(&cpu));
+        assert!(!matcher("host", MatchType::Eq, "web-1").metric_matches(&cpu));
+    }
+
+    #[test]
+    fn not_eq_matches_different_or_missing_attributes() {
+        let cpu = metric("cpu.usage", &[("service", "api")]);
+
+        assert!(
+            !matcher(METRIC_NAME_ATTRIBUTE, MatchType::NotEq, "cpu.usage").metric_matches(&cpu)
+        );
+        assert!(matcher(METRIC_NAME_ATTRIBUTE, MatchType::NotEq, "mem.usage").metric_matches(&cpu));
+        assert!(!matcher("service", MatchType::NotEq, "api").metric_matches(&cpu));
+        assert!(matcher("service", MatchType::NotEq, "web").metric_matches(&cpu));
+        assert!(matcher("host", MatchType::NotEq, "web-1").metric_matches(&cpu));
+    }
+
+    #[test]
+    fn regex_matches_the_whole_value() {
+        let web = metric("http.requests", &[("host", "web-12")]);
+
+        assert!(matcher("host", MatchType::Regex, "web-[0-9]+").metric_matches(&web));
+        assert!(!matcher("host", MatchType::Regex, "web").metric_matches(&web));
+        assert!(!matcher("missing", MatchType::Regex, ".*").metric_matches(&web));
+        assert!(matcher(METRIC_NAME_ATTRIBUTE, MatchType::Regex, "http\\..*").metric_matches(&web));
+
+        assert!(!matcher("host", MatchType::NotRegex, "web-[0-9]+").metric_matches(&web));
+        assert!(matcher("host", MatchType::NotRegex, "db-.*").metric_matches(&web));
+        assert!(matcher("missing", MatchType::NotRegex, ".*").metric_matches(&web));
+    }
+
+    #[test]
+    fn glob_matches() {
+        let web = metric("http.requests", &[("host", "web-12.prod")]);
+
+        assert!(matcher("host", MatchType::Glob, "web-*").metric_matches(&web));
+        assert!(matcher("host", MatchType::Glob, "web-??.prod").metric_matches(&web));
+        assert!(!matcher("host", MatchType::Glob, "web-?.prod").metric_matches(&web));
+        // dots are not regex wildcards
+        assert!(!matcher("host", MatchType::Glob, "web-12?prod*x").metric_matches(&web));
+        assert!(!matcher("host", MatchType::Glob, "db-*").metric_matches(&web));
+        assert!(!matcher("missing", MatchType::Glob, "*").metric_matches(&web));
+    }
+
+    #[test]
+    fn set_membership() {
+        let web = metric("http.requests", &[("env", "prod")]);
+
+        assert!(list("env", MatchType::In, &["dev", "staging", "prod"]).metric_matches(&web));
+        assert!(!list("env", MatchType::In, &["dev", "staging"]).metric_matches(&web));
+        assert!(!list("missing", MatchType::In, &["prod"]).metric_matches(&web));
+        assert!(matcher("env", MatchType::In, "prod").metric_matches(&web));
+        // commas are part of the value
+        assert!(!list("env", MatchType::In, &["dev,prod"]).metric_matches(&web));
+
+        assert!(!list("env", MatchType::NotIn, &["dev", "prod"]).metric_matches(&web));
+        assert!(list("env", MatchType::NotIn, &["dev", "staging"]).metric_matches(&web));
+        assert!(list("missing", MatchType::NotIn, &["prod"]).metric_matches(&web));
+    }
+
+    #[test]
+    fn existence() {
+        let web = metric("http.requests", &[("env", "prod")]);
+
+        assert!(matcher("env", MatchType::Exists, "").metric_matches(&web));
+        assert!(!matcher("host", MatchType::Exists, "").metric_matches(&web));
+        assert!(matcher(METRIC_NAME_ATTRIBUTE, MatchType::Exists, "").metric_matches(&web));
+        assert!(!matcher("env", MatchType::Absent, "").metric_matches(&web));
+        assert!(matcher("host", MatchType::Absent, "").metric_matches(&web));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(Match::new("host".to_string(), MatchType::Regex, "web-(".to_string()).is_err());
+        assert!(Match::list("host".to_string(), MatchType::Eq, vec![]).is_err());
+    }
+
+    #[test]
+    fn tag_based_config_requires_all_matchers() {
+        let cpu = metric("cpu.usage", &[("service", "api"), ("env", "prod")]);
+
+        assert!(config(vec![]).metric_matches(&cpu));
+        assert!(config(vec![
+            matcher(METRIC_NAME_ATTRIBUTE, MatchType::Eq, "cpu.usage"),
+            matcher("service", MatchType::Eq, "api"),
+            matcher("env", MatchType::NotEq, "dev"),
+        ])
+        .metric_matches(&cpu));
+        assert!(!config(vec![
+            matcher(METRIC_NAME_ATTRIBUTE, MatchType::Eq, "cpu.usage"),
+            matcher("service", MatchType::Eq, "web"),
+        ])
+        .metric_matches(&cpu));
+    }
+
+    #[test]
+    fn logical_operators() {
+        let cpu = metric("cpu.usage", &[]);
+        let identity = |name| *leaf(name);
+
+        assert!(identity("cpu.usage").metric_matches(&cpu));
+        assert!(!identity("mem.usage").metric_matches(&cpu));
+
+        assert!(!LogicalOperator::Not(leaf("cpu.usage")).metric_matches(&cpu));
+        assert!(LogicalOperator::Not(leaf("mem.usage")).metric_matches(&cpu));
+
+        for (left, right, and, or) in [
+            ("cpu.usage", "cpu.usage", true, true),
+            ("cpu.usage", "mem.usage", false, true),
+            ("mem.usage", "cpu.usage", false, true),
+            ("mem.usage", "disk.usage", false, false),
+        ] {
+            assert_eq!(
+                and,
+                LogicalOperator::And(leaf(left), leaf(right)).metric_matches(&cpu)
+            );
+            assert_eq!(
+                or,
+                LogicalOperator::Or(leaf(left), leaf(right)).metric_matches(&cpu)
+            );
+        }
+    }
+
+    #[test]
+    fn nested_logical_operators() {
+        let cpu = metric("cpu.usage", &[]);
+
+        // NOT (mem.usage OR disk.usage)
+        let operator = LogicalOperator::Not(Box::new(LogicalOperator::Or(
+            leaf("mem.usage"),
+            leaf("disk.usage"),
+        )));
+        assert!(operator.metric_matches(&cpu));
+
+        // cpu.usage AND NOT cpu.usage
+        let operator = LogicalOperator::And(
+            leaf("cpu.usage"),
+            Box::new(LogicalOperator::Not(leaf("cpu.usage"))),
+        );
+        assert!(!operator.metric_matches(&cpu));
+    }
+
+    #[test]
+    fn validate_datapoints() {
+        let mut config = config(vec![]);
+        assert!(config.validate().is_ok());
+
+        config.datapoints_to_alarm = Some(3);
+        config.datapoints_to_recover = Some(5);
+        assert!(config.validate().is_ok());
+
+        config.datapoints_to_alarm = Some(0);
+        assert!(config.validate().is_err());
+
+        config.datapoints_to_alarm = Some(3);
+        config.datapoints_to_recover = Some(6);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn three_valued_logic() {
+        use LogicalOperator::*;
+        let value = |v: Option<bool>| Box::new(Identity(Box::new(v)));
+        let evaluate = |op: LogicalOperator<Option<bool>>| op.evaluate(&|v| *v);
+
+        for (left, right, and, or) in [
+            (Some(true), Some(true), Some(true), Some(true)),
+            (Some(true), Some(false), Some(false), Some(true)),
+            (Some(true), None, None, Some(true)),
+            (Some(false), None, Some(false), None),
+            (None, None, None, None),
+        ] {
+            assert_eq!(and, evaluate(And(value(left), value(right))));
+            assert_eq!(and, evaluate(And(value(right), value(left))));
+            assert_eq!(or, evaluate(Or(value(left), value(right))));
+            assert_eq!(or, evaluate(Or(value(right), value(left))));
+        }
+        assert_eq!(None, evaluate(Not(value(None))));
+        assert_eq!(Some(false), evaluate(Not(value(Some(true)))));
     }
 }
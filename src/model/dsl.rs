@@ -0,0 +1,922 @@
+//! Textual representation of the alarms, e.g.
+//!
+//! ```text
+//! max(cpu.usage, service=my_nice_service) > 80 over 5m AND avg(memory.usage) > 80 over 10m
+//! ALARM("db-latency") AND NOT ALARM("db-maintenance")
+//! ```
+//!
+//! Grammar (keywords are case insensitive):
+//!
+//! ```text
+//! expression := or
+//! or         := and ("OR" and)*
+//! and        := not ("AND" not)*
+//! not        := "NOT" not | "(" expression ")" | reference | condition
+//! reference  := "ALARM" "(" value ")"
+//! condition  := [aggregation "("] matcher ([","] matcher)* [")"] comparison number clause*
+//! matcher    := value                                 (metric_name=value)
+//!             | attribute ("=" | "!=" | "=~" | "!~" | "glob") value
+//!             | attribute ["not"] "in" "(" value ("," value)* ")"
+//!             | attribute ("exists" | "absent")
+//! comparison := ">" | "<" | "==" | "!="
+//! clause     := "over" duration | "statistic" statistic | "datapoints" number
+//!             | "recover" number | "recover_at" number | "missing" missing_data
+//!             | "by" "(" attribute ("," attribute)* ")"
+//! ```
+//!
+//! Values are either words (letters, digits and `_.-:/`) or double quoted strings.
+//! Without the aggregation parentheses `!=` is always a matcher, use
+//! `avg(cpu.usage) != 80` to compare. The aggregation defaults to `avg` and
+//! the window to 5 minutes.
+use crate::model::alarm::{
+    self, Aggregation, AlarmConfig, CombinationAlarmConfig, CompositeAlarmConfig, LogicalOperator,
+    Match, MatchType, MissingData, Statistic, TagBasedAlarmConfig, ThresholdType,
+    METRIC_NAME_ATTRIBUTE,
+};
+use std::fmt;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("column {column}: {message}")]
+    Syntax { column: usize, message: String },
+    #[error("column {column}: {source}")]
+    InvalidAlarm { column: usize, source: alarm::Error },
+}
+
+impl Error {
+    /// column (starting at 1) where the error was found.
+    pub fn column(&self) -> usize {
+        match self {
+            Error::Syntax { column, .. } | Error::InvalidAlarm { column, .. } => *column,
+        }
+    }
+}
+
+const DEFAULT_TIME_WINDOW: i64 = 5;
+
+/// parses an alarm written in the DSL.
+pub fn parse(input: &str) -> Result<AlarmConfig, Error> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: input.chars().count() + 1,
+    };
+    let expression = parser.expression()?;
+    if let Some(token) = parser.peek() {
+        return Err(parser.unexpected(token, "AND, OR or the end of the alarm"));
+    }
+
+    let leaves = expression.items();
+    let first_is_reference = matches!(leaves[0], Leaf::Reference(..));
+    if let Some(leaf) = leaves
+        .iter()
+        .find(|leaf| matches!(leaf, Leaf::Reference(..)) != first_is_reference)
+    {
+        return Err(Error::Syntax {
+            column: leaf.column(),
+            message: "alarm references and conditions cannot be combined".to_string(),
+        });
+    }
+
+    if first_is_reference {
+        return Ok(AlarmConfig::Composite(CompositeAlarmConfig {
+            alarm: expression.map(&mut |leaf| match leaf {
+                Leaf::Reference(id, _) => id,
+                Leaf::Condition(..) => unreachable!(),
+            }),
+        }));
+    }
+
+    let alarm = expression.map(&mut |leaf| match leaf {
+        Leaf::Condition(config, _) => config,
+        Leaf::Reference(..) => unreachable!(),
+    });
+    match alarm {
+        LogicalOperator::Identity(config) => Ok(AlarmConfig::TagBased(*config)),
+        alarm => {
+            let time_window = alarm
+                .items()
+                .iter()
+                .map(|config| config.time_window)
+                .max()
+                .unwrap_or(DEFAULT_TIME_WINDOW);
+            Ok(AlarmConfig::Combination(CombinationAlarmConfig {
+                alarm,
+                time_window,
+            }))
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    LeftParen,
+    RightParen,
+    Comma,
+    Operator(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(value) => write!(f, "\"{}\"", value),
+            Token::LeftParen => f.write_str("'('"),
+            Token::RightParen => f.write_str("')'"),
+            Token::Comma => f.write_str("','"),
+            Token::Operator(operator) => write!(f, "'{}'", operator),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | ':' | '/')
+}
+
+/// splits the input into (column, token).
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, Error> {
+    const OPERATORS: [&str; 7] = ["==", "!=", "=~", "!~", "=", ">", "<"];
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = match c {
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            ',' => Token::Comma,
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('"') => break,
+                        Some('\\') => {
+                            i += 1;
+                            match chars.get(i) {
+                                Some(c @ ('"' | '\\')) => value.push(*c),
+                                _ => {
+                                    return Err(Error::Syntax {
+                                        column: i + 1,
+                                        message: "invalid escape, only \\\" and \\\\ are allowed"
+                                            .to_string(),
+                                    })
+                                }
+                            }
+                        }
+                        Some(c) => value.push(*c),
+                        None => {
+                            return Err(Error::Syntax {
+                                column,
+                                message: "unterminated string".to_string(),
+                            })
+                        }
+                    }
+                    i += 1;
+                }
+                Token::Quoted(value)
+            }
+            c if is_word_char(c) => {
+                let start = i;
+                while i + 1 < chars.len() && is_word_char(chars[i + 1]) {
+                    i += 1;
+                }
+                Token::Word(chars[start..=i].iter().collect())
+            }
+            _ => {
+                let operator = OPERATORS.iter().find(|operator| {
+                    operator
+                        .chars()
+                        .enumerate()
+                        .all(|(j, c)| chars.get(i + j) == Some(&c))
+                });
+                match operator {
+                    Some(operator) => {
+                        i += operator.len() - 1;
+                        Token::Operator(operator)
+                    }
+                    None => {
+                        return Err(Error::Syntax {
+                            column,
+                            message: format!("unexpected character '{}'", c),
+                        })
+                    }
+                }
+            }
+        };
+        tokens.push((column, token));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+/// leaf of the parsed expression with the column where it starts.
+enum Leaf {
+    Condition(TagBasedAlarmConfig, usize),
+    Reference(String, usize),
+}
+
+impl Leaf {
+    fn column(&self) -> usize {
+        match self {
+            Leaf::Condition(_, column) | Leaf::Reference(_, column) => *column,
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    /// column after the last character, used for errors at the end of the input.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(usize, Token)> {
+        self.tokens.get(self.position)
+    }
+
+    fn column(&self) -> usize {
+        self.peek().map(|(column, _)| *column).unwrap_or(self.end)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(usize, Token), Error> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => Err(Error::Syntax {
+                column: self.end,
+                message: format!("expected {}, found the end of the alarm", expected),
+            }),
+        }
+    }
+
+    fn unexpected(&self, (column, token): &(usize, Token), expected: &str) -> Error {
+        Error::Syntax {
+            column: *column,
+            message: format!("expected {}, found {}", expected, token),
+        }
+    }
+
+    /// checks if the next token is the (case insensitive) keyword.
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some((_, Token::Word(word))) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn accept(&mut self, token: &Token) -> bool {
+        let found = matches!(self.peek(), Some((_, next)) if next == token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), Error> {
+        let next = self.next(&token.to_string())?;
+        if next.1 == token {
+            Ok(())
+        } else {
+            Err(self.unexpected(&next, &token.to_string()))
+        }
+    }
+
+    fn expression(&mut self) -> Result<LogicalOperator<Leaf>, Error> {
+        let mut left = self.and()?;
+        while self.accept_keyword("OR") {
+            let right = self.and()?;
+            left = LogicalOperator::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<LogicalOperator<Leaf>, Error> {
+        let mut left = self.not()?;
+        while self.accept_keyword("AND") {
+            let right = self.not()?;
+            left = LogicalOperator::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<LogicalOperator<Leaf>, Error> {
+        if self.accept_keyword("NOT") {
+            return Ok(LogicalOperator::Not(Box::new(self.not()?)));
+        }
+        if self.accept(&Token::LeftParen) {
+            let expression = self.expression()?;
+            self.expect(Token::RightParen)?;
+            return Ok(expression);
+        }
+
+        let column = self.column();
+        if self.peek_keyword("ALARM")
+            && matches!(
+                self.tokens.get(self.position + 1),
+                Some((_, Token::LeftParen))
+            )
+        {
+            self.position += 2;
+            let id = self.value("an alarm id")?;
+            self.expect(Token::RightParen)?;
+            return Ok(LogicalOperator::Identity(Box::new(Leaf::Reference(
+                id, column,
+            ))));
+        }
+
+        let condition = self.condition()?;
+        condition
+            .validate()
+            .map_err(|source| Error::InvalidAlarm { column, source })?;
+        Ok(LogicalOperator::Identity(Box::new(Leaf::Condition(
+            condition, column,
+        ))))
+    }
+
+    fn value(&mut self, expected: &str) -> Result<String, Error> {
+        match self.next(expected)? {
+            (_, Token::Word(value)) | (_, Token::Quoted(value)) => Ok(value),
+            token => Err(self.unexpected(&token, expected)),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, expected: &str) -> Result<T, Error> {
+        let token = self.next(expected)?;
+        match &token.1 {
+            Token::Word(word) => word.parse().map_err(|_| self.unexpected(&token, expected)),
+            _ => Err(self.unexpected(&token, expected)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<TagBasedAlarmConfig, Error> {
+        let aggregation = match (self.peek(), self.tokens.get(self.position + 1)) {
+            (Some((_, Token::Word(word))), Some((_, Token::LeftParen))) => {
+                let aggregation = AGGREGATIONS
+                    .iter()
+                    .find(|(name, _)| word.eq_ignore_ascii_case(name))
+                    .map(|(_, aggregation)| *aggregation);
+                if aggregation.is_none() {
+                    return Err(Error::Syntax {
+                        column: self.column(),
+                        message: format!(
+                            "unknown aggregation '{}', expected one of {}",
+                            word,
+                            names(&AGGREGATIONS)
+                        ),
+                    });
+                }
+                self.position += 2;
+                aggregation
+            }
+            _ => None,
+        };
+
+        let mut matchers = vec![self.matcher()?];
+        loop {
+            match self.peek() {
+                Some((_, Token::Comma)) => {
+                    self.position += 1;
+                    matchers.push(self.matcher()?);
+                }
+                Some((_, Token::Word(_))) | Some((_, Token::Quoted(_))) => {
+                    matchers.push(self.matcher()?)
+                }
+                _ => break,
+            }
+        }
+        if aggregation.is_some() {
+            self.expect(Token::RightParen)?;
+        }
+
+        let comparison = self.next("a comparison (>, <, ==, !=)")?;
+        let value_comp = match comparison.1 {
+            Token::Operator(">") => ThresholdType::GreaterThan,
+            Token::Operator("<") => ThresholdType::LessThan,
+            Token::Operator("==") => ThresholdType::Eq,
+            Token::Operator("!=") => ThresholdType::NotEq,
+            _ => return Err(self.unexpected(&comparison, "a comparison (>, <, ==, !=)")),
+        };
+        let value = self.number("a number")?;
+
+        let mut config = TagBasedAlarmConfig {
+            matchers,
+            agg: aggregation.unwrap_or(Aggregation::Avg),
+            value,
+            value_comp,
+            time_window: DEFAULT_TIME_WINDOW,
+            datapoints_to_alarm: None,
+            datapoints_to_recover: None,
+            recovery_value: None,
+            missing_data: MissingData::Missing,
+            statistic: Statistic::Value,
+            group_by: vec![],
+        };
+        self.clauses(&mut config)?;
+        Ok(config)
+    }
+
+    fn matcher(&mut self) -> Result<Match, Error> {
+        let column = self.column();
+        let attribute = self.value("a metric name or an attribute")?;
+
+        let operator = match self.peek() {
+            Some((_, Token::Operator(operator))) => Some(*operator),
+            _ => None,
+        };
+        let not_in = self.peek_keyword("not")
+            && matches!(self.tokens.get(self.position + 1), Some((_, Token::Word(word))) if word.eq_ignore_ascii_case("in"));
+
+        let (match_type, value) = if let Some(match_type) = match operator {
+            Some("=") => Some(MatchType::Eq),
+            Some("!=") => Some(MatchType::NotEq),
+            Some("=~") => Some(MatchType::Regex),
+            Some("!~") => Some(MatchType::NotRegex),
+            _ => None,
+        } {
+            self.position += 1;
+            (match_type, self.value("a value")?)
+        } else if self.accept_keyword("glob") {
+            (MatchType::Glob, self.value("a glob")?)
+        } else if self.accept_keyword("in") {
+            return Match::list(attribute, MatchType::In, self.list()?)
+                .map_err(|source| Error::InvalidAlarm { column, source });
+        } else if not_in {
+            self.position += 2;
+            return Match::list(attribute, MatchType::NotIn, self.list()?)
+                .map_err(|source| Error::InvalidAlarm { column, source });
+        } else if self.accept_keyword("exists") {
+            (MatchType::Exists, String::new())
+        } else if self.accept_keyword("absent") {
+            (MatchType::Absent, String::new())
+        } else {
+            // a bare value is the name of the metric
+            return Match::new(METRIC_NAME_ATTRIBUTE.to_string(), MatchType::Eq, attribute)
+                .map_err(|source| Error::InvalidAlarm { column, source });
+        };
+        Match::new(attribute, match_type, value)
+            .map_err(|source| Error::InvalidAlarm { column, source })
+    }
+
+    /// `(a, b, c)`
+    fn list(&mut self) -> Result<Vec<String>, Error> {
+        self.expect(Token::LeftParen)?;
+        let mut values = vec![self.value("a value")?];
+        while self.accept(&Token::Comma) {
+            values.push(self.value("a value")?);
+        }
+        self.expect(Token::RightParen)?;
+        Ok(values)
+    }
+
+    fn clauses(&mut self, config: &mut TagBasedAlarmConfig) -> Result<(), Error> {
+        loop {
+            if self.accept_keyword("over") {
+                config.time_window = self.duration()?;
+            } else if self.accept_keyword("statistic") {
+                config.statistic = self.statistic()?;
+            } else if self.accept_keyword("datapoints") {
+                config.datapoints_to_alarm = Some(self.number("a number of data points")?);
+            } else if self.accept_keyword("recover") {
+                config.datapoints_to_recover = Some(self.number("a number of data points")?);
+            } else if self.accept_keyword("recover_at") {
+                config.recovery_value = Some(self.number("a number")?);
+            } else if self.accept_keyword("missing") {
+                let token = self.next("a missing data policy")?;
+                config.missing_data = match &token.1 {
+                    Token::Word(word) => MISSING_DATA
+                        .iter()
+                        .find(|(name, _)| word.eq_ignore_ascii_case(name))
+                        .map(|(_, missing_data)| *missing_data),
+                    _ => None,
+                }
+                .ok_or_else(|| self.unexpected(&token, &names(&MISSING_DATA)))?;
+            } else if self.accept_keyword("by") {
+                config.group_by = self.list()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// `5m` or `1h`, in minutes.
+    fn duration(&mut self) -> Result<i64, Error> {
+        const EXPECTED: &str = "a duration (e.g. 5m or 1h)";
+        let token = self.next(EXPECTED)?;
+        let minutes = match &token.1 {
+            Token::Word(word) => {
+                if let Some(minutes) = word.strip_suffix('m') {
+                    minutes.parse::<i64>().ok()
+                } else if let Some(hours) = word.strip_suffix('h') {
+                    hours.parse::<i64>().ok().and_then(|h| h.checked_mul(60))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        minutes.ok_or_else(|| self.unexpected(&token, EXPECTED))
+    }
+
+    /// `value`, `count`, `sum`, `mean` or `quantile(0.99)`
+    fn statistic(&mut self) -> Result<Statistic, Error> {
+        const EXPECTED: &str = "a statistic (value, count, sum, mean or quantile)";
+        let token = self.next(EXPECTED)?;
+        let word = match &token.1 {
+            Token::Word(word) => word.to_ascii_lowercase(),
+            _ => return Err(self.unexpected(&token, EXPECTED)),
+        };
+        match word.as_str() {
+            "value" => Ok(Statistic::Value),
+            "count" => Ok(Statistic::Count),
+            "sum" => Ok(Statistic::Sum),
+            "mean" => Ok(Statistic::Mean),
+            "quantile" => {
+                self.expect(Token::LeftParen)?;
+                let q = self.number("a quantile between 0 and 1")?;
+                self.expect(Token::RightParen)?;
+                Ok(Statistic::Quantile(q))
+            }
+            _ => Err(self.unexpected(&token, EXPECTED)),
+        }
+    }
+}
+
+const AGGREGATIONS: [(&str, Aggregation); 11] = [
+    ("avg", Aggregation::Avg),
+    ("max", Aggregation::Max),
+    ("min", Aggregation::Min),
+    ("sum", Aggregation::Sum),
+    ("count", Aggregation::Count),
+    ("last", Aggregation::Last),
+    ("stddev", Aggregation::StdDev),
+    ("p50", Aggregation::P50),
+    ("p90", Aggregation::P90),
+    ("p99", Aggregation::P99),
+    ("rate", Aggregation::Rate),
+];
+
+const MISSING_DATA: [(&str, MissingData); 4] = [
+    ("breaching", MissingData::Breaching),
+    ("not_breaching", MissingData::NotBreaching),
+    ("ignore", MissingData::Ignore),
+    ("missing", MissingData::Missing),
+];
+
+fn names<T>(values: &[(&str, T)]) -> String {
+    values
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn name_of<T: PartialEq>(values: &[(&'static str, T)], value: &T) -> &'static str {
+    values
+        .iter()
+        .find(|(_, v)| v == value)
+        .map(|(name, _)| *name)
+        .unwrap_or_default()
+}
+
+/// keywords that must be quoted to be used as values.
+const KEYWORDS: [&str; 15] = [
+    "and",
+    "or",
+    "not",
+    "alarm",
+    "in",
+    "glob",
+    "exists",
+    "absent",
+    "over",
+    "statistic",
+    "datapoints",
+    "recover",
+    "recover_at",
+    "missing",
+    "by",
+];
+
+/// writes the value as a word if possible, otherwise quoted.
+fn write_value(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    let is_word = !value.is_empty()
+        && value.chars().all(is_word_char)
+        && !KEYWORDS.iter().any(|k| value.eq_ignore_ascii_case(k))
+        && !AGGREGATIONS
+            .iter()
+            .any(|(a, _)| value.eq_ignore_ascii_case(a));
+    if is_word {
+        f.write_str(value)
+    } else {
+        write!(
+            f,
+            "\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    }
+}
+
+fn write_list<'a>(
+    f: &mut fmt::Formatter<'_>,
+    values: impl Iterator<Item = &'a str>,
+) -> fmt::Result {
+    f.write_str("(")?;
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write_value(f, value)?;
+    }
+    f.write_str(")")
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.attribute() == METRIC_NAME_ATTRIBUTE && self.match_type() == MatchType::Eq {
+            return write_value(f, self.value());
+        }
+
+        write_value(f, self.attribute())?;
+        let operator = match self.match_type() {
+            MatchType::Eq => "=",
+            MatchType::NotEq => "!=",
+            MatchType::Regex => "=~",
+            MatchType::NotRegex => "!~",
+            MatchType::Glob => " glob ",
+            MatchType::In => " in ",
+            MatchType::NotIn => " not in ",
+            MatchType::Exists => return f.write_str(" exists"),
+            MatchType::Absent => return f.write_str(" absent"),
+        };
+        f.write_str(operator)?;
+        match self.match_type() {
+            MatchType::In | MatchType::NotIn => {
+                write_list(f, self.values().iter().map(|v| v.as_str()))
+            }
+            _ => write_value(f, self.value()),
+        }
+    }
+}
+
+impl fmt::Display for TagBasedAlarmConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", name_of(&AGGREGATIONS, &self.agg))?;
+        for (i, matcher) in self.matchers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", matcher)?;
+        }
+        let comparison = match self.value_comp {
+            ThresholdType::GreaterThan => ">",
+            ThresholdType::LessThan => "<",
+            ThresholdType::Eq => "==",
+            ThresholdType::NotEq => "!=",
+        };
+        write!(f, ") {} {}", comparison, self.value)?;
+
+        if self.time_window % 60 == 0 {
+            write!(f, " over {}h", self.time_window / 60)?;
+        } else {
+            write!(f, " over {}m", self.time_window)?;
+        }
+        match self.statistic {
+            Statistic::Value => {}
+            Statistic::Count => f.write_str(" statistic count")?,
+            Statistic::Sum => f.write_str(" statistic sum")?,
+            Statistic::Mean => f.write_str(" statistic mean")?,
+            Statistic::Quantile(q) => write!(f, " statistic quantile({})", q)?,
+        }
+        if let Some(datapoints) = self.datapoints_to_alarm {
+            write!(f, " datapoints {}", datapoints)?;
+        }
+        if let Some(datapoints) = self.datapoints_to_recover {
+            write!(f, " recover {}", datapoints)?;
+        }
+        if let Some(value) = self.recovery_value {
+            write!(f, " recover_at {}", value)?;
+        }
+        if self.missing_data != MissingData::Missing {
+            write!(f, " missing {}", name_of(&MISSING_DATA, &self.missing_data))?;
+        }
+        if !self.group_by.is_empty() {
+            f.write_str(" by ")?;
+            write_list(f, self.group_by.iter().map(|v| v.as_str()))?;
+        }
+        Ok(())
+    }
+}
+
+/// writes the expression, with parentheses only where the precedence requires it.
+fn write_operator<I>(
+    f: &mut fmt::Formatter<'_>,
+    operator: &LogicalOperator<I>,
+    write_item: &impl Fn(&mut fmt::Formatter<'_>, &I) -> fmt::Result,
+) -> fmt::Result {
+    fn precedence<I>(operator: &LogicalOperator<I>) -> u8 {
+        match operator {
+            LogicalOperator::Or(..) => 0,
+            LogicalOperator::And(..) => 1,
+            LogicalOperator::Not(..) | LogicalOperator::Identity(..) => 2,
+        }
+    }
+
+    let write_child = |f: &mut fmt::Formatter<'_>, child: &LogicalOperator<I>, parens: bool| {
+        if parens {
+            f.write_str("(")?;
+            write_operator(f, child, write_item)?;
+            f.write_str(")")
+        } else {
+            write_operator(f, child, write_item)
+        }
+    };
+
+    match operator {
+        LogicalOperator::Identity(item) => write_item(f, item),
+        LogicalOperator::And(left, right) | LogicalOperator::Or(left, right) => {
+            let keyword = match operator {
+                LogicalOperator::And(..) => " AND ",
+                _ => " OR ",
+            };
+            // operators are left associative
+            write_child(f, left, precedence(left) < precedence(operator))?;
+            f.write_str(keyword)?;
+            write_child(f, right, precedence(right) <= precedence(operator))
+        }
+        LogicalOperator::Not(item) => {
+            f.write_str("NOT ")?;
+            write_child(f, item, precedence(item) < 2)
+        }
+    }
+}
+
+impl fmt::Display for AlarmConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlarmConfig::TagBased(config) => write!(f, "{}", config),
+            AlarmConfig::Combination(config) => {
+                write_operator(f, &config.alarm, &|f, config| write!(f, "{}", config))
+            }
+            AlarmConfig::Composite(config) => write_operator(f, &config.alarm, &|f, id| {
+                f.write_str("ALARM(\"")?;
+                f.write_str(&id.replace('\\', "\\\\").replace('"', "\\\""))?;
+                f.write_str("\")")
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(input: &str) -> String {
+        let printed = parse(input).unwrap().to_string();
+        assert_eq!(printed, parse(&printed).unwrap().to_string(), "{}", input);
+        printed
+    }
+
+    fn error(input: &str) -> Error {
+        match parse(input) {
+            Ok(config) => panic!("{} parsed as {}", input, config),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_tag_based_alarms() {
+        let config = match parse("metric_name=cpu.usage service=my_nice_service > 80").unwrap() {
+            AlarmConfig::TagBased(config) => config,
+            _ => panic!("expected a tag based alarm"),
+        };
+        assert_eq!(2, config.matchers.len());
+        assert_eq!("service", config.matchers[1].attribute());
+        assert_eq!(Aggregation::Avg, config.agg);
+        assert_eq!(80.0, config.value);
+        assert_eq!(DEFAULT_TIME_WINDOW, config.time_window);
+
+        let config = match parse(
+            r#"P99(http.latency, host=~"web-.*", env in (prod, staging)) > 0.5 over 1h
+               statistic quantile(0.9) datapoints 3 recover 2 recover_at 0.3
+               missing not_breaching by (host)"#,
+        )
+        .unwrap()
+        {
+            AlarmConfig::TagBased(config) => config,
+            _ => panic!("expected a tag based alarm"),
+        };
+        assert_eq!(Aggregation::P99, config.agg);
+        assert_eq!(MatchType::Regex, config.matchers[1].match_type());
+        assert_eq!(vec!["prod", "staging"], config.matchers[2].values());
+        assert_eq!(60, config.time_window);
+        assert_eq!(Statistic::Quantile(0.9), config.statistic);
+        assert_eq!(Some(3), config.datapoints_to_alarm);
+        assert_eq!(Some(2), config.datapoints_to_recover);
+        assert_eq!(Some(0.3), config.recovery_value);
+        assert_eq!(MissingData::NotBreaching, config.missing_data);
+        assert_eq!(vec!["host"], config.group_by);
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(
+            "avg(a) > 1 over 5m OR avg(b) > 1 over 5m AND NOT avg(c) > 1 over 5m",
+            round_trip("a > 1 or b > 1 and not c > 1")
+        );
+        assert_eq!(
+            "(avg(a) > 1 over 5m OR avg(b) > 1 over 5m) AND avg(c) > 1 over 10m",
+            round_trip("(a > 1 OR b > 1) AND c > 1 over 10m")
+        );
+        assert_eq!(
+            "avg(a) > 1 over 5m AND (avg(b) > 1 over 5m AND avg(c) > 1 over 5m)",
+            round_trip("a > 1 AND (b > 1 AND c > 1)")
+        );
+        assert_eq!(
+            "NOT (avg(a) > 1 over 5m OR avg(b) > 1 over 5m)",
+            round_trip("NOT (a > 1 OR b > 1)")
+        );
+
+        match parse("cpu.usage > 80 AND memory.usage > 80 over 15m").unwrap() {
+            AlarmConfig::Combination(config) => assert_eq!(15, config.time_window),
+            _ => panic!("expected a combination alarm"),
+        }
+    }
+
+    #[test]
+    fn parses_alarm_references() {
+        assert_eq!(
+            r#"ALARM("db-latency") AND NOT ALARM("db-maintenance")"#,
+            round_trip(r#"ALARM("db-latency") AND NOT ALARM("db-maintenance")"#)
+        );
+        assert!(matches!(
+            parse(r#"alarm(db)"#).unwrap(),
+            AlarmConfig::Composite(_)
+        ));
+    }
+
+    #[test]
+    fn pretty_printer_round_trips() {
+        for input in [
+            "max(cpu.usage, service=my_nice_service) > 80 over 5m",
+            r#"min("cpu usage", "and"="x \"y\"", host!=web-1, host!~"db-.*") < -1.5 over 2h"#,
+            r#"sum(requests, host glob "web-*", env not in (dev, "qa env"), az exists, pod absent) == 0 over 90m"#,
+            "rate(requests) != 10 over 5m statistic mean missing breaching by (host, env)",
+            "last(queue.size) > 100 over 10m datapoints 2 recover 5 recover_at 50",
+            r#"avg(cpu.usage, env in ("a,b", c)) > 1 over 5m"#,
+        ] {
+            assert_eq!(input, round_trip(input));
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_column() {
+        let e = error("cpu.usage > 80 AND");
+        assert_eq!(19, e.column());
+        assert_eq!(
+            "column 19: expected a metric name or an attribute, found the end of the alarm",
+            e.to_string()
+        );
+
+        let e = error("cpu.usage >> 80");
+        assert_eq!(12, e.column());
+        assert!(e.to_string().contains("expected a number, found '>'"));
+
+        let e = error("median(cpu.usage) > 80");
+        assert_eq!(1, e.column());
+        assert!(e.to_string().contains("unknown aggregation 'median'"));
+
+        assert_eq!(8, error("(a > 1 b > 2").column());
+        assert_eq!(15, error(r#"a > 1 AND b = "x"#).column());
+        assert_eq!(7, error("a > 1 # b").column());
+        assert_eq!(12, error("a > 1 over 5s").column());
+        assert!(matches!(
+            error("a > 1 over 5é"),
+            Error::Syntax { column: 12, .. }
+        ));
+        assert_eq!(11, error(r#"a > 1 AND ALARM("b")"#).column());
+        assert_eq!(3, error(r#"a host=~"(" > 1"#).column());
+        assert!(matches!(
+            error("a > 1 over 5m datapoints 6"),
+            Error::InvalidAlarm { column: 1, .. }
+        ));
+    }
+}
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Metrics is based on [OpenTelemetry](https://github.com/open-telemetry/opentelemetry-proto/blob/v0.9.0/opentelemetry/proto/metrics/v1/metrics.proto#L141)
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Metric {
     /// name of the metric, including its DNS name prefix. It must be unique
     pub name: String,
@@ -13,7 +13,7 @@ pub struct Metric {
     /// reported value type for the data points, as well as the relatationship to
     /// the time interval over which they are reported.
     pub data: MetricData,
-    /// Time when this metric was send.
+    /// Time when this metric was send, in milliseconds since the epoch.
     pub time: u64,
     /// The set of key/value pairs that uniquely identify the timeseries from
     /// where this point belongs. The list may be empty (may contain 0 elements).
@@ -38,7 +38,7 @@ pub struct Metric {
 /// to support correct rate calculation.  Although it may be omitted
 /// when the start time is truly unknown, setting StartTime is
 /// strongly encouraged.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MetricData {
     /// The last bool If "true" means that the sum is monotonic.
     Sum(DataPoint, AggregationTemporality, bool),
@@ -46,7 +46,7 @@ pub enum MetricData {
     Histogram(HistogramDataPoint, AggregationTemporality),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DataPoint {
     pub start_time: u64,
     pub time: u64,
@@ -62,7 +62,7 @@ pub struct DataPoint {
 /// If the histogram does not contain the distribution of values, then both
 /// "explicit_bounds" and "bucket_counts" must be omitted and only "count" and
 /// "sum" are known.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct HistogramDataPoint {
     pub start_time: u64,
     pub time: u64,
@@ -97,7 +97,7 @@ pub struct HistogramDataPoint {
     pub explicity_bouds: Box<[f64]>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum AggregationTemporality {
     None,
     /// DELTA is an AggregationTemporality for a metric aggregator which reports
@@ -153,8 +153,8 @@ pub enum AggregationTemporality {
     ///   10. The system recovers and resumes receiving at time=t_1.
     ///   11. A request is received, the system measures 1 request.
     ///   12. The 1 second collection cycle ends. A metric is exported for the
-    ///      number of requests received over the interval of time t_1 to
-    ///      t_0+1 with a value of 1.
+    ///       number of requests received over the interval of time t_1 to
+    ///       t_0+1 with a value of 1.
     ///
     /// Note: Even though, when reporting changes since last report time, using
     /// CUMULATIVE is valid, it is not recommended. This may cause problems for
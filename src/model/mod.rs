@@ -1,2 +1,3 @@
 pub mod alarm;
+pub mod dsl;
 pub mod metrics;
@@ -0,0 +1,327 @@
+use crate::wal::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How sealed pages are compressed, the page being written never is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// parses `none`, `lz4` or `zstd`.
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "invalid compression {}, expected none, lz4 or zstd",
+                s
+            )),
+        }
+    }
+}
+
+impl Compression {
+    const ZSTD_LEVEL: i32 = 3;
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(Error::CorruptedLogFile),
+        }
+    }
+
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(block.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(block)),
+            Compression::Zstd => Ok(zstd::bulk::compress(block, Self::ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decompress(&self, block: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let data = match self {
+            Compression::None => block.to_vec(),
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(block, len).map_err(|_| Error::CorruptedLogFile)?
+            }
+            Compression::Zstd => {
+                zstd::bulk::decompress(block, len).map_err(|_| Error::CorruptedLogFile)?
+            }
+        };
+        match data.len() == len {
+            true => Ok(data),
+            false => Err(Error::CorruptedLogFile),
+        }
+    }
+}
+
+/// CompressedPage is a sealed page compressed in blocks, so that reading
+/// from an offset only decompresses the block it is in:
+///
+/// | magic (4) | version (1) | compression (1) | block size (4, LE) | length (8, LE) |
+///
+/// followed by every block as | compressed length (4, LE) | data |. Blocks
+/// are `block size` bytes of the page once decompressed, except the last one.
+pub(super) struct CompressedPage {
+    file: File,
+    compression: Compression,
+    block_size: usize,
+    /// length of the page once decompressed.
+    len: u64,
+    /// where each block starts on the file and its compressed length.
+    blocks: Vec<(u64, usize)>,
+    /// the last block read, by index.
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl CompressedPage {
+    /// uncompressed pages start with a frame, which never starts with it.
+    const MAGIC: &'static [u8; 4] = b"GBWZ";
+    const VERSION: u8 = 1;
+    const HEADER_SIZE: usize = 18;
+    const BLOCK_SIZE: usize = 64 * 1024;
+
+    /// None if the page is not compressed.
+    pub fn open(path: &Path) -> Result<Option<Self>, Error> {
+        let mut file = File::open(path)?;
+        let mut header = [0; Self::HEADER_SIZE];
+        let mut read = 0;
+        while read < header.len() {
+            match file.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if read < Self::MAGIC.len() || &header[..4] != Self::MAGIC {
+            return Ok(None);
+        }
+        if read < Self::HEADER_SIZE {
+            return Err(Error::CorruptedLogFile);
+        }
+        if header[4] != Self::VERSION {
+            return Err(Error::UnsupportedVersion(header[4]));
+        }
+        let compression = Compression::from_id(header[5])?;
+        let block_size = u32::from_le_bytes(header[6..10].try_into().unwrap()) as usize;
+        let len = u64::from_le_bytes(header[10..18].try_into().unwrap());
+        if block_size == 0 {
+            return Err(Error::CorruptedLogFile);
+        }
+
+        let file_len = file.metadata()?.len();
+        let mut blocks = vec![];
+        let mut offset = Self::HEADER_SIZE as u64;
+        while offset < file_len {
+            let mut size = [0; 4];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut size)
+                .map_err(|_| Error::CorruptedLogFile)?;
+            let size = u32::from_le_bytes(size) as usize;
+            blocks.push((offset + 4, size));
+            offset += 4 + size as u64;
+        }
+        if offset != file_len || blocks.len() as u64 != len.div_ceil(block_size as u64) {
+            return Err(Error::CorruptedLogFile);
+        }
+
+        Ok(Some(Self {
+            file,
+            compression,
+            block_size,
+            len,
+            blocks,
+            cache: None,
+        }))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// reads from the block the offset is in, returns 0 at the end of the page.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let index = (offset / self.block_size as u64) as usize;
+        if self.cache.as_ref().map(|(i, _)| *i) != Some(index) {
+            let (start, size) = self.blocks[index];
+            let mut data = vec![0; size];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file
+                .read_exact(&mut data)
+                .map_err(|_| Error::CorruptedLogFile)?;
+            let len = (self.len - (index * self.block_size) as u64).min(self.block_size as u64);
+            let block = self.compression.decompress(&data, len as usize)?;
+            self.cache = Some((index, block));
+        }
+
+        let block = &self.cache.as_ref().unwrap().1;
+        let start = (offset % self.block_size as u64) as usize;
+        let read = buf.len().min(block.len() - start);
+        buf[..read].copy_from_slice(&block[start..start + read]);
+        Ok(read)
+    }
+
+    /// writes the compressed version of the (uncompressed) page next to it
+    /// and returns its path, the page is replaced later with `replace` so
+    /// that a crash leaves the uncompressed one.
+    pub fn write_compressed(path: &Path, compression: Compression) -> Result<PathBuf, Error> {
+        let tmp = path.with_extension("tmp");
+        let written = Self::write_blocks(path, &tmp, compression);
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written.map(|()| tmp)
+    }
+
+    fn write_blocks(path: &Path, tmp: &Path, compression: Compression) -> Result<(), Error> {
+        let data = fs::read(path)?;
+        let mut file = File::create(tmp)?;
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE);
+        header.extend_from_slice(Self::MAGIC);
+        header.push(Self::VERSION);
+        header.push(compression.id());
+        header.extend_from_slice(&(Self::BLOCK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        file.write_all(&header)?;
+        for block in data.chunks(Self::BLOCK_SIZE) {
+            let block = compression.compress(block)?;
+            file.write_all(&(block.len() as u32).to_le_bytes())?;
+            file.write_all(&block)?;
+        }
+        Ok(file.sync_all()?)
+    }
+
+    /// replaces the page by its uncompressed version, so that it can be
+    /// written again (see `WAL::truncate`).
+    pub fn decompress(&mut self, path: &Path) -> Result<(), Error> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        let mut offset = 0;
+        let mut buf = vec![0; self.block_size];
+        loop {
+            let read = self.read(offset, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read])?;
+            offset += read as u64;
+        }
+        file.sync_all()?;
+        Self::replace(&tmp, path)
+    }
+
+    /// renames `tmp` to `path`, the page it replaces may still be open.
+    pub fn replace(tmp: &Path, path: &Path) -> Result<(), Error> {
+        fs::rename(tmp, path)?;
+        if let Some(dir) = path.parent() {
+            OpenOptions::new().read(true).open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// reads a page from `offset`, decompressing it if needed, along with the
+/// length of the decompressed page. Used by `Iter`.
+pub(super) fn open_page(path: &Path, offset: u64) -> Result<(Box<dyn Read + Send>, u64), Error> {
+    match CompressedPage::open(path)? {
+        Some(page) => {
+            let len = page.len();
+            Ok((Box::new(PageReader { page, offset }), len))
+        }
+        None => {
+            let mut file = File::open(path)?;
+            let len = file.metadata()?.len();
+            file.seek(SeekFrom::Start(offset))?;
+            Ok((Box::new(file), len))
+        }
+    }
+}
+
+struct PageReader {
+    page: CompressedPage,
+    offset: u64,
+}
+
+impl Read for PageReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.page.read(self.offset, buf).map_err(|e| match e {
+            Error::IOError(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+        })?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn parses_compression() {
+        assert_eq!(Ok(Compression::None), "none".parse());
+        assert_eq!(Ok(Compression::Lz4), "lz4".parse());
+        assert_eq!(Ok(Compression::Zstd), "zstd".parse());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn pages_are_read_from_any_offset() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log_page_0");
+        // more than one block
+        let data: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            fs::write(&path, &data).unwrap();
+            assert!(CompressedPage::open(&path).unwrap().is_none());
+
+            let tmp = CompressedPage::write_compressed(&path, compression).unwrap();
+            CompressedPage::replace(&tmp, &path).unwrap();
+            let mut page = CompressedPage::open(&path).unwrap().unwrap();
+            assert_eq!(data.len() as u64, page.len());
+            if compression != Compression::None {
+                assert!(fs::metadata(&path).unwrap().len() < data.len() as u64);
+            }
+
+            let mut buf = [0; 100];
+            let offset = CompressedPage::BLOCK_SIZE as u64 - 40;
+            // up to the end of the block
+            assert_eq!(40, page.read(offset, &mut buf).unwrap());
+            assert_eq!(&data[offset as usize..offset as usize + 40], &buf[..40]);
+            assert_eq!(0, page.read(data.len() as u64, &mut buf).unwrap());
+
+            let mut read = vec![];
+            let (mut reader, len) = open_page(&path, 10).unwrap();
+            assert_eq!(data.len() as u64, len);
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(&data[10..], &read[..]);
+
+            page.decompress(&path).unwrap();
+            assert_eq!(data, fs::read(&path).unwrap());
+        }
+    }
+}
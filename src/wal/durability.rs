@@ -0,0 +1,232 @@
+use crate::wal::Error;
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// When the WAL calls fsync, pages are always synced once they are full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Durability {
+    /// every write is on disk once its `Commit` is waited on, writers
+    /// waiting at the same time share one fsync (group commit).
+    Always,
+    /// a background thread syncs the writes every interval.
+    Interval(Duration),
+    /// syncs once this many bytes were written since the last sync.
+    Bytes(u64),
+    /// leaves it to the operating system.
+    #[default]
+    Os,
+}
+
+/// parses `always`, `os`, `<N>ms` or `<N>bytes`, N must be at least 1.
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |n: &str| {
+            n.parse::<u64>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("invalid durability {}, N must be at least 1", s))
+        };
+        match s {
+            "always" => Ok(Durability::Always),
+            "os" => Ok(Durability::Os),
+            _ => match (s.strip_suffix("ms"), s.strip_suffix("bytes")) {
+                (Some(ms), _) => Ok(Durability::Interval(Duration::from_millis(number(ms)?))),
+                (_, Some(bytes)) => Ok(Durability::Bytes(number(bytes)?)),
+                _ => Err(format!(
+                    "invalid durability {}, expected always, os, <N>ms or <N>bytes",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Syncer keeps track of what was written to the active page and what is
+/// already on disk, it is shared with the writers waiting for a commit and
+/// the background flusher.
+pub(super) struct Syncer {
+    durability: Durability,
+    /// the active page.
+    file: Mutex<File>,
+    /// bytes written to the WAL since it was opened.
+    written: AtomicU64,
+    /// bytes known to be on disk, locked while syncing so that writers
+    /// waiting for the same sync share it.
+    synced: Mutex<u64>,
+}
+
+impl Syncer {
+    pub(super) fn new(durability: Durability, file: File) -> Self {
+        Self {
+            durability,
+            file: Mutex::new(file),
+            written: AtomicU64::new(0),
+            synced: Mutex::new(0),
+        }
+    }
+
+    /// called when a new page becomes the active one.
+    pub(super) fn set_file(&self, file: File) {
+        *self.file.lock().unwrap() = file;
+    }
+
+    /// records a write, syncing if the durability requires it.
+    pub(super) fn written(&self, bytes: usize) -> Result<(), Error> {
+        let written = self.written.fetch_add(bytes as u64, Ordering::AcqRel) + bytes as u64;
+        if let Durability::Bytes(threshold) = self.durability {
+            if written - *self.synced.lock().unwrap() >= threshold {
+                self.sync_to(written)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn commit(self: &Arc<Self>) -> Commit {
+        Commit {
+            syncer: self.clone(),
+            position: self.written.load(Ordering::Acquire),
+        }
+    }
+
+    pub(super) fn sync(&self) -> Result<(), Error> {
+        self.sync_to(self.written.load(Ordering::Acquire))
+    }
+
+    /// makes sure the first `position` bytes are on disk.
+    fn sync_to(&self, position: u64) -> Result<(), Error> {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= position {
+            // synced by someone else while we waited
+            return Ok(());
+        }
+        let written = self.written.load(Ordering::Acquire);
+        self.file.lock().unwrap().sync_data()?;
+        *synced = written;
+        Ok(())
+    }
+
+    /// starts the background flusher for `Durability::Interval`, it stops
+    /// (after a last sync) once the returned sender is dropped.
+    pub(super) fn start_flusher(self: &Arc<Self>) -> Result<Option<Sender<()>>, Error> {
+        let interval = match self.durability {
+            Durability::Interval(interval) => interval,
+            _ => return Ok(None),
+        };
+        let (stop, stopped) = mpsc::channel::<()>();
+        let syncer = self.clone();
+        thread::Builder::new()
+            .name("wal-flusher".to_string())
+            .spawn(move || loop {
+                let result = stopped.recv_timeout(interval);
+                if let Err(e) = syncer.sync() {
+                    event!(Level::ERROR, "could not sync the WAL {}", e);
+                }
+                if result != Err(RecvTimeoutError::Timeout) {
+                    break;
+                }
+            })?;
+        Ok(Some(stop))
+    }
+}
+
+/// Commit is returned after writing to the WAL, waiting on it makes sure
+/// the writes are durable according to the `Durability` of the WAL.
+#[must_use]
+pub struct Commit {
+    syncer: Arc<Syncer>,
+    position: u64,
+}
+
+impl Commit {
+    /// only blocks (on fsync) with `Durability::Always`, it should be called
+    /// without holding the lock of the WAL so that writers can share the sync.
+    pub fn wait(self) -> Result<(), Error> {
+        match self.syncer.durability {
+            Durability::Always => self.syncer.sync_to(self.position),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_durability() {
+        assert_eq!(Ok(Durability::Always), "always".parse());
+        assert_eq!(Ok(Durability::Os), "os".parse());
+        assert_eq!(
+            Ok(Durability::Interval(Duration::from_millis(100))),
+            "100ms".parse()
+        );
+        assert_eq!(Ok(Durability::Bytes(4096)), "4096bytes".parse());
+        assert!("sometimes".parse::<Durability>().is_err());
+        assert!("fastms".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn refuses_zero_durability() {
+        assert!("0ms".parse::<Durability>().is_err());
+        assert!("0bytes".parse::<Durability>().is_err());
+    }
+
+    fn syncer(durability: Durability, dir: &temp_dir::TempDir) -> Arc<Syncer> {
+        let file = File::create(dir.path().join("log_page_0")).unwrap();
+        Arc::new(Syncer::new(durability, file))
+    }
+
+    fn synced(syncer: &Syncer) -> u64 {
+        *syncer.synced.lock().unwrap()
+    }
+
+    #[test]
+    fn syncs_according_to_the_durability() {
+        let dir = temp_dir::TempDir::new().unwrap();
+
+        let always = syncer(Durability::Always, &dir);
+        always.written(4).unwrap();
+        let commit = always.commit();
+        always.written(4).unwrap();
+        assert_eq!(0, synced(&always));
+        commit.wait().unwrap();
+        // the sync also covered the second write
+        assert_eq!(8, synced(&always));
+
+        let bytes = syncer(Durability::Bytes(10), &dir);
+        bytes.written(4).unwrap();
+        assert_eq!(0, synced(&bytes));
+        bytes.written(8).unwrap();
+        assert_eq!(12, synced(&bytes));
+
+        let os = syncer(Durability::Os, &dir);
+        os.written(4).unwrap();
+        os.commit().wait().unwrap();
+        assert_eq!(0, synced(&os));
+    }
+
+    #[test]
+    fn flusher_syncs_in_the_background() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let syncer = syncer(Durability::Interval(Duration::from_millis(5)), &dir);
+        let stop = syncer.start_flusher().unwrap();
+        assert!(stop.is_some());
+
+        syncer.written(4).unwrap();
+        for _ in 0..100 {
+            if synced(&syncer) == 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(4, synced(&syncer));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::wal::Error;
+
+/// Frame is a record as written to the WAL:
+///
+/// | magic/version (1) | record type (1) | length (4, LE) | crc32c (4, LE) | data |
+///
+/// the checksum covers the record type, the length and the data, so torn
+/// writes (e.g. a crash in the middle of a write) are detected on recovery.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+    pub record_type: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// the high bits identify a frame, the low bits are the format version.
+    const MAGIC: u8 = 0xB0;
+    pub const VERSION: u8 = 1;
+    pub const HEADER_SIZE: usize = 10;
+
+    pub fn new(record_type: u8, data: Vec<u8>) -> Self {
+        Self { record_type, data }
+    }
+
+    /// size of the frame on disk.
+    pub fn size(&self) -> usize {
+        Self::HEADER_SIZE + self.data.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.size());
+        buf.push(Self::MAGIC | Self::VERSION);
+        buf.push(self.record_type);
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.checksum().to_le_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    /// parses the header, returning the record type, the length of the data
+    /// and the expected checksum.
+    pub fn decode_header(header: &[u8; Self::HEADER_SIZE]) -> Result<(u8, usize, u32), Error> {
+        if header[0] & 0xF0 != Self::MAGIC {
+            return Err(Error::CorruptedLogFile);
+        }
+        let version = header[0] & 0x0F;
+        if version > Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let length = u32::from_le_bytes(header[2..6].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[6..10].try_into().unwrap());
+        Ok((header[1], length, checksum))
+    }
+
+    /// builds the frame from its parts, failing if the checksum does not match.
+    pub fn verify(record_type: u8, data: Vec<u8>, checksum: u32) -> Result<Self, Error> {
+        let frame = Self::new(record_type, data);
+        if frame.checksum() != checksum {
+            return Err(Error::CorruptedLogFile);
+        }
+        Ok(frame)
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut header = [self.record_type, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        crc32c::crc32c_append(crc32c::crc32c(&header), &self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(buf: &[u8]) -> Result<Frame, Error> {
+        let (record_type, length, checksum) =
+            Frame::decode_header(buf[..Frame::HEADER_SIZE].try_into().unwrap())?;
+        let data = buf[Frame::HEADER_SIZE..].to_vec();
+        assert_eq!(length, data.len());
+        Frame::verify(record_type, data, checksum)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = Frame::new(3, b"my_entry".to_vec());
+        let encoded = frame.encode();
+        assert_eq!(frame.size(), encoded.len());
+        assert_eq!(0xB1, encoded[0]);
+        assert_eq!(frame, decode(&encoded).unwrap());
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let encoded = Frame::new(3, b"my_entry".to_vec()).encode();
+
+        let mut flipped = encoded.clone();
+        flipped[Frame::HEADER_SIZE + 2] ^= 1;
+        assert!(matches!(decode(&flipped), Err(Error::CorruptedLogFile)));
+
+        let mut wrong_type = encoded.clone();
+        wrong_type[1] = 4;
+        assert!(matches!(decode(&wrong_type), Err(Error::CorruptedLogFile)));
+
+        let mut newer = encoded;
+        newer[0] = 0xB2;
+        assert!(matches!(decode(&newer), Err(Error::UnsupportedVersion(2))));
+
+        assert!(matches!(
+            decode(&[0; Frame::HEADER_SIZE]),
+            Err(Error::CorruptedLogFile)
+        ));
+    }
+}
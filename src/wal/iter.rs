@@ -0,0 +1,130 @@
+use crate::wal::compression::open_page;
+use crate::wal::{Error, Frame, WAL};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::PathBuf;
+
+/// where a frame starts on the WAL.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Position {
+    pub page: usize,
+    pub offset: u64,
+}
+
+impl Position {
+    pub fn new(page: usize, offset: u64) -> Self {
+        Self { page, offset }
+    }
+}
+
+/// Iter reads the frames of the WAL in order, from a position until the end
+/// of the last page, moving to the next page when one ends. It opens its own
+/// files, so the WAL can be written (or truncated) while iterating, and
+/// decompresses the pages that are compressed.
+///
+/// An incomplete frame or a checksum mismatch is returned as
+/// `Error::CorruptedLogFile` and ends the iteration, `position` is then
+/// where the corrupted frame starts.
+pub struct Iter {
+    dir: PathBuf,
+    /// pages left to read, the current one first.
+    pages: Vec<usize>,
+    reader: Option<BufReader<Box<dyn Read + Send>>>,
+    /// length of the current page.
+    len: u64,
+    position: Position,
+    done: bool,
+}
+
+impl Iter {
+    pub(crate) fn new(dir: PathBuf, pages: Vec<usize>, position: Position) -> Self {
+        Self {
+            dir,
+            pages,
+            reader: None,
+            len: 0,
+            position,
+            done: false,
+        }
+    }
+
+    /// where the next frame starts.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            let page = match self.pages.first() {
+                Some(page) => *page,
+                None => return Ok(None),
+            };
+            if self.reader.is_none() {
+                let path = self.dir.join(WAL::page_name(page));
+                let (reader, len) = open_page(&path, self.position.offset)?;
+                self.reader = Some(BufReader::new(reader));
+                self.len = len;
+            }
+            let reader = self.reader.as_mut().unwrap();
+
+            if reader.fill_buf()?.is_empty() {
+                // the end of the page
+                self.pages.remove(0);
+                self.reader = None;
+                if let Some(page) = self.pages.first() {
+                    self.position = Position::new(*page, 0);
+                }
+                continue;
+            }
+
+            let mut header = [0; Frame::HEADER_SIZE];
+            read_exact(reader, &mut header)?;
+            let (record_type, length, checksum) = Frame::decode_header(&header)?;
+            let end = self.position.offset + (Frame::HEADER_SIZE + length) as u64;
+            if end > self.len {
+                // the active page may have grown since it was opened
+                self.len = open_page(&self.dir.join(WAL::page_name(page)), 0)?.1;
+                if end > self.len {
+                    return Err(Error::CorruptedLogFile);
+                }
+            }
+            let mut data = vec![0; length];
+            read_exact(reader, &mut data)?;
+            let frame = Frame::verify(record_type, data, checksum)?;
+            return Ok(Some(frame));
+        }
+    }
+}
+
+/// fills the buffer, a page that ends before is corrupted (e.g. a torn write).
+pub(super) fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::CorruptedLogFile,
+        _ => Error::IOError(e),
+    })
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Position, Frame), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_frame() {
+            Ok(Some(frame)) => {
+                let position = self.position;
+                self.position.offset += frame.size() as u64;
+                Some(Ok((position, frame)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
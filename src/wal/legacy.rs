@@ -0,0 +1,84 @@
+use crate::wal::compression::open_page;
+use crate::wal::iter::read_exact;
+use crate::wal::{Error, Position};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// UnframedIter reads the entries of a page written before records were
+/// framed (see `Frame`), each one prefixed by its length in 8 bytes (native
+/// endian) and without a checksum. The page may be compressed, since it is
+/// sealed once it is recovered.
+///
+/// A length past the end of the page (e.g. a torn write) is returned as
+/// `Error::CorruptedLogFile` and ends the iteration, `position` is then
+/// where the entry starts.
+pub struct UnframedIter {
+    reader: BufReader<Box<dyn Read + Send>>,
+    /// length of the page.
+    len: u64,
+    position: Position,
+    done: bool,
+}
+
+impl UnframedIter {
+    const LENGTH_SIZE: u64 = 8;
+
+    pub(crate) fn new(path: &Path, page: usize) -> Result<Self, Error> {
+        let (reader, len) = open_page(path, 0)?;
+        Ok(Self {
+            reader: BufReader::new(reader),
+            len,
+            position: Position::new(page, 0),
+            done: false,
+        })
+    }
+
+    /// where the next entry starts.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut length = [0; Self::LENGTH_SIZE as usize];
+        read_exact(&mut self.reader, &mut length)?;
+        let length = u64::from_ne_bytes(length);
+        let remaining = self
+            .len
+            .saturating_sub(self.position.offset + Self::LENGTH_SIZE);
+        if length > remaining {
+            // do not allocate what a torn length claims
+            return Err(Error::CorruptedLogFile);
+        }
+        let mut data = vec![0; length as usize];
+        read_exact(&mut self.reader, &mut data)?;
+        Ok(Some(data))
+    }
+}
+
+impl Iterator for UnframedIter {
+    type Item = Result<(Position, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_entry() {
+            Ok(Some(data)) => {
+                let position = self.position;
+                self.position.offset += Self::LENGTH_SIZE + data.len() as u64;
+                Some(Ok((position, data)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
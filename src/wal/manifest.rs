@@ -0,0 +1,167 @@
+use crate::wal::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Segment is a page of the WAL (a `log_page_N` file) as listed in the manifest.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Segment {
+    pub page: usize,
+    /// when the first and last records were written, in milliseconds since
+    /// the epoch. Unknown for pages written before the manifest existed.
+    pub first_time: Option<u64>,
+    pub last_time: Option<u64>,
+    /// sealed pages are full and never written again, only the last page is active.
+    pub sealed: bool,
+}
+
+/// Manifest lists the segments of the WAL in order, so that a page deleted
+/// by mistake is detected instead of silently skipped on recovery.
+///
+/// It is replaced atomically every time a page is created or deleted, the
+/// timestamps of the active page are only as fresh as the last replacement
+/// (see `WAL::sync`).
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub(crate) struct Manifest {
+    pub segments: Vec<Segment>,
+}
+
+impl Manifest {
+    const FILE_NAME: &'static str = "manifest";
+
+    /// None if the WAL was created before the manifest existed.
+    pub fn read(dir: &Path) -> Result<Option<Self>, Error> {
+        let data = match fs::read(dir.join(Self::FILE_NAME)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| Error::InvalidManifest(e.to_string()))
+    }
+
+    /// replaces the manifest atomically, a crash leaves the previous one.
+    pub fn write(&self, dir: &Path) -> Result<(), Error> {
+        let data = serde_json::to_vec(self).map_err(|e| Error::InvalidManifest(e.to_string()))?;
+        let tmp = dir.join(format!("{}.tmp", Self::FILE_NAME));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(Self::FILE_NAME))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn segment(&self, page: usize) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.page == page)
+    }
+
+    /// checks the pages found on disk (sorted) against the manifest. Returns
+    /// the pages that are not in it, which should be deleted: the pages before
+    /// the first segment were being removed and the pages after the last one
+    /// were being created (so they are empty) or truncated when the WAL
+    /// crashed. Any page of the manifest that is missing is an error.
+    pub fn validate(&self, pages: &[usize]) -> Result<Vec<usize>, Error> {
+        for pair in pages.windows(2) {
+            if pair[1] != pair[0] + 1 {
+                return Err(Error::MissingPage(pair[0] + 1));
+            }
+        }
+        for pair in self.segments.windows(2) {
+            if pair[1].page != pair[0].page + 1 || !pair[0].sealed {
+                return Err(Error::InvalidManifest(format!(
+                    "segment {} is out of order",
+                    pair[1].page
+                )));
+            }
+        }
+
+        let first = match self.segments.first() {
+            Some(segment) => segment.page,
+            None => return Ok(vec![]),
+        };
+        let last = self.segments.last().unwrap().page;
+        let before = pages.iter().take_while(|p| **p < first).count();
+        match pages.get(before) {
+            Some(page) if *page == first => {}
+            _ => return Err(Error::MissingPage(first)),
+        }
+        // pages are contiguous, the last one is enough
+        match pages.last() {
+            Some(page) if *page < last => Err(Error::MissingPage(page + 1)),
+            _ => Ok(pages
+                .iter()
+                .copied()
+                .filter(|p| *p < first || *p > last)
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn manifest(pages: &[usize]) -> Manifest {
+        Manifest {
+            segments: pages
+                .iter()
+                .enumerate()
+                .map(|(i, page)| Segment {
+                    page: *page,
+                    first_time: Some(1_000),
+                    last_time: Some(2_000),
+                    sealed: i + 1 < pages.len(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(None, Manifest::read(dir.path()).unwrap());
+
+        let manifest = manifest(&[3, 4]);
+        manifest.write(dir.path()).unwrap();
+        assert_eq!(Some(manifest), Manifest::read(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(
+            Vec::<usize>::new(),
+            Manifest::default().validate(&[0, 1]).unwrap()
+        );
+        assert!(matches!(
+            Manifest::default().validate(&[0, 2]),
+            Err(Error::MissingPage(1))
+        ));
+
+        let manifest = manifest(&[2, 3, 4]);
+        assert_eq!(Vec::<usize>::new(), manifest.validate(&[2, 3, 4]).unwrap());
+        // removed from the manifest but not from the disk
+        assert_eq!(vec![0, 1], manifest.validate(&[0, 1, 2, 3, 4]).unwrap());
+        // created on disk but not on the manifest
+        assert_eq!(vec![5], manifest.validate(&[2, 3, 4, 5]).unwrap());
+        assert!(matches!(
+            manifest.validate(&[2, 3]),
+            Err(Error::MissingPage(4))
+        ));
+        assert!(matches!(
+            manifest.validate(&[3, 4]),
+            Err(Error::MissingPage(2))
+        ));
+        assert!(matches!(manifest.validate(&[]), Err(Error::MissingPage(2))));
+
+        let mut unsealed = manifest.clone();
+        unsealed.segments[0].sealed = false;
+        assert!(matches!(
+            unsealed.validate(&[2, 3, 4]),
+            Err(Error::InvalidManifest(_))
+        ));
+    }
+}
@@ -1,10 +1,33 @@
+mod compression;
+mod durability;
+mod frame;
+mod iter;
+mod legacy;
+mod manifest;
+mod writer;
+
+use chrono::Utc;
+use compression::CompressedPage;
+pub use compression::Compression;
+use durability::Syncer;
+pub use durability::{Commit, Durability};
+pub use frame::Frame;
+pub use iter::{Iter, Position};
+pub use legacy::UnframedIter;
+use manifest::Manifest;
+pub use manifest::Segment;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
-use std::io::{BufReader, Error as StdIOError, Write};
-use std::path::PathBuf;
+use std::io::{Error as StdIOError, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tracing::{event, Level};
+pub use writer::{Append, Writer};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -14,28 +37,53 @@ pub enum Error {
     PageIndexOutOfRange,
     #[error("Error while loading logs")]
     CorruptedLogFile,
+    #[error("Log written by a newer version ({0}) of the WAL")]
+    UnsupportedVersion(u8),
+    #[error("Invalid manifest {0}")]
+    InvalidManifest(String),
+    #[error("Page {0} of the WAL is missing")]
+    MissingPage(usize),
+    #[error("The WAL writer stopped")]
+    WriterStopped,
+    #[error("Could not sync the WAL {0}")]
+    WriteFailed(String),
 }
 
 /// Log is a single-file WAL, it allows callers to write to the end of the file
-/// and read X bytes from any offset.
+/// and read X bytes from any offset. Sealed logs may be compressed, which
+/// is transparent to the readers.
 struct Log {
     writer: File,
     reader: File,
+    path: PathBuf,
     name: String,
+    /// number of the page, from the name of the file.
+    page: usize,
+    /// when the first and last entries were written, see `Segment`.
+    first_time: Option<u64>,
+    last_time: Option<u64>,
+    /// None if the page is not compressed.
+    compressed: Option<CompressedPage>,
 }
 
 impl Log {
-    fn new(file_name: PathBuf, name: String) -> Result<Self, Error> {
+    fn new(file_name: PathBuf, name: String, page: usize) -> Result<Self, Error> {
         let writer = OpenOptions::new()
             .create(true)
             .append(true)
             .open(&file_name)?;
         let reader = OpenOptions::new().read(true).open(&file_name)?;
+        let compressed = CompressedPage::open(&file_name)?;
 
         Ok(Self {
             writer,
             reader,
+            path: file_name,
             name,
+            page,
+            first_time: None,
+            last_time: None,
+            compressed,
         })
     }
 
@@ -44,61 +92,157 @@ impl Log {
     fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
         let curr_offset = self.len()?;
         self.writer.write_all(data)?;
+        let now = Utc::now().timestamp_millis() as u64;
+        self.first_time.get_or_insert(now);
+        self.last_time = Some(now);
 
         Ok(curr_offset)
     }
 
     fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if let Some(compressed) = self.compressed.as_mut() {
+            return compressed.read(offset, buf);
+        }
         self.reader.seek(SeekFrom::Start(offset))?;
         Ok(self.reader.read(buf)?)
     }
 
+    /// drops everything after `len`, a compressed page is decompressed first
+    /// since it is going to be written again.
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        if let Some(mut compressed) = self.compressed.take() {
+            compressed.decompress(&self.path)?;
+            self.reopen()?;
+        }
+        self.writer.set_len(len)?;
+        Ok(self.writer.sync_all()?)
+    }
+
+    /// compresses the page, it must be sealed.
+    fn compress(&mut self, compression: Compression) -> Result<(), Error> {
+        if compression == Compression::None || self.compressed.is_some() {
+            return Ok(());
+        }
+        let tmp = CompressedPage::write_compressed(&self.path, compression)?;
+        self.replace_compressed(&tmp)
+    }
+
+    /// replaces the page by its compressed version written at `tmp`
+    /// (see `CompressedPage::write_compressed`).
+    fn replace_compressed(&mut self, tmp: &Path) -> Result<(), Error> {
+        CompressedPage::replace(tmp, &self.path)?;
+        self.reopen()?;
+        self.compressed = CompressedPage::open(&self.path)?;
+        Ok(())
+    }
+
+    /// the file was replaced, the old one is not needed anymore.
+    fn reopen(&mut self) -> Result<(), Error> {
+        self.writer = OpenOptions::new().append(true).open(&self.path)?;
+        self.reader = OpenOptions::new().read(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// length of the page, decompressed.
     fn len(&self) -> Result<usize, Error> {
-        Ok(self.writer.metadata()?.len() as usize)
+        match &self.compressed {
+            Some(compressed) => Ok(compressed.len() as usize),
+            None => Ok(self.writer.metadata()?.len() as usize),
+        }
     }
 }
 
 /// Implements a WAL for the application
-/// is up to the caller to handle snapshot (to reduce the disk usage, probably
-/// should create a new WAL dump the content from memory and delete the old one), validating
-/// the content of the files (e.g. making sure all entries are valid and not corrupted)
-/// and handling any indexing needed.
+/// is up to the caller to delete the pages it does not need anymore (with
+/// `remove_pages_before`, see `AlarmService::compact`), validating the content
+/// of the records and handling any indexing needed.
 pub struct WAL {
     path: PathBuf,
+    /// sorted by page, the last one is the one being written.
     logs: Vec<Log>,
-    curr_page: usize,
     max_size_per_page: usize,
+    compression: Compression,
+    syncer: Arc<Syncer>,
+    /// the sealed page being compressed, and the thread compressing it
+    /// (see `compress_in_background`).
+    compressing: Option<(usize, JoinHandle<Result<PathBuf, Error>>)>,
+    /// stops the background flusher when the WAL is dropped.
+    _flusher: Option<Sender<()>>,
+    /// the next write fails after writing half of the data.
+    #[cfg(test)]
+    pub(crate) fail_next_write: bool,
 }
 
 pub struct Config {
     pub dir: PathBuf,
     pub max_size_per_page: usize,
+    /// when writes are synced to disk.
+    pub durability: Durability,
+    /// how pages are compressed once they are full.
+    pub compression: Compression,
+}
+
+impl Config {
+    /// 64MB pages, synced by the operating system and not compressed.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            max_size_per_page: 64 * 1024 * 1024,
+            durability: Durability::Os,
+            compression: Compression::None,
+        }
+    }
 }
 
 impl WAL {
     const LOG_PREFIX: &'static str = "log_page_";
 
     pub fn new(config: Config) -> Result<Self, Error> {
-        let mut logs = Self::find_logs(&config.dir)?;
+        let manifest = Manifest::read(&config.dir)?.unwrap_or_default();
+        let mut logs = Self::find_logs(&config.dir, &manifest)?;
 
-        let mut curr_page = logs.len();
-
-        if curr_page == 0 {
+        if logs.is_empty() {
             let first_log = Self::create_log(config.dir.clone(), 0)?;
-            curr_page = 1;
             logs.push(first_log);
         }
+        // e.g. a crash before a full page was compressed
+        let (active, sealed) = logs.split_last_mut().unwrap();
+        for log in sealed {
+            log.compress(config.compression)?;
+        }
+        if active.compressed.is_some() {
+            // the page after it was lost, it is written again
+            active.truncate(active.len()? as u64)?;
+        }
 
-        Ok(Self {
+        let syncer = Arc::new(Syncer::new(
+            config.durability,
+            logs.last().unwrap().writer.try_clone()?,
+        ));
+        let flusher = syncer.start_flusher()?;
+
+        let wal = Self {
             path: config.dir,
             max_size_per_page: config.max_size_per_page,
+            compression: config.compression,
             logs,
-            curr_page,
-        })
+            syncer,
+            compressing: None,
+            _flusher: flusher,
+            #[cfg(test)]
+            fail_next_write: false,
+        };
+        wal.write_manifest()?;
+        Ok(wal)
+    }
+
+    /// name of the file of the page.
+    fn page_name(page: usize) -> String {
+        format!("{}{}", Self::LOG_PREFIX, page)
     }
 
     fn create_log(mut path: PathBuf, page: usize) -> Result<Log, Error> {
-        path.push(format!("{}{}", Self::LOG_PREFIX, page));
+        path.push(Self::page_name(page));
         let file_name = path
             .as_path()
             .file_name()
@@ -106,68 +250,327 @@ impl WAL {
             .to_os_string()
             .into_string()
             .unwrap();
-        Log::new(path, file_name)
+        Log::new(path, file_name, page)
     }
 
-    fn find_logs(path: &PathBuf) -> Result<Vec<Log>, Error> {
+    /// the pages on disk, validated against the manifest (see `Manifest::validate`).
+    fn find_logs(path: &PathBuf, manifest: &Manifest) -> Result<Vec<Log>, Error> {
+        // pages that were being compressed (or decompressed), the page
+        // itself was not replaced
+        for entry in fs::read_dir(path)?.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(Self::LOG_PREFIX) && name.ends_with(".tmp") {
+                event!(Level::WARN, "removing WAL page {:0} left by a crash", name);
+                fs::remove_file(entry.path())?;
+            }
+        }
+
         let mut entries: Vec<_> = fs::read_dir(path)?
             .filter_map(Result::ok)
-            .filter(|e| {
-                if let Some(file_name) = e.path().file_name().and_then(|s| s.to_str()) {
-                    e.path().is_file() && file_name.starts_with(Self::LOG_PREFIX)
-                } else {
-                    false
-                }
+            .filter(|e| e.path().is_file())
+            .filter_map(|e| {
+                let file_name = e.path().file_name()?.to_str()?.to_string();
+                let page = file_name.strip_prefix(Self::LOG_PREFIX)?.parse().ok()?;
+                Some((page, file_name, e.path()))
             })
             .collect();
 
-        entries.sort_by_key(|e| e.path().file_name().unwrap().to_os_string());
+        // by number, log_page_10 comes after log_page_9
+        entries.sort_by_key(|(page, _, _)| *page);
 
+        let pages: Vec<usize> = entries.iter().map(|(page, _, _)| *page).collect();
+        let leftovers = manifest.validate(&pages)?;
         let mut logs = Vec::with_capacity(entries.len());
 
-        for entry in entries {
-            let name = entry.path().file_name().unwrap().to_os_string();
-            logs.push(Log::new(entry.path(), name.into_string().unwrap())?);
+        for (page, name, path) in entries {
+            if leftovers.contains(&page) {
+                event!(Level::WARN, "removing WAL page {:0} left by a crash", name);
+                fs::remove_file(path)?;
+                continue;
+            }
+            let mut log = Log::new(path, name, page)?;
+            if let Some(segment) = manifest.segment(page) {
+                log.first_time = segment.first_time;
+                log.last_time = segment.last_time;
+            }
+            logs.push(log);
         }
 
         Ok(logs)
     }
 
+    /// the pages of the WAL in order, the last one is the active page.
+    pub fn segments(&self) -> Vec<Segment> {
+        let active = self.last_page();
+        self.logs
+            .iter()
+            .map(|log| Segment {
+                page: log.page,
+                first_time: log.first_time,
+                last_time: log.last_time,
+                sealed: log.page != active,
+            })
+            .collect()
+    }
+
+    fn write_manifest(&self) -> Result<(), Error> {
+        Manifest {
+            segments: self.segments(),
+        }
+        .write(&self.path)
+    }
+
     /// writes to the end of the last page
     /// returns the (page, offset) so that you can retrieve the entry later
     pub fn write(&mut self, data: &[u8]) -> Result<(usize, usize), Error> {
+        self.swap_compressed(false)?;
         if self.curr_page_size() + data.len() > self.max_size_per_page {
-            let log = Self::create_log(self.path.clone(), self.curr_page)?;
-            self.logs.push(log);
-            self.curr_page += 1;
+            self.new_page()?;
+        }
+
+        let log = self.logs.last_mut().unwrap();
+        #[cfg(test)]
+        if std::mem::take(&mut self.fail_next_write) {
+            log.write(&data[..data.len() / 2])?;
+            return Err(StdIOError::other("injected write failure").into());
+        }
+        let offset = log.write(data)?;
+        let page = log.page;
+        self.syncer.written(data.len())?;
+
+        Ok((page, offset))
+    }
+
+    /// seals the active page, the next writes go to a new page. Does nothing
+    /// if the active page is empty.
+    pub fn seal(&mut self) -> Result<(), Error> {
+        self.swap_compressed(false)?;
+        if self.curr_page_size() == 0 {
+            return Ok(());
+        }
+        self.new_page()
+    }
+
+    fn new_page(&mut self) -> Result<(), Error> {
+        self.sync()?;
+        let log = Self::create_log(self.path.clone(), self.last_page() + 1)?;
+        event!(Level::INFO, "created new WAL page {:0}", log.name);
+        self.syncer.set_file(log.writer.try_clone()?);
+        self.logs.push(log);
+        self.write_manifest()?;
+
+        let sealed = self.logs[self.logs.len() - 2].page;
+        self.compress_in_background(sealed)
+    }
+
+    /// compresses the sealed page on another thread, so that the writes do
+    /// not wait for it. The page is replaced once it is done (see
+    /// `swap_compressed`), one page is compressed at a time.
+    fn compress_in_background(&mut self, page: usize) -> Result<(), Error> {
+        if self.compression == Compression::None {
+            return Ok(());
+        }
+        self.swap_compressed(true)?;
+        let path = self.log(page)?.path.clone();
+        let compression = self.compression;
+        let thread = thread::Builder::new()
+            .name("wal-compression".to_string())
+            .spawn(move || CompressedPage::write_compressed(&path, compression))?;
+        self.compressing = Some((page, thread));
+        Ok(())
+    }
+
+    /// replaces the page compressed in the background by its compressed
+    /// version if it is done, `wait` waits for it otherwise. A page that
+    /// could not be compressed is kept as it is.
+    fn swap_compressed(&mut self, wait: bool) -> Result<(), Error> {
+        match &self.compressing {
+            Some((_, thread)) if wait || thread.is_finished() => {}
+            _ => return Ok(()),
+        }
+        let (page, thread) = self.compressing.take().unwrap();
+        match thread.join() {
+            Ok(Ok(tmp)) => match self.index(page) {
+                Ok(index) => self.logs[index].replace_compressed(&tmp)?,
+                Err(_) => fs::remove_file(tmp)?,
+            },
+            Ok(Err(e)) => event!(
+                Level::ERROR,
+                "could not compress WAL page {:0}: {:1}",
+                page,
+                e
+            ),
+            Err(_) => event!(Level::ERROR, "could not compress WAL page {:0}", page),
+        }
+        Ok(())
+    }
+
+    /// everything written so far, waiting on it makes the writes durable
+    /// (see `Durability`).
+    pub fn commit(&self) -> Commit {
+        self.syncer.commit()
+    }
+
+    /// writes the record framed (see `Frame`), returns the (page, offset)
+    /// so that you can retrieve it later with `read_frame`.
+    pub fn append(&mut self, record_type: u8, data: &[u8]) -> Result<(usize, usize), Error> {
+        self.write(&Frame::new(record_type, data.to_vec()).encode())
+    }
+
+    /// reads the frame starting at offset, None if the page ends there.
+    /// Returns `Error::CorruptedLogFile` if the frame is incomplete or its
+    /// checksum does not match.
+    pub fn read_frame(&mut self, page: usize, offset: u64) -> Result<Option<Frame>, Error> {
+        let len = self.log(page)?.len()? as u64;
+        if offset >= len {
+            return Ok(None);
         }
 
-        let offset = self.logs.get_mut(self.curr_page - 1).unwrap().write(data)?;
+        let mut header = [0; Frame::HEADER_SIZE];
+        self.read_exact(page, offset, &mut header)?;
+        let (record_type, length, checksum) = Frame::decode_header(&header)?;
+        if offset + (Frame::HEADER_SIZE + length) as u64 > len {
+            // a torn or corrupted header, do not allocate what it claims
+            return Err(Error::CorruptedLogFile);
+        }
+        let mut data = vec![0; length];
+        self.read_exact(page, offset + Frame::HEADER_SIZE as u64, &mut data)?;
+        Frame::verify(record_type, data, checksum).map(Some)
+    }
+
+    /// iterates over the frames from `position` until the end of the WAL,
+    /// see `Iter`. The position must be on a page that was not removed.
+    pub fn iter_from(&self, position: Position) -> Result<Iter, Error> {
+        let index = self.index(position.page)?;
+        let pages = self.logs[index..].iter().map(|log| log.page).collect();
+        Ok(Iter::new(self.path.clone(), pages, position))
+    }
 
-        Ok((self.curr_page - 1, offset))
+    /// iterates over the entries of a page written before records were
+    /// framed (see `is_unframed`).
+    pub fn iter_unframed(&self, page: usize) -> Result<UnframedIter, Error> {
+        UnframedIter::new(&self.log(page)?.path, page)
+    }
+
+    /// fills the buffer, a page that ends before is corrupted (e.g. a torn write).
+    fn read_exact(
+        &mut self,
+        page: usize,
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let read = self.read(page, offset, buf)?;
+            if read == 0 {
+                return Err(Error::CorruptedLogFile);
+            }
+            offset += read as u64;
+            buf = &mut buf[read..];
+        }
+        Ok(())
+    }
+
+    /// checks if the page was written before records were framed (see
+    /// `Frame`), when every entry was prefixed by its length in 8 bytes
+    /// (native endian) and encoded as JSON. Those are read with `iter_unframed`.
+    pub fn is_unframed(&mut self, page: usize) -> Result<bool, Error> {
+        let mut header = [0; 9];
+        match self.read_exact(page, 0, &mut header) {
+            Ok(()) => {}
+            Err(Error::CorruptedLogFile) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let length = u64::from_ne_bytes(header[..8].try_into().unwrap());
+        let page_len = self.log(page)?.len()? as u64;
+        Ok(header[8] == b'{' && length <= page_len.saturating_sub(8))
+    }
+
+    /// drops everything from (page, offset) onwards, including the pages
+    /// after it. Used to discard a corrupted tail during recovery.
+    pub fn truncate(&mut self, page: usize, offset: u64) -> Result<(), Error> {
+        self.swap_compressed(true)?;
+        let index = self.index(page)?;
+        self.logs[index].truncate(offset)?;
+        // the manifest goes first, pages after it are deleted by `WAL::new`
+        let removed_logs: Vec<Log> = self.logs.drain(index + 1..).collect();
+        self.syncer.set_file(self.logs[index].writer.try_clone()?);
+        self.write_manifest()?;
+        for log in removed_logs {
+            fs::remove_file(self.path.join(&log.name))?;
+        }
+        Ok(())
+    }
+
+    /// deletes the pages before `page`, the page being written is never deleted.
+    /// Returns how many pages were deleted.
+    pub fn remove_pages_before(&mut self, page: usize) -> Result<usize, Error> {
+        self.swap_compressed(true)?;
+        let page = page.min(self.last_page());
+        let removed = self.logs.iter().take_while(|log| log.page < page).count();
+        if removed == 0 {
+            return Ok(0);
+        }
+        // the manifest goes first, pages before it are deleted by `WAL::new`
+        let removed_logs: Vec<Log> = self.logs.drain(..removed).collect();
+        self.write_manifest()?;
+        for log in removed_logs {
+            fs::remove_file(self.path.join(&log.name))?;
+            event!(Level::INFO, "removed WAL page {:0}", log.name);
+        }
+        Ok(removed)
     }
 
     /// read page starting from offset. Each page is a log file.
     /// if usize = 0 there was nothing to be read.
     pub fn read(&mut self, page: usize, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let index = self.index(page)?;
+        self.logs[index].read(offset, buf)
+    }
+
+    fn index(&self, page: usize) -> Result<usize, Error> {
         self.logs
-            .get_mut(page)
-            .ok_or(Error::PageIndexOutOfRange)?
-            .read(offset, buf)
+            .binary_search_by_key(&page, |log| log.page)
+            .map_err(|_| Error::PageIndexOutOfRange)
+    }
+
+    fn log(&self, page: usize) -> Result<&Log, Error> {
+        Ok(&self.logs[self.index(page)?])
+    }
+
+    /// flushes the current page to disk, older pages are synced when a new
+    /// page is created. The manifest is updated with the active page timestamps.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.syncer.sync()?;
+        self.write_manifest()
+    }
+
+    /// the oldest page that was not removed.
+    pub fn first_page(&self) -> usize {
+        self.logs.first().unwrap().page
     }
 
+    /// the page being written.
     pub fn last_page(&self) -> usize {
-        self.curr_page
+        self.logs.last().unwrap().page
     }
 
     pub fn curr_page_size(&self) -> usize {
-        self.logs.get(self.curr_page - 1).unwrap().len().unwrap()
+        self.logs.last().unwrap().len().unwrap()
     }
 
     pub fn is_empty_wal(&self) -> bool {
-        self.last_page() == 1 && self.curr_page_size() == 0
+        self.logs.len() == 1 && self.curr_page_size() == 0
+    }
+}
+impl Drop for WAL {
+    /// the page being compressed is replaced before the WAL is closed.
+    fn drop(&mut self) {
+        if let Err(e) = self.swap_compressed(true) {
+            event!(Level::ERROR, "could not compress WAL page: {:0}", e);
+        }
     }
 }
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -176,11 +579,11 @@ mod test {
     #[test]
     fn read_and_write_on_log() {
         let dir = TempDir::new().unwrap();
-        let mut log = Log::new(dir.path().join("mylog"), "test".into()).unwrap();
+        let mut log = Log::new(dir.path().join("mylog"), "test".into(), 0).unwrap();
         let entry = "my_entry".as_bytes();
-        let result = log.write(&entry).unwrap();
+        let result = log.write(entry).unwrap();
 
-        assert_eq!(0 as usize, result);
+        assert_eq!(0_usize, result);
 
         let mut buf = [0; 8];
         log.read(0, &mut buf).unwrap();
@@ -191,21 +594,21 @@ mod test {
         log.read(3, &mut buf).unwrap();
         assert_eq!("entry".as_bytes(), buf);
 
-        let result = log.write(&entry).unwrap();
-        assert_eq!(8 as usize, result);
+        let result = log.write(entry).unwrap();
+        assert_eq!(8_usize, result);
     }
 
     #[test]
     fn read_and_write_on_wal() {
         let dir = TempDir::new().unwrap();
         let mut wal = WAL::new(Config {
-            dir: dir.path().to_path_buf(),
             max_size_per_page: 8,
+            ..Config::new(dir.path().to_path_buf())
         })
         .unwrap();
         let entry = "my_entry".as_bytes();
-        let result = wal.write(&entry).unwrap();
-        assert_eq!((0 as usize, 0 as usize), result);
+        let result = wal.write(entry).unwrap();
+        assert_eq!((0_usize, 0_usize), result);
 
         let mut buf = [0; 8];
         wal.read(0, 0, &mut buf).unwrap();
@@ -213,11 +616,234 @@ mod test {
 
         // write on second page
         let entry = "second".as_bytes();
-        let result = wal.write(&entry).unwrap();
-        assert_eq!((1 as usize, 0 as usize), result);
+        let result = wal.write(entry).unwrap();
+        assert_eq!((1_usize, 0_usize), result);
 
         let mut buf = [0; 6];
         wal.read(1, 0, &mut buf).unwrap();
         assert_eq!(entry, buf);
     }
+
+    #[test]
+    fn frames_on_wal() {
+        let dir = TempDir::new().unwrap();
+        let mut wal = WAL::new(Config {
+            max_size_per_page: 32,
+            ..Config::new(dir.path().to_path_buf())
+        })
+        .unwrap();
+        assert_eq!((0, 0), wal.append(1, b"first").unwrap());
+        assert_eq!((0, 15), wal.append(2, b"second").unwrap());
+        // does not fit on the first page
+        assert_eq!((1, 0), wal.append(1, b"third").unwrap());
+
+        assert_eq!(
+            Some(Frame::new(2, b"second".to_vec())),
+            wal.read_frame(0, 15).unwrap()
+        );
+        assert_eq!(None, wal.read_frame(0, 31).unwrap());
+        assert!(matches!(
+            wal.read_frame(2, 0),
+            Err(Error::PageIndexOutOfRange)
+        ));
+
+        // a torn write
+        wal.write(&Frame::new(1, b"fourth".to_vec()).encode()[..12])
+            .unwrap();
+        assert!(matches!(
+            wal.read_frame(1, 15),
+            Err(Error::CorruptedLogFile)
+        ));
+
+        wal.truncate(0, 15).unwrap();
+        assert_eq!(0, wal.last_page());
+        assert_eq!(15, wal.curr_page_size());
+        assert!(!dir.path().join("log_page_1").exists());
+        assert_eq!((0, 15), wal.append(3, b"fifth").unwrap());
+    }
+
+    #[test]
+    fn frame_lengths_past_the_page_are_corrupted() {
+        let dir = TempDir::new().unwrap();
+        let mut wal = WAL::new(Config::new(dir.path().to_path_buf())).unwrap();
+        wal.append(1, b"first").unwrap();
+        let mut header = Frame::new(2, b"second".to_vec()).encode();
+        header[2..6].copy_from_slice(&u32::MAX.to_le_bytes());
+        wal.write(&header).unwrap();
+
+        assert!(matches!(
+            wal.read_frame(0, 15),
+            Err(Error::CorruptedLogFile)
+        ));
+        let mut frames = wal.iter_from(Position::default()).unwrap();
+        assert!(frames.next().unwrap().is_ok());
+        assert!(matches!(frames.next(), Some(Err(Error::CorruptedLogFile))));
+        assert_eq!(Position::new(0, 15), frames.position());
+    }
+
+    #[test]
+    fn unframed_pages_are_iterated_and_sealed() {
+        let dir = TempDir::new().unwrap();
+        let mut wal = WAL::new(Config::new(dir.path().to_path_buf())).unwrap();
+        // as written before records were framed, the second length is torn
+        wal.write(&6_usize.to_ne_bytes()).unwrap();
+        wal.write(b"{\"a\":1").unwrap();
+        wal.write(&u64::MAX.to_ne_bytes()).unwrap();
+        assert!(wal.is_unframed(0).unwrap());
+
+        let mut entries = wal.iter_unframed(0).unwrap();
+        let (position, data) = entries.next().unwrap().unwrap();
+        assert_eq!(
+            (Position::new(0, 0), b"{\"a\":1".to_vec()),
+            (position, data)
+        );
+        assert!(matches!(entries.next(), Some(Err(Error::CorruptedLogFile))));
+        assert_eq!(Position::new(0, 14), entries.position());
+
+        wal.seal().unwrap();
+        assert_eq!((1, 0), wal.append(1, b"first").unwrap());
+        // an empty page is not sealed again
+        let mut wal = WAL::new(Config::new(dir.path().to_path_buf())).unwrap();
+        wal.truncate(1, 0).unwrap();
+        wal.seal().unwrap();
+        assert_eq!(1, wal.last_page());
+    }
+
+    #[test]
+    fn pages_are_ordered_by_number_and_listed_in_the_manifest() {
+        let dir = TempDir::new().unwrap();
+        let config = || Config {
+            max_size_per_page: 16,
+            ..Config::new(dir.path().to_path_buf())
+        };
+        let mut wal = WAL::new(config()).unwrap();
+        for i in 0..12_u8 {
+            wal.append(i, &[i]).unwrap();
+        }
+        assert_eq!(11, wal.last_page());
+        let segments = wal.segments();
+        assert_eq!(12, segments.len());
+        assert!(segments[..11].iter().all(|segment| segment.sealed));
+        assert!(!segments[11].sealed);
+        assert!(segments[0].first_time.is_some());
+        wal.sync().unwrap();
+        drop(wal);
+
+        let mut wal = WAL::new(config()).unwrap();
+        assert_eq!(segments, wal.segments());
+        // log_page_10 is not replayed before log_page_2
+        let types: Vec<u8> = (wal.first_page()..=wal.last_page())
+            .map(|page| wal.read_frame(page, 0).unwrap().unwrap().record_type)
+            .collect();
+        assert_eq!((0..12).collect::<Vec<u8>>(), types);
+        drop(wal);
+
+        fs::remove_file(dir.path().join("log_page_4")).unwrap();
+        assert!(matches!(WAL::new(config()), Err(Error::MissingPage(4))));
+        fs::remove_file(dir.path().join("log_page_0")).unwrap();
+        fs::remove_file(dir.path().join("log_page_1")).unwrap();
+        fs::remove_file(dir.path().join("log_page_2")).unwrap();
+        fs::remove_file(dir.path().join("log_page_3")).unwrap();
+        assert!(matches!(WAL::new(config()), Err(Error::MissingPage(0))));
+    }
+
+    #[test]
+    fn iterates_over_pages() {
+        let dir = TempDir::new().unwrap();
+        let mut wal = WAL::new(Config {
+            max_size_per_page: 32,
+            ..Config::new(dir.path().to_path_buf())
+        })
+        .unwrap();
+        assert_eq!(0, wal.iter_from(Position::default()).unwrap().count());
+
+        for (i, data) in [&b"first"[..], b"second", b"third", b"fourth"]
+            .iter()
+            .enumerate()
+        {
+            wal.append(i as u8, data).unwrap();
+        }
+        let positions: Vec<Position> = wal
+            .iter_from(Position::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(
+            vec![
+                Position::new(0, 0),
+                Position::new(0, 15),
+                Position::new(1, 0),
+                Position::new(1, 15),
+            ],
+            positions
+        );
+
+        let mut frames = wal.iter_from(Position::new(0, 15)).unwrap();
+        assert_eq!(
+            (Position::new(0, 15), Frame::new(1, b"second".to_vec())),
+            frames.next().unwrap().unwrap()
+        );
+        assert_eq!(
+            (Position::new(1, 0), Frame::new(2, b"third".to_vec())),
+            frames.next().unwrap().unwrap()
+        );
+
+        // a torn write
+        wal.write(&Frame::new(4, b"fifth".to_vec()).encode()[..12])
+            .unwrap();
+        let mut frames = wal.iter_from(Position::new(1, 0)).unwrap();
+        assert_eq!(2, frames.by_ref().take(2).filter(Result::is_ok).count());
+        assert!(matches!(frames.next(), Some(Err(Error::CorruptedLogFile))));
+        assert_eq!(Position::new(2, 0), frames.position());
+        assert!(frames.next().is_none());
+
+        assert!(matches!(
+            wal.iter_from(Position::new(3, 0)),
+            Err(Error::PageIndexOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn sealed_pages_are_compressed() {
+        let dir = TempDir::new().unwrap();
+        let config = || Config {
+            max_size_per_page: 4096,
+            compression: Compression::Zstd,
+            ..Config::new(dir.path().to_path_buf())
+        };
+        let mut wal = WAL::new(config()).unwrap();
+        let record = b"cpu.usage host=web-1 region=eu-west-1".to_vec();
+        for _ in 0..200 {
+            wal.append(1, &record).unwrap();
+        }
+        assert!(wal.last_page() > 0);
+        wal.swap_compressed(true).unwrap();
+        let sealed = fs::metadata(dir.path().join("log_page_0")).unwrap().len();
+        assert!((sealed as usize) < wal.log(0).unwrap().len().unwrap() / 4);
+        assert_eq!(
+            Some(Frame::new(1, record.clone())),
+            wal.read_frame(0, 47).unwrap()
+        );
+        let frames: Vec<Frame> = wal
+            .iter_from(Position::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(vec![Frame::new(1, record.clone()); 200], frames);
+        drop(wal);
+
+        // a crash while a page was compressed
+        fs::write(dir.path().join("log_page_1.tmp"), b"GBWZ").unwrap();
+        // the page is written again once truncated
+        let mut wal = WAL::new(config()).unwrap();
+        assert!(!dir.path().join("log_page_1.tmp").exists());
+        wal.truncate(0, 47).unwrap();
+        assert_eq!((0, 47), wal.append(2, &record).unwrap());
+        let types: Vec<u8> = wal
+            .iter_from(Position::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().1.record_type)
+            .collect();
+        assert_eq!(vec![1, 2], types);
+    }
 }
@@ -0,0 +1,248 @@
+use crate::wal::{Error, Position, WAL};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use tokio::sync::oneshot;
+
+enum Request {
+    Append {
+        record_type: u8,
+        data: Vec<u8>,
+        done: oneshot::Sender<Result<Position, Error>>,
+    },
+    /// answered once every request before it was written.
+    Barrier(mpsc::Sender<()>),
+}
+
+/// Writer appends records to the WAL from a dedicated thread, so that async
+/// callers never block on the file writes or fsyncs. Records are written in
+/// the order they were queued, in batches that share a single sync (see
+/// `Durability`).
+pub struct Writer {
+    wal: Arc<Mutex<WAL>>,
+    requests: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    /// the most records written before syncing.
+    const MAX_BATCH: usize = 1024;
+
+    pub fn new(wal: WAL) -> Result<Self, Error> {
+        let wal = Arc::new(Mutex::new(wal));
+        let (requests, received) = mpsc::channel();
+        let thread = {
+            let wal = wal.clone();
+            thread::Builder::new()
+                .name("wal-writer".to_string())
+                .spawn(move || Self::run(wal, received))?
+        };
+        Ok(Self {
+            wal,
+            requests: Some(requests),
+            thread: Some(thread),
+        })
+    }
+
+    /// queues the record, the returned future resolves with its position once
+    /// it is durable. Dropping the future does not cancel the write.
+    pub fn append(&self, record_type: u8, data: Vec<u8>) -> Append {
+        let (done, result) = oneshot::channel();
+        let request = Request::Append {
+            record_type,
+            data,
+            done,
+        };
+        // if the thread stopped, the future resolves to `WriterStopped`
+        let _ = self.requests.as_ref().unwrap().send(request);
+        Append(result)
+    }
+
+    /// the WAL, for reads and maintenance (e.g. removing pages). Blocks until
+    /// every record queued before was written.
+    pub fn wal(&self) -> MutexGuard<'_, WAL> {
+        let (done, written) = mpsc::channel();
+        if self
+            .requests
+            .as_ref()
+            .unwrap()
+            .send(Request::Barrier(done))
+            .is_ok()
+        {
+            let _ = written.recv();
+        }
+        self.wal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(wal: Arc<Mutex<WAL>>, requests: Receiver<Request>) {
+        // set once the WAL could not be restored after a failed write
+        let mut failed = None;
+        while let Ok(request) = requests.recv() {
+            let mut batch = vec![request];
+            while batch.len() < Self::MAX_BATCH {
+                match requests.try_recv() {
+                    Ok(request) => batch.push(request),
+                    Err(_) => break,
+                }
+            }
+
+            let (results, commit) = {
+                let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+                let results: Vec<_> = batch
+                    .iter()
+                    .map(|request| match request {
+                        Request::Append {
+                            record_type, data, ..
+                        } => Self::write(&mut wal, &mut failed, *record_type, data),
+                        Request::Barrier(_) => Ok(Position::default()),
+                    })
+                    .collect();
+                (results, wal.commit())
+            };
+            let synced = commit.wait().map_err(|e| e.to_string());
+
+            for (request, result) in batch.into_iter().zip(results) {
+                match request {
+                    Request::Append { done, .. } => {
+                        let result = match &synced {
+                            Ok(()) => result,
+                            Err(e) => result.and(Err(Error::WriteFailed(e.clone()))),
+                        };
+                        let _ = done.send(result);
+                    }
+                    Request::Barrier(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// appends the record, a failed write is truncated so that the records
+    /// after it are not written after a partial one. If the WAL cannot be
+    /// truncated, every record after it fails.
+    fn write(
+        wal: &mut WAL,
+        failed: &mut Option<String>,
+        record_type: u8,
+        data: &[u8],
+    ) -> Result<Position, Error> {
+        if let Some(e) = failed {
+            return Err(Error::WriteFailed(e.clone()));
+        }
+        let (page, len) = (wal.last_page(), wal.curr_page_size());
+        match wal.append(record_type, data) {
+            Ok((page, offset)) => Ok(Position::new(page, offset as u64)),
+            Err(e) => {
+                if let Err(truncate_error) = wal.truncate(page, len as u64) {
+                    *failed = Some(truncate_error.to_string());
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Writer {
+    /// writes everything that was queued before stopping the thread.
+    fn drop(&mut self) {
+        drop(self.requests.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Append resolves once the record is durable, see `Writer::append`.
+#[must_use]
+pub struct Append(oneshot::Receiver<Result<Position, Error>>);
+
+impl Future for Append {
+    type Output = Result<Position, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(Error::WriterStopped)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wal::{Config, Durability};
+    use temp_dir::TempDir;
+
+    #[tokio::test]
+    async fn appends_in_order_and_resolves_once_written() {
+        let dir = TempDir::new().unwrap();
+        let writer = Writer::new(
+            WAL::new(Config {
+                max_size_per_page: 32,
+                durability: Durability::Always,
+                ..Config::new(dir.path().to_path_buf())
+            })
+            .unwrap(),
+        )
+        .unwrap();
+
+        let appends: Vec<Append> = (0..4_u8).map(|i| writer.append(i, vec![i; 6])).collect();
+        // the barrier waits for the queued records
+        assert_eq!(1, writer.wal().last_page());
+        let mut positions = vec![];
+        for append in appends {
+            positions.push(append.await.unwrap());
+        }
+        assert_eq!(
+            vec![
+                Position::new(0, 0),
+                Position::new(0, 16),
+                Position::new(1, 0),
+                Position::new(1, 16),
+            ],
+            positions
+        );
+
+        let dropped = writer.append(4, vec![4; 6]);
+        drop(dropped);
+        drop(writer);
+        let wal = WAL::new(Config {
+            max_size_per_page: 32,
+            durability: Durability::Always,
+            ..Config::new(dir.path().to_path_buf())
+        })
+        .unwrap();
+        let types: Vec<u8> = wal
+            .iter_from(Position::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().1.record_type)
+            .collect();
+        assert_eq!(vec![0, 1, 2, 3, 4], types);
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_is_not_followed_by_the_next_records() {
+        let dir = TempDir::new().unwrap();
+        let writer = Writer::new(WAL::new(Config::new(dir.path().to_path_buf())).unwrap()).unwrap();
+
+        writer.append(0, vec![0; 6]).await.unwrap();
+        writer.wal().fail_next_write = true;
+        let failed = writer.append(1, vec![1; 6]);
+        let next = writer.append(2, vec![2; 6]);
+        assert!(failed.await.is_err());
+        // written where the failed record started
+        assert_eq!(Position::new(0, 16), next.await.unwrap());
+
+        drop(writer);
+        let wal = WAL::new(Config::new(dir.path().to_path_buf())).unwrap();
+        let types: Vec<u8> = wal
+            .iter_from(Position::default())
+            .unwrap()
+            .map(|entry| entry.unwrap().1.record_type)
+            .collect();
+        assert_eq!(vec![0, 2], types);
+    }
+}
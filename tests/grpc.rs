@@ -19,9 +19,20 @@ fn start_grpc_server() {
     INIT.call_once(|| {
         Handle::current().spawn(async {
             let logs = TempDir::new().unwrap();
-            guardian_bell::app::App::run_server(8080, logs.path())
-                .await
-                .unwrap();
+            let storage = TempDir::new().unwrap();
+            guardian_bell::app::App::run_server(guardian_bell::app::Config {
+                grpc_server_port: 8080,
+                http_server_port: 8081,
+                logs_dir: logs.path().to_owned(),
+                storage_path: storage.path().to_owned(),
+                max_size_per_page_wal: 1024 * 1024,
+                wal_durability: guardian_bell::wal::Durability::Always,
+                wal_compression: guardian_bell::wal::Compression::Lz4,
+                evaluation_interval: Duration::from_secs(1),
+                alarms_path: None,
+            })
+            .await
+            .unwrap();
         });
     });
 }
@@ -43,7 +54,7 @@ async fn connect() -> Channel {
             (Err(e), i) => {
                 if i <= 3 {
                     sleep(Duration::from_secs(i)).await;
-                    retry = 1 + retry
+                    retry += 1
                 } else {
                     panic!("could not connect {:?}", e);
                 }
@@ -69,7 +80,7 @@ async fn is_healthy() {
 
     assert_eq!(
         ServingStatus::Serving,
-        ServingStatus::from_i32(response.status).unwrap()
+        ServingStatus::try_from(response.status).unwrap()
     );
 }
 
@@ -86,6 +97,6 @@ async fn shutdown_gracefully() {
     let response = client.check(request).await.unwrap().into_inner();
     assert_eq!(
         ServingStatus::NotServing,
-        ServingStatus::from_i32(response.status).unwrap()
+        ServingStatus::try_from(response.status).unwrap()
     );
 }